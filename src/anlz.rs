@@ -0,0 +1,385 @@
+use std::{fs, path::Path};
+
+// Rekordbox keeps what it analysed about a track in ANLZ files, ANLZ0000.DAT with an .EXT next
+// to it for newer things. Everything is big endian:
+//
+//   File header   "PMAI", header length, file length, the tags start after the header
+//   Tag           four letter kind, header length, tag length, the entries start after the header
+//
+//   PQTZ   beatgrid, 8 bytes per beat: number in the bar, BPM * 100, time in ms
+//   PCOB   memory or hot cues, each a PCPT entry of its own length
+//   PSSI   phrases, in the .EXT. Rekordbox 6 garbles the body with an XOR mask
+//
+// Other tags are skipped.
+
+const FILE_MAGIC: &[u8; 4] = b"PMAI";
+
+/// PSSI bodies are XORed with this, each byte plus the number of phrases
+const PSSI_MASK: [u8; 19] = [
+    0xCB, 0xE1, 0xEE, 0xFA, 0xE5, 0xEE, 0xAD, 0xEE, 0xE9, 0xD2, 0xE9, 0xEB, 0xE1, 0xE9, 0xF3, 0xE8,
+    0xE9, 0xF4, 0xE1,
+];
+
+/// Where the garbled part of a PSSI tag starts, right at the mood
+const PSSI_MASK_START: usize = 0x12;
+
+/// Loop end of a cue that is not a loop
+const NO_LOOP: u32 = u32::MAX;
+
+#[derive(Clone, Debug)]
+pub struct Beat {
+    /// 1 to 4, 1 being the downbeat
+    pub number: u8,
+    pub bpm: f32,
+    pub time_ms: u32,
+    /// Counted from the first downbeat at 1, beats before it are in bar 0
+    pub bar: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cue {
+    /// 0 for a memory cue, 1 for hot cue A and so on
+    pub hot_cue: u32,
+    pub time_ms: u32,
+    /// Set for loops
+    pub loop_end_ms: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Phrase {
+    pub index: u16,
+    /// First beat of the phrase, counting from 1
+    pub beat: u16,
+    /// Intro, verse, chorus and so on, which one depends on the mood
+    pub kind: u16,
+}
+
+/// Phrase analysis of a track
+#[derive(Clone, Debug)]
+pub struct Structure {
+    /// 1 high, 2 mid, 3 low
+    pub mood: u16,
+    pub end_beat: u16,
+    pub phrases: Vec<Phrase>,
+}
+
+/// Where a time falls in the beatgrid
+#[derive(Clone, Copy, Debug)]
+pub struct GridPosition {
+    /// Beats counted like Rekordbox's bar and beat counter, `bar * 4 + beat`
+    pub beats: i32,
+    /// How far into the beat, 0 to 1
+    pub phase: f32,
+    pub bpm: f32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Analysis {
+    pub beats: Vec<Beat>,
+    pub cues: Vec<Cue>,
+    pub structure: Option<Structure>,
+}
+
+impl Analysis {
+    /// Read an .DAT and the .EXT next to it, if there is one
+    pub fn from_file(path: &Path) -> Result<Analysis, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let mut analysis = Analysis::parse(&data).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        let ext = path.with_extension("EXT");
+        if ext != path {
+            if let Ok(data) = fs::read(&ext) {
+                let extended = Analysis::parse(&data).map_err(|e| format!("{}: {e}", ext.display()))?;
+                analysis.merge(extended);
+            }
+        }
+        Ok(analysis)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Analysis, String> {
+        if data.get(..4) != Some(FILE_MAGIC) {
+            return Err("Not an ANLZ file".to_string());
+        }
+        let mut analysis = Analysis::default();
+        let mut at = u32_at(data, 4)? as usize;
+        while at + 12 <= data.len() {
+            let len_tag = u32_at(data, at + 8)? as usize;
+            let tag = data
+                .get(at..at + len_tag)
+                .filter(|_| len_tag >= 12)
+                .ok_or_else(|| format!("Broken tag at {at:X}"))?;
+            match &tag[..4] {
+                b"PQTZ" => analysis.beats = parse_beatgrid(tag)?,
+                b"PCOB" => analysis.cues.extend(parse_cues(tag)?),
+                b"PSSI" => analysis.structure = Some(parse_structure(tag)?),
+                _ => (),
+            }
+            at += len_tag;
+        }
+        Ok(analysis)
+    }
+
+    /// Take what the .EXT has that the .DAT does not
+    fn merge(&mut self, extended: Analysis) {
+        if self.beats.is_empty() {
+            self.beats = extended.beats;
+        }
+        // The .EXT repeats the cues with colors
+        if self.cues.is_empty() {
+            self.cues = extended.cues;
+        }
+        if self.structure.is_none() {
+            self.structure = extended.structure;
+        }
+    }
+
+    /// Where `time_ms` falls in the beatgrid, `None` before the first beat, after the last or
+    /// without a grid
+    pub fn position(&self, time_ms: f64) -> Option<GridPosition> {
+        let index = self
+            .beats
+            .partition_point(|x| x.time_ms as f64 <= time_ms)
+            .checked_sub(1)?;
+        let beat = &self.beats[index];
+        // The last beat lasts as long as its tempo says
+        let length = match self.beats.get(index + 1) {
+            Some(next) => next.time_ms as f64 - beat.time_ms as f64,
+            None => 60_000. / beat.bpm as f64,
+        };
+        if length <= 0. || time_ms >= beat.time_ms as f64 + length {
+            return None;
+        }
+        let phase = ((time_ms - beat.time_ms as f64) / length) as f32;
+        Some(GridPosition {
+            beats: beat.bar * 4 + beat.number as i32,
+            phase: phase.min(0.999),
+            bpm: beat.bpm,
+        })
+    }
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, String> {
+    data.get(at..at + 2)
+        .map(|x| u16::from_be_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| format!("Cut off at {at:X}"))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, String> {
+    data.get(at..at + 4)
+        .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+        .ok_or_else(|| format!("Cut off at {at:X}"))
+}
+
+fn parse_beatgrid(tag: &[u8]) -> Result<Vec<Beat>, String> {
+    let start = u32_at(tag, 4)? as usize;
+    let count = u32_at(tag, 0x14)? as usize;
+    let mut bar = 0;
+    let mut beats = Vec::with_capacity(count);
+    for i in 0..count {
+        let at = start + i * 8;
+        let number = u16_at(tag, at)? as u8;
+        if number == 1 {
+            bar += 1;
+        }
+        beats.push(Beat {
+            number,
+            bpm: u16_at(tag, at + 2)? as f32 / 100.,
+            time_ms: u32_at(tag, at + 4)?,
+            bar,
+        });
+    }
+    Ok(beats)
+}
+
+fn parse_cues(tag: &[u8]) -> Result<Vec<Cue>, String> {
+    let count = u16_at(tag, 0x12)? as usize;
+    let mut at = u32_at(tag, 4)? as usize;
+    let mut cues = Vec::with_capacity(count);
+    for _ in 0..count {
+        if tag.get(at..at + 4) != Some(b"PCPT") {
+            return Err(format!("Expected a PCPT entry at {at:X} of PCOB"));
+        }
+        let len_entry = u32_at(tag, at + 8)? as usize;
+        let loop_end_ms = u32_at(tag, at + 0x24)?;
+        cues.push(Cue {
+            hot_cue: u32_at(tag, at + 0x0C)?,
+            time_ms: u32_at(tag, at + 0x20)?,
+            loop_end_ms: (loop_end_ms != NO_LOOP).then_some(loop_end_ms),
+        });
+        at += len_entry.max(1);
+    }
+    Ok(cues)
+}
+
+fn parse_structure(tag: &[u8]) -> Result<Structure, String> {
+    let len_entry = u32_at(tag, 0x0C)? as usize;
+    let count = u16_at(tag, 0x10)?;
+    let mut tag = tag.to_vec();
+    // Moods are 1 to 3, anything else is still garbled
+    if !(1..=3).contains(&u16_at(&tag, PSSI_MASK_START)?) {
+        for (i, byte) in tag[PSSI_MASK_START..].iter_mut().enumerate() {
+            *byte ^= PSSI_MASK[i % PSSI_MASK.len()].wrapping_add(count as u8);
+        }
+    }
+
+    let start = u32_at(&tag, 4)? as usize;
+    let phrases = (0..count as usize)
+        .map(|i| {
+            let at = start + i * len_entry;
+            Ok(Phrase {
+                index: u16_at(&tag, at)?,
+                beat: u16_at(&tag, at + 2)?,
+                kind: u16_at(&tag, at + 4)?,
+            })
+        })
+        .collect::<Result<Vec<Phrase>, String>>()?;
+    Ok(Structure {
+        mood: u16_at(&tag, PSSI_MASK_START)?,
+        end_beat: u16_at(&tag, 0x1A)?,
+        phrases,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_be_bytes()).collect()
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_be_bytes()).collect()
+    }
+
+    fn tag(kind: &[u8; 4], header: &[u8], body: &[u8]) -> Vec<u8> {
+        let len_header = 12 + header.len() as u32;
+        [kind, &u32s(&[len_header, len_header + body.len() as u32])[..], header, body].concat()
+    }
+
+    fn file(tags: &[Vec<u8>]) -> Vec<u8> {
+        let body = tags.concat();
+        [FILE_MAGIC, &u32s(&[0x1C, 0x1C + body.len() as u32])[..], &[0; 16], &body].concat()
+    }
+
+    /// 128 BPM from 100ms, starting with two beats before the first downbeat
+    fn beatgrid(count: u32) -> Vec<u8> {
+        let body: Vec<u8> = (0..count)
+            .flat_map(|i| [u16s(&[[3, 4, 1, 2][i as usize % 4], 12800]), u32s(&[100 + i * 469])].concat())
+            .collect();
+        tag(b"PQTZ", &u32s(&[0, 0x80000, count]), &body)
+    }
+
+    fn cue(hot_cue: u32, time_ms: u32, loop_end_ms: u32) -> Vec<u8> {
+        let body = [
+            u32s(&[hot_cue, 0, 0x10000]),
+            u16s(&[0xFFFF, 0xFFFF, 0x0100, 1000]),
+            u32s(&[time_ms, loop_end_ms]),
+        ]
+        .concat();
+        let mut entry = [&b"PCPT"[..], &u32s(&[0x1C, 0x38]), &body].concat();
+        entry.resize(0x38, 0);
+        entry
+    }
+
+    fn cues(entries: &[Vec<u8>]) -> Vec<u8> {
+        let header = [u32s(&[1]), u16s(&[0, entries.len() as u16]), u32s(&[u32::MAX])].concat();
+        tag(b"PCOB", &header, &entries.concat())
+    }
+
+    /// Three phrases of 32 beats, garbled like Rekordbox 6 writes them when `mask` is set
+    fn structure(mask: bool) -> Vec<u8> {
+        let count = 3;
+        let header = [u32s(&[24]), u16s(&[count, 2, 0, 0, 0, 200, 0, 0])].concat();
+        let entries: Vec<u8> = (0..count)
+            .flat_map(|i| [u16s(&[i + 1, 1 + i * 32, [1, 2, 5][i as usize]]), vec![0; 18]].concat())
+            .collect();
+        let mut tag = tag(b"PSSI", &header, &entries);
+        if mask {
+            for (i, byte) in tag[PSSI_MASK_START..].iter_mut().enumerate() {
+                *byte ^= PSSI_MASK[i % PSSI_MASK.len()].wrapping_add(count as u8);
+            }
+        }
+        tag
+    }
+
+    #[test]
+    fn tags() {
+        let other = tag(b"PVBR", &[0; 4], &[1; 400]);
+        let data = file(&[beatgrid(40), cues(&[cue(1, 100, NO_LOOP), cue(0, 2000, 4000)]), other]);
+        let analysis = Analysis::parse(&data).unwrap();
+
+        assert_eq!(analysis.beats.len(), 40);
+        let beat = &analysis.beats[5];
+        assert_eq!((beat.number, beat.bpm, beat.time_ms, beat.bar), (4, 128., 100 + 5 * 469, 1));
+        // The pickup beats are in bar 0
+        assert_eq!(analysis.beats[1].bar, 0);
+        assert_eq!(analysis.beats[2].bar, 1);
+
+        assert_eq!(analysis.cues.len(), 2);
+        assert_eq!((analysis.cues[0].hot_cue, analysis.cues[0].time_ms), (1, 100));
+        assert_eq!(analysis.cues[0].loop_end_ms, None);
+        assert_eq!(analysis.cues[1].loop_end_ms, Some(4000));
+        assert!(analysis.structure.is_none());
+    }
+
+    #[test]
+    fn phrases() {
+        for mask in [false, true] {
+            let analysis = Analysis::parse(&file(&[structure(mask)])).unwrap();
+            let structure = analysis.structure.unwrap();
+            assert_eq!((structure.mood, structure.end_beat), (2, 200));
+            let phrases: Vec<_> = structure.phrases.iter().map(|x| (x.index, x.beat, x.kind)).collect();
+            assert_eq!(phrases, [(1, 1, 1), (2, 33, 2), (3, 65, 5)]);
+        }
+    }
+
+    #[test]
+    fn position() {
+        let analysis = Analysis::parse(&file(&[beatgrid(4)])).unwrap();
+        assert!(analysis.position(99.).is_none());
+
+        // Halfway into the first downbeat
+        let position = analysis.position(100. + 2. * 469. + 234.5).unwrap();
+        assert_eq!(position.beats, 5);
+        assert!((position.phase - 0.5).abs() < 0.001, "{}", position.phase);
+        assert_eq!(position.bpm, 128.);
+
+        // The last beat lasts as long as 128 BPM says
+        assert!(analysis.position(100. + 3. * 469. + 468.).is_some());
+        assert!(analysis.position(100. + 3. * 469. + 469.).is_none());
+        assert!(Analysis::default().position(0.).is_none());
+    }
+
+    #[test]
+    fn broken() {
+        assert_eq!(Analysis::parse(b"PMAJ").unwrap_err(), "Not an ANLZ file");
+        let mut data = file(&[beatgrid(4)]);
+        data.truncate(data.len() - 1);
+        assert_eq!(Analysis::parse(&data).unwrap_err(), "Broken tag at 1C");
+
+        let mut entry = cue(1, 100, NO_LOOP);
+        entry[..4].copy_from_slice(b"PCPX");
+        assert_eq!(
+            Analysis::parse(&file(&[cues(&[entry])])).unwrap_err(),
+            "Expected a PCPT entry at 18 of PCOB"
+        );
+    }
+
+    #[test]
+    fn ext_next_to_dat() {
+        let dat = std::env::temp_dir().join(format!("rkbx_os2l_anlz_{}.DAT", std::process::id()));
+        let ext = dat.with_extension("EXT");
+        fs::write(&dat, file(&[beatgrid(8), cues(&[cue(1, 100, NO_LOOP)])])).unwrap();
+        fs::write(&ext, file(&[cues(&[cue(1, 100, NO_LOOP), cue(2, 500, NO_LOOP)]), structure(true)])).unwrap();
+        let analysis = Analysis::from_file(&dat);
+        fs::remove_file(&dat).unwrap();
+        fs::remove_file(&ext).unwrap();
+
+        let analysis = analysis.unwrap();
+        assert_eq!(analysis.beats.len(), 8);
+        // The cues of the .DAT are kept
+        assert_eq!(analysis.cues.len(), 1);
+        assert_eq!(analysis.structure.unwrap().phrases.len(), 3);
+    }
+}
@@ -0,0 +1,264 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    error::{Error, Result},
+    metadata::ContentSource,
+};
+
+// Rekordbox serves its library on a local API, the same one its own tools use:
+//
+//   GET /api/v1/data/djmdContents/<track id>/   ->   {"item": {"Title": ..., "FolderPath": ...}}
+//
+// with the bearer read from its memory. A missing track comes back as 404, a bearer that is
+// no longer accepted as 401, either as the status or as "code" in the body.
+
+/// Where Rekordbox serves its API unless given another with `-r`
+pub const DEFAULT_API_URL: &str = "http://127.0.0.1:30001";
+
+/// Lookups are made one after the other, so do not let one hold up the rest for long
+const API_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What Rekordbox's own tools send, which the API is known to accept
+const USER_AGENT: &str = "rekordbox/6.8.4.0001 Windows 11(64bit)";
+
+/// A track in the Rekordbox library, as far as we use it
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DjmdContent {
+    #[serde(default, deserialize_with = "lenient")]
+    pub title: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub artist_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub album_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub genre_name: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub key_name: Option<String>,
+    /// Hundredths of a BPM, see `bpm`
+    #[serde(rename = "BPM", default, deserialize_with = "lenient")]
+    pub bpm_centi: Option<u32>,
+    /// In seconds
+    #[serde(default, deserialize_with = "lenient")]
+    pub length: Option<u32>,
+    /// Full path of the file, despite the name
+    pub folder_path: String,
+    #[serde(rename = "FileNameL")]
+    pub file_name: String,
+    /// The ANLZ .DAT file, from the Rekordbox share folder, eg. /PIONEER/USBANLZ/...
+    #[serde(default, deserialize_with = "lenient")]
+    pub analysis_data_path: Option<String>,
+}
+
+impl DjmdContent {
+    pub fn bpm(&self) -> Option<f32> {
+        self.bpm_centi.map(|x| x as f32 / 100.)
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        self.length.map(|x| Duration::from_secs(x as u64))
+    }
+
+    /// The title, or the file name for tracks without one
+    pub fn display_title(&self) -> &str {
+        match &self.title {
+            Some(title) if !title.is_empty() => title,
+            _ => &self.file_name,
+        }
+    }
+}
+
+/// Fields Rekordbox leaves empty or gives another type are treated as missing, only the path
+/// is needed
+fn lenient<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
+
+#[derive(Deserialize)]
+struct ContentResponse {
+    code: Option<u16>,
+    item: Option<DjmdContent>,
+}
+
+/// Client for the Rekordbox API, kept for the whole run so connections are reused
+pub struct RekordboxApi {
+    client: reqwest::blocking::Client,
+    base_url: String,
+}
+
+impl RekordboxApi {
+    pub fn new(base_url: &str) -> Result<Self> {
+        Self::with_timeout(base_url, API_TIMEOUT)
+    }
+
+    fn with_timeout(base_url: &str, timeout: Duration) -> Result<Self> {
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(timeout)
+            .build()?;
+        Ok(Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+impl ContentSource for RekordboxApi {
+    /// The track with this id, or `None` if Rekordbox does not know it. A refused bearer gives
+    /// `Error::ApiUnauthorized`, read it again and retry.
+    fn content(&self, track_id: i32, bearer: &str) -> Result<Option<DjmdContent>> {
+        let response = self
+            .client
+            .get(format!("{}/api/v1/data/djmdContents/{track_id}/", self.base_url))
+            .header("Accept", "*/*")
+            .bearer_auth(bearer)
+            .send()?;
+        let status = response.status().as_u16();
+        let text = response.text()?;
+        // The body says what went wrong better than the status, when it parses
+        let response: Option<ContentResponse> = serde_json::from_str(&text).ok();
+        let code = response.as_ref().and_then(|x| x.code).unwrap_or(status);
+
+        match code {
+            401 => Err(Error::ApiUnauthorized),
+            404 => Ok(None),
+            200..=299 => match response.and_then(|x| x.item) {
+                Some(item) => Ok(Some(item)),
+                None => Err(Error::Api(format!("Unexpected answer for track {track_id}: {text}"))),
+            },
+            _ => Err(Error::Api(format!("Status {code} for track {track_id}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content() {
+        let text = r#"{"code": 200, "item": {
+            "ID": "12345", "Title": "Track", "ArtistName": "Artist", "AlbumName": "",
+            "GenreName": null, "KeyName": "8A", "BPM": 12800, "Length": 240,
+            "FolderPath": "D:/Music/track.mp3", "FileNameL": "track.mp3",
+            "AnalysisDataPath": "/PIONEER/USBANLZ/abc/ANLZ0000.DAT"
+        }}"#;
+        let response: ContentResponse = serde_json::from_str(text).unwrap();
+        assert_eq!(response.code, Some(200));
+        let content = response.item.unwrap();
+        assert_eq!(content.title.as_deref(), Some("Track"));
+        assert_eq!(content.artist_name.as_deref(), Some("Artist"));
+        assert_eq!(content.album_name.as_deref(), Some(""));
+        assert_eq!(content.genre_name, None);
+        assert_eq!(content.key_name.as_deref(), Some("8A"));
+        assert_eq!(content.bpm(), Some(128.));
+        assert_eq!(content.duration(), Some(Duration::from_secs(240)));
+        assert_eq!(content.folder_path, "D:/Music/track.mp3");
+        assert_eq!(content.file_name, "track.mp3");
+        assert_eq!(content.analysis_data_path.as_deref(), Some("/PIONEER/USBANLZ/abc/ANLZ0000.DAT"));
+        assert_eq!(content.display_title(), "Track");
+    }
+
+    #[test]
+    fn lenient_fields() {
+        // Anything but the path can be missing or of another type
+        let text = r#"{"BPM": "fast", "Length": -1, "Title": 7,
+            "FolderPath": "D:/Music/track.mp3", "FileNameL": "track.mp3"}"#;
+        let content: DjmdContent = serde_json::from_str(text).unwrap();
+        assert_eq!(content.bpm(), None);
+        assert_eq!(content.duration(), None);
+        assert_eq!(content.title, None);
+        assert_eq!(content.display_title(), "track.mp3");
+
+        assert!(serde_json::from_str::<DjmdContent>(r#"{"Title": "Track"}"#).is_err());
+    }
+
+    #[test]
+    fn error_body() {
+        let response: ContentResponse = serde_json::from_str(r#"{"code": 401, "message": "no"}"#).unwrap();
+        assert_eq!(response.code, Some(401));
+        assert!(response.item.is_none());
+    }
+
+    /// A local API answering every request with `status` and `body`, or not at all without a
+    /// status. Gives its address and the requests it got.
+    fn server(status: Option<u16>, body: &'static str) -> (String, std::sync::mpsc::Receiver<String>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                while reader.read_line(&mut request).unwrap() > 0 && !request.ends_with("\r\n\r\n") {}
+                let _ = tx.send(request);
+                let Some(status) = status else {
+                    // Hold the connection open without answering
+                    std::thread::sleep(Duration::from_secs(2));
+                    continue;
+                };
+                let length = body.len();
+                let response = format!("HTTP/1.1 {status} X\r\nContent-Length: {length}\r\n\r\n{body}");
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn answer(status: Option<u16>, body: &'static str) -> Result<Option<DjmdContent>> {
+        let (url, _) = server(status, body);
+        RekordboxApi::with_timeout(&url, Duration::from_millis(200))?.content(12345, "abc")
+    }
+
+    #[test]
+    fn request() {
+        let body = r#"{"code": 200, "item": {"FolderPath": "D:/Music/a.mp3", "FileNameL": "a.mp3"}}"#;
+        let (url, requests) = server(Some(200), body);
+        let content = RekordboxApi::new(&format!("{url}/")).unwrap().content(12345, "abc").unwrap();
+        assert_eq!(content.unwrap().folder_path, "D:/Music/a.mp3");
+
+        let request = requests.recv().unwrap().to_lowercase();
+        assert!(request.starts_with("get /api/v1/data/djmdcontents/12345/ http/1.1\r\n"), "{request}");
+        assert!(request.contains("\r\nauthorization: bearer abc\r\n"), "{request}");
+        let user_agent = format!("\r\nuser-agent: {}\r\n", USER_AGENT.to_lowercase());
+        assert!(request.contains(&user_agent), "{request}");
+    }
+
+    #[test]
+    fn stale_bearer() {
+        assert!(matches!(answer(Some(401), ""), Err(Error::ApiUnauthorized)));
+        // Rekordbox also answers 200 with the code in the body
+        assert!(matches!(answer(Some(200), r#"{"code": 401}"#), Err(Error::ApiUnauthorized)));
+    }
+
+    #[test]
+    fn unknown_track() {
+        assert!(matches!(answer(Some(404), ""), Ok(None)));
+        assert!(matches!(answer(Some(200), r#"{"code": 404, "item": null}"#), Ok(None)));
+    }
+
+    #[test]
+    fn failures() {
+        assert!(matches!(
+            answer(Some(500), "oops"),
+            Err(Error::Api(e)) if e == "Status 500 for track 12345"
+        ));
+        assert!(matches!(
+            answer(Some(200), "{}"),
+            Err(Error::Api(e)) if e == "Unexpected answer for track 12345: {}"
+        ));
+        let started = std::time::Instant::now();
+        assert!(matches!(answer(None, ""), Err(Error::Api(_))));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
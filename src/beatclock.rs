@@ -0,0 +1,365 @@
+use std::time::Duration;
+
+// Rekordbox's beat counter changes some time after the beat, a poll late at best, and the grid
+// position moves in steps whenever Rekordbox updates the deck time. The clock follows either
+// with a phase-locked loop: it runs at the tempo it has fitted, and every observed position
+// corrects that tempo a little and pulls the phase in over the next beat instead of jumping to
+// it. What it gives is smooth, only moves forward while playing, and tells when the next beat
+// is due.
+//
+// Times are counted from whatever the caller likes, so the clock can be fed made up beats.
+
+/// Part of a phase error that is pulled in over the next beat
+const PHASE_GAIN: f64 = 0.5;
+
+/// Part of a phase error per beat that goes into the tempo, critically damped with `PHASE_GAIN`
+const TEMPO_GAIN: f64 = 0.0625;
+
+/// A bigger error is a jump in the track or a missed beat, the clock starts over from it
+const RESYNC_BEATS: f64 = 0.25;
+
+/// The fitted tempo stays this close to the one Rekordbox shows
+const MAX_TEMPO_DEVIATION: f64 = 0.05;
+
+#[derive(Clone, Copy)]
+struct Lock {
+    /// Beats at `time`
+    position: f64,
+    time: Duration,
+    /// Fitted beats per second
+    tempo: f64,
+    /// Beats per second until the next observation, the tempo with the phase correction
+    rate: f64,
+}
+
+impl Lock {
+    fn position(&self, time: Duration) -> f64 {
+        self.position + time.saturating_sub(self.time).as_secs_f64() * self.rate
+    }
+}
+
+/// Beat position fitted to what Rekordbox shows, in beats counted like its bar and beat counter
+#[derive(Default)]
+pub struct BeatClock {
+    lock: Option<Lock>,
+    /// Beats per second from Rekordbox's tempo
+    nominal: f64,
+    /// Stays in place while the deck is stopped
+    held: bool,
+    /// Last beat given out by [`BeatClock::beat`]
+    emitted: Option<i32>,
+}
+
+impl BeatClock {
+    /// Forget the phase, the next observation is taken as it is
+    pub fn reset(&mut self) {
+        self.lock = None;
+        self.emitted = None;
+    }
+
+    /// Rekordbox's tempo. The fitted tempo starts over from it when it changes.
+    pub fn set_bpm(&mut self, time: Duration, bpm: f32) {
+        let nominal = bpm as f64 / 60.;
+        if nominal == self.nominal {
+            return;
+        }
+        self.nominal = nominal;
+        if let Some(lock) = &mut self.lock {
+            lock.position = lock.position(time);
+            lock.time = time;
+            lock.tempo = nominal;
+            lock.rate = if self.held { 0. } else { nominal };
+        }
+    }
+
+    /// Stop where the clock is, for a deck that is not playing
+    pub fn hold(&mut self, time: Duration) {
+        if let Some(lock) = &mut self.lock {
+            if !self.held {
+                lock.position = lock.position(time);
+                lock.time = time;
+                lock.rate = 0.;
+            }
+        }
+        self.held = true;
+    }
+
+    /// Run on from where the clock was held
+    pub fn resume(&mut self, time: Duration) {
+        if let Some(lock) = &mut self.lock {
+            if self.held {
+                lock.time = time;
+                lock.rate = lock.tempo;
+            }
+        }
+        self.held = false;
+    }
+
+    /// The deck was at `position` beats at `time`
+    pub fn observe(&mut self, time: Duration, position: f64) {
+        let Some(lock) = &mut self.lock else {
+            self.start(time, position);
+            return;
+        };
+        let error = position - lock.position(time);
+        if self.held || error.abs() > RESYNC_BEATS || self.nominal <= 0. {
+            self.start(time, position);
+            return;
+        }
+
+        let beat = 1. / lock.tempo;
+        let elapsed = time.saturating_sub(lock.time).as_secs_f64();
+        lock.position += elapsed * lock.rate;
+        lock.time = time;
+        lock.tempo = (lock.tempo + TEMPO_GAIN * error * elapsed / (beat * beat)).clamp(
+            self.nominal * (1. - MAX_TEMPO_DEVIATION),
+            self.nominal * (1. + MAX_TEMPO_DEVIATION),
+        );
+        lock.rate = lock.tempo + PHASE_GAIN * error / beat;
+    }
+
+    fn start(&mut self, time: Duration, position: f64) {
+        // The fitted tempo carries over a jump in the track, unless Rekordbox's has moved away
+        let tempo = self.lock.map_or(self.nominal, |x| x.tempo);
+        let tempo = if tempo > 0. && (tempo / self.nominal - 1.).abs() <= MAX_TEMPO_DEVIATION {
+            tempo
+        } else {
+            self.nominal
+        };
+        self.lock = Some(Lock {
+            position,
+            time,
+            tempo,
+            rate: if self.held { 0. } else { tempo },
+        });
+    }
+
+    /// Beats at `time`, `None` until something was observed
+    pub fn position(&self, time: Duration) -> Option<f64> {
+        Some(self.lock?.position(time))
+    }
+
+    /// The fitted tempo in BPM, `None` until something was observed
+    pub fn bpm(&self) -> Option<f32> {
+        Some((self.lock?.tempo * 60.) as f32)
+    }
+
+    /// How far into the beat the clock is at `time`, 0 to 1
+    pub fn phase(&self, time: Duration) -> Option<f32> {
+        Some(self.position(time)?.rem_euclid(1.) as f32)
+    }
+
+    /// The beat the clock is in at `time`, if it was not given out before. A resync can land
+    /// a little behind where the clock was, its beat went out already; only a jump back by more
+    /// than a beat is a new one.
+    pub fn beat(&mut self, time: Duration) -> Option<i32> {
+        let beat = self.position(time)?.floor() as i32;
+        if self.emitted.is_some_and(|x| (x - 1..=x).contains(&beat)) {
+            return None;
+        }
+        self.emitted = Some(beat);
+        Some(beat)
+    }
+
+    /// When the beat after `time` is due, `None` while held or without a tempo
+    pub fn next_beat(&self, time: Duration) -> Option<Duration> {
+        let lock = self.lock?;
+        if lock.rate <= 0. {
+            return None;
+        }
+        let position = lock.position(time);
+        let beats = position.floor() + 1. - position;
+        Some(time + Duration::from_secs_f64(beats / lock.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLL: Duration = Duration::from_millis(16);
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    /// Poll a playing clock from `from` to `to`, observing what `observed` gives for each poll,
+    /// and collect the beats it gives out.
+    fn run(
+        clock: &mut BeatClock,
+        from: Duration,
+        to: Duration,
+        mut observed: impl FnMut(Duration) -> Option<(Duration, f64)>,
+    ) -> Vec<i32> {
+        let mut beats = vec![];
+        let mut time = from;
+        while time < to {
+            if let Some((at, position)) = observed(time) {
+                clock.observe(at, position);
+            }
+            beats.extend(clock.beat(time));
+            time += POLL;
+        }
+        beats
+    }
+
+    fn started(bpm: f32) -> BeatClock {
+        let mut clock = BeatClock::default();
+        clock.set_bpm(Duration::ZERO, bpm);
+        clock.observe(Duration::ZERO, 0.);
+        clock
+    }
+
+    #[test]
+    fn steady_lock() {
+        // 120 bpm, the grid position in steps of 10ms like Rekordbox's deck time
+        let mut clock = started(120.);
+        let grid = |time: Duration| {
+            let step = ms(time.as_millis() as u64 / 10 * 10);
+            Some((time, step.as_secs_f64() * 2.))
+        };
+        let beats = run(&mut clock, Duration::ZERO, ms(10_000), grid);
+        assert_eq!(beats, (0..20).collect::<Vec<_>>());
+
+        let position = clock.position(ms(10_000)).unwrap();
+        assert!((position - 20.).abs() < 0.01, "{position}");
+        let next = clock.next_beat(ms(10_100)).unwrap();
+        assert!(next.abs_diff(ms(10_500)) < ms(5), "{next:?}");
+    }
+
+    #[test]
+    fn late_counter() {
+        // The counter moves 30ms after the beat, and is seen a poll later
+        let mut clock = started(120.);
+        let mut last = 0;
+        let counter = |time: Duration| {
+            let beats = time.saturating_sub(ms(30)).as_millis() as i32 / 500;
+            if beats == last {
+                return None;
+            }
+            last = beats;
+            Some((time.saturating_sub(POLL / 2), beats as f64))
+        };
+        let beats = run(&mut clock, POLL, ms(20_000), counter);
+        assert_eq!(beats, (0..40).collect::<Vec<_>>());
+
+        // Behind by about the lag, on tempo
+        let lag = 40. - clock.position(ms(20_000)).unwrap();
+        assert!((0.0..0.1).contains(&lag), "{lag}");
+        let bpm = clock.bpm().unwrap();
+        assert!((bpm - 120.).abs() < 0.6, "{bpm}");
+    }
+
+    /// Xorshift, so the jitter is the same on every run
+    fn jitter(seed: u64) -> impl FnMut() -> Duration {
+        let mut state = seed;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            Duration::from_micros(state % 16_001)
+        }
+    }
+
+    #[test]
+    fn jittered_observations() {
+        // Rekordbox shows 120 but the track plays at 121, every position is 0-16ms old when read
+        let rate = 121. / 60.;
+        let mut clock = started(120.);
+        let mut delay = jitter(0x5EED);
+        let mut worst = 0f64;
+        let mut time = POLL;
+        while time < ms(30_000) {
+            clock.observe(time, time.saturating_sub(delay()).as_secs_f64() * rate);
+            if time > ms(10_000) {
+                // Behind by the 8ms the reads are late on average, give or take the jitter
+                let error = time.as_secs_f64() * rate - clock.position(time).unwrap();
+                worst = worst.max((error - 0.008 * rate).abs());
+            }
+            time += POLL;
+        }
+        assert!(worst < 0.01, "phase error of {worst} beats");
+        let bpm = clock.bpm().unwrap();
+        assert!((bpm - 121.).abs() < 0.1, "{bpm}");
+    }
+
+    #[test]
+    fn small_step_is_pulled_in() {
+        let mut clock = started(120.);
+        let grid = |time: Duration| Some((time, time.as_secs_f64() * 2.));
+        run(&mut clock, Duration::ZERO, ms(5_000), grid);
+
+        // The track moves a tenth of a beat back, the clock slows down instead of jumping
+        let step = |time: Duration| Some((time, time.as_secs_f64() * 2. - 0.1));
+        let mut time = ms(5_000);
+        let mut last = clock.position(time).unwrap();
+        while time < ms(20_000) {
+            let (at, position) = step(time).unwrap();
+            clock.observe(at, position);
+            let now = clock.position(time).unwrap();
+            assert!(now >= last, "went back from {last} to {now}");
+            last = now;
+            time += POLL;
+        }
+        let error = clock.position(time).unwrap() - step(time).unwrap().1;
+        assert!(error.abs() < 0.002, "{error}");
+    }
+
+    #[test]
+    fn backward_jump() {
+        let mut clock = started(120.);
+        let grid = |time: Duration| Some((time, time.as_secs_f64() * 2.));
+        run(&mut clock, Duration::ZERO, ms(10_050), grid);
+        assert_eq!(clock.emitted, Some(20));
+
+        // A resync to just behind the beat that went out does not give it again
+        let behind = |time: Duration| Some((time, (time - ms(400)).as_secs_f64() * 2.));
+        let beats = run(&mut clock, ms(10_050), ms(11_000), behind);
+        assert_eq!(beats, vec![21]);
+
+        // A jump back in the track does
+        let seek = |time: Duration| Some((time, (time - ms(5_000)).as_secs_f64() * 2.));
+        let beats = run(&mut clock, ms(11_000), ms(12_000), seek);
+        assert_eq!(beats, vec![12, 13]);
+    }
+
+    #[test]
+    fn hold_and_release() {
+        let mut clock = started(120.);
+        let grid = |time: Duration| Some((time, time.as_secs_f64() * 2.));
+        run(&mut clock, Duration::ZERO, ms(2_000), grid);
+        let held_at = clock.position(ms(2_000)).unwrap();
+        assert_eq!(clock.beat(ms(2_000)), Some(4));
+
+        clock.hold(ms(2_000));
+        assert_eq!(clock.position(ms(3_000)), Some(held_at));
+        assert_eq!(clock.next_beat(ms(3_000)), None);
+        assert_eq!(clock.beat(ms(3_000)), None);
+
+        // Plays on from where it stopped
+        clock.resume(ms(3_000));
+        let position = clock.position(ms(3_250)).unwrap();
+        assert!((position - held_at - 0.5).abs() < 0.01, "{position}");
+        assert!(clock.next_beat(ms(3_250)).is_some());
+    }
+
+    #[test]
+    fn tempo_change() {
+        let mut clock = started(120.);
+        let grid = |time: Duration| Some((time, time.as_secs_f64() * 2.));
+        run(&mut clock, Duration::ZERO, ms(4_000), grid);
+
+        // The position carries on, at the new tempo
+        let before = clock.position(ms(4_000)).unwrap();
+        clock.set_bpm(ms(4_000), 150.);
+        assert_eq!(clock.position(ms(4_000)), Some(before));
+        let position = clock.position(ms(4_400)).unwrap();
+        assert!((position - before - 1.).abs() < 0.01, "{position}");
+
+        let faster = |time: Duration| Some((time, 8. + (time - ms(4_000)).as_secs_f64() * 2.5));
+        let beats = run(&mut clock, ms(4_000), ms(8_000), faster);
+        assert_eq!(beats, (8..18).collect::<Vec<_>>());
+        let next = clock.next_beat(ms(8_100)).unwrap();
+        assert!(next.abs_diff(ms(8_400)) < ms(5), "{next:?}");
+    }
+}
@@ -0,0 +1,206 @@
+use std::{env, fs, io::ErrorKind, path::PathBuf};
+
+use crate::{
+    api::DEFAULT_API_URL,
+    error::{Error, Result},
+    rewrite::{Normalization, PathRewrite, Rule},
+};
+
+// Settings that do not change from run to run live in ./config next to the offsets, one
+// `key = value` per line, with lines starting with # left out. Paths can have # in them, so
+// there are no comments after a value:
+//
+//   metadata = database
+//   database = D:\Pioneer\rekordbox\master.db
+//   database_key = <key>
+//
+// Flags given on the command line win over the file. Without a file the defaults are used.
+
+/// Where track paths and titles are looked up
+#[derive(Clone, Copy, PartialEq)]
+pub enum MetadataSource {
+    /// The local API Rekordbox serves, with the bearer read from its memory
+    Api,
+    /// The library database master.db, read directly
+    Database,
+}
+
+pub struct Config {
+    pub metadata: MetadataSource,
+    pub api_url: String,
+    /// master.db, in the Rekordbox folder of the user's AppData unless given
+    pub database: Option<PathBuf>,
+    /// The SQLCipher key of master.db, which Rekordbox does not publish so it is not shipped here
+    pub database_key: Option<String>,
+    /// Where Rekordbox keeps the ANLZ files, see `analysis_folder`
+    pub analysis_folder: Option<PathBuf>,
+    /// Changes to the track paths before they are sent, see rewrite.rs
+    pub rewrite: PathRewrite,
+    /// Milliseconds beats are sent ahead of the music, for lights that react late
+    pub lookahead: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            metadata: MetadataSource::Api,
+            api_url: DEFAULT_API_URL.to_string(),
+            database: None,
+            database_key: None,
+            analysis_folder: None,
+            rewrite: PathRewrite::default(),
+            lookahead: 0.,
+        }
+    }
+}
+
+/// Rekordbox's folder in the user's AppData, where it keeps the library
+pub fn rekordbox_folder() -> Option<PathBuf> {
+    Some(PathBuf::from(env::var_os("APPDATA")?).join("Pioneer").join("rekordbox"))
+}
+
+impl Config {
+    /// The folder the analysis paths of tracks start from, the share folder of Rekordbox unless
+    /// given
+    pub fn analysis_folder(&self) -> Option<PathBuf> {
+        self.analysis_folder
+            .clone()
+            .or_else(|| Some(rekordbox_folder()?.join("share")))
+    }
+
+    /// Read the config file, or the defaults if there is none
+    pub fn from_file(path: &str) -> Result<Config> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => {
+                return Err(Error::Config {
+                    line: None,
+                    message: format!("{path}: {e}"),
+                })
+            }
+        };
+        Config::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Config> {
+        let mut config = Config::default();
+        for (i, row) in text.lines().enumerate() {
+            let error = |message: String| Error::Config { line: Some(i + 1), message };
+            let row = row.trim();
+            if row.is_empty() || row.starts_with('#') {
+                continue;
+            }
+            let (key, value) = row
+                .split_once('=')
+                .ok_or_else(|| error(format!("Expected 'key = value', got '{row}'")))?;
+            let value = value.trim();
+            match key.trim() {
+                "metadata" => {
+                    config.metadata = match value {
+                        "api" => MetadataSource::Api,
+                        "database" => MetadataSource::Database,
+                        _ => return Err(error(format!("metadata is api or database, not '{value}'"))),
+                    }
+                }
+                "api_url" => config.api_url = value.to_string(),
+                "database" => config.database = Some(PathBuf::from(value)),
+                "database_key" => config.database_key = Some(value.to_string()),
+                "analysis_folder" => config.analysis_folder = Some(PathBuf::from(value)),
+                "rewrite" => config.rewrite.rules.push(Rule::parse(value).map_err(error)?),
+                "normalize" => {
+                    config.rewrite.normalization = Some(
+                        Normalization::parse(value)
+                            .ok_or_else(|| error(format!("normalize is nfc or nfd, not '{value}'")))?,
+                    )
+                }
+                "lookahead" => {
+                    config.lookahead = value
+                        .parse()
+                        .map_err(|_| error(format!("lookahead is in milliseconds, not '{value}'")))?
+                }
+                key => return Err(error(format!("Unknown key '{key}'"))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Line and message of the error `text` gives
+    fn error(text: &str) -> (Option<usize>, String) {
+        match Config::parse(text) {
+            Err(Error::Config { line, message }) => (line, message),
+            Err(e) => panic!("not a config error: {e}"),
+            Ok(_) => panic!("parsed"),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::parse("").unwrap();
+        assert!(config.metadata == MetadataSource::Api);
+        assert_eq!(config.api_url, DEFAULT_API_URL);
+        assert!(config.database.is_none());
+        assert!(config.rewrite.rules.is_empty());
+        assert_eq!(config.lookahead, 0.);
+    }
+
+    #[test]
+    fn parses_every_key() {
+        let config = Config::parse(
+            "\
+# Comments and empty lines are left out
+
+metadata = database
+database = D:\\Pioneer\\#1\\master.db
+database_key = abc = def
+api_url = http://127.0.0.1:30002
+  analysis_folder=/mnt/share
+rewrite = prefix D:/Music => //nas/music
+rewrite = drive E => F
+normalize = NFC
+lookahead = 40.5
+",
+        )
+        .unwrap();
+        assert!(config.metadata == MetadataSource::Database);
+        assert_eq!(config.database, Some(PathBuf::from("D:\\Pioneer\\#1\\master.db")));
+        // Only the first = splits
+        assert_eq!(config.database_key.as_deref(), Some("abc = def"));
+        assert_eq!(config.api_url, "http://127.0.0.1:30002");
+        assert_eq!(config.analysis_folder(), Some(PathBuf::from("/mnt/share")));
+        assert_eq!(config.rewrite.rules.len(), 2);
+        assert_eq!(config.rewrite.apply("E:/Music/a.mp3"), "F:/Music/a.mp3");
+        assert!(matches!(config.rewrite.normalization, Some(Normalization::Nfc)));
+        assert_eq!(config.lookahead, 40.5);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(error("metadata = api\nplayer = cdj"), (Some(2), "Unknown key 'player'".to_string()));
+        assert_eq!(error("\n\nmetadata"), (Some(3), "Expected 'key = value', got 'metadata'".to_string()));
+        assert_eq!(
+            error("metadata = xml"),
+            (Some(1), "metadata is api or database, not 'xml'".to_string())
+        );
+        assert_eq!(
+            error("lookahead = soon"),
+            (Some(1), "lookahead is in milliseconds, not 'soon'".to_string())
+        );
+        assert_eq!(error("normalize = nfkc").1, "normalize is nfc or nfd, not 'nfkc'");
+        assert_eq!(
+            error("# rules\nrewrite = move a => b"),
+            (Some(2), "Unknown rule 'move', expected prefix, drive or regex".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_file_is_the_defaults() {
+        let path = std::env::temp_dir().join("rkbx_os2l_no_such_config");
+        assert!(Config::from_file(path.to_str().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::{
+    api::DjmdContent,
+    config::rekordbox_folder,
+    error::{Error, Result},
+    metadata::ContentSource,
+};
+
+// Rekordbox 6 and later keep the library in master.db, an SQLCipher database. The tracks are
+// in djmdContent, with the artist, album, genre and key in tables of their own. Rows are only
+// marked as deleted, so those are skipped.
+
+const CONTENT_QUERY: &str = "
+    SELECT c.Title, a.Name, al.Name, g.Name, k.ScaleName, c.BPM, c.Length, c.FolderPath,
+        c.FileNameL, c.AnalysisDataPath
+    FROM djmdContent c
+    LEFT JOIN djmdArtist a ON a.ID = c.ArtistID
+    LEFT JOIN djmdAlbum al ON al.ID = c.AlbumID
+    LEFT JOIN djmdGenre g ON g.ID = c.GenreID
+    LEFT JOIN djmdKey k ON k.ID = c.KeyID
+    WHERE c.ID = ?1 AND c.rb_local_deleted = 0";
+
+/// master.db in the default Rekordbox folder
+pub fn default_path() -> Option<PathBuf> {
+    Some(rekordbox_folder()?.join("master.db"))
+}
+
+/// The Rekordbox library, opened read-only so Rekordbox can keep writing to it
+pub struct MasterDb {
+    connection: Connection,
+}
+
+impl MasterDb {
+    pub fn open(path: &Path, key: &str) -> Result<Self> {
+        let error = |e: rusqlite::Error| Error::Database(format!("{}: {e}", path.display()));
+        let connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(error)?;
+        connection.pragma_update(None, "key", key).map_err(error)?;
+        // A wrong key only shows on the first read
+        connection
+            .query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|e| Error::Database(format!("{}: {e}, is database_key right?", path.display())))?;
+        Ok(Self { connection })
+    }
+}
+
+impl ContentSource for MasterDb {
+    fn content(&self, track_id: i32, _bearer: &str) -> Result<Option<DjmdContent>> {
+        self.connection
+            .query_row(CONTENT_QUERY, params![track_id.to_string()], |row| {
+                Ok(DjmdContent {
+                    title: row.get(0)?,
+                    artist_name: row.get(1)?,
+                    album_name: row.get(2)?,
+                    genre_name: row.get(3)?,
+                    key_name: row.get(4)?,
+                    bpm_centi: row.get(5)?,
+                    length: row.get(6)?,
+                    folder_path: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                    file_name: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+                    analysis_data_path: row.get(9)?,
+                })
+            })
+            .optional()
+            .map_err(|e| Error::Database(format!("Track {track_id}: {e}")))
+    }
+
+    fn track_ids(&self) -> Result<Vec<i32>> {
+        let error = |e: rusqlite::Error| Error::Database(e.to_string());
+        let mut statement = self
+            .connection
+            .prepare("SELECT ID FROM djmdContent WHERE rb_local_deleted = 0")
+            .map_err(error)?;
+        let ids = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(error)?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .map_err(error)?;
+        // Rekordbox only reads numeric ids into its decks
+        Ok(ids.iter().filter_map(|x| x.parse().ok()).collect())
+    }
+
+    fn uses_bearer(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "test key";
+
+    /// A master.db with tracks 1, deleted 2, 3 without artist and the like, and a non-numeric id
+    fn fixture(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_{name}_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let connection = Connection::open(&path).unwrap();
+        connection.pragma_update(None, "key", KEY).unwrap();
+        connection
+            .execute_batch(
+                "
+                CREATE TABLE djmdContent (ID VARCHAR(255) PRIMARY KEY, Title VARCHAR(255),
+                    ArtistID VARCHAR(255), AlbumID VARCHAR(255), GenreID VARCHAR(255),
+                    KeyID VARCHAR(255), BPM INTEGER, Length INTEGER, FolderPath VARCHAR(255),
+                    FileNameL VARCHAR(255), AnalysisDataPath VARCHAR(255), rb_local_deleted INTEGER);
+                CREATE TABLE djmdArtist (ID VARCHAR(255) PRIMARY KEY, Name VARCHAR(255));
+                CREATE TABLE djmdAlbum (ID VARCHAR(255) PRIMARY KEY, Name VARCHAR(255));
+                CREATE TABLE djmdGenre (ID VARCHAR(255) PRIMARY KEY, Name VARCHAR(255));
+                CREATE TABLE djmdKey (ID VARCHAR(255) PRIMARY KEY, ScaleName VARCHAR(255));
+                INSERT INTO djmdArtist VALUES ('10', 'Artist');
+                INSERT INTO djmdAlbum VALUES ('20', 'Album');
+                INSERT INTO djmdGenre VALUES ('30', 'House');
+                INSERT INTO djmdKey VALUES ('40', '8A');
+                INSERT INTO djmdContent VALUES ('1', 'Track', '10', '20', '30', '40', 12800, 240,
+                    'D:/Music/a.mp3', 'a.mp3', '/PIONEER/USBANLZ/abc/ANLZ0000.DAT', 0);
+                INSERT INTO djmdContent VALUES ('2', 'Deleted', NULL, NULL, NULL, NULL, 12000, 200,
+                    'D:/Music/b.mp3', 'b.mp3', NULL, 1);
+                INSERT INTO djmdContent VALUES ('3', NULL, '11', NULL, NULL, NULL, NULL, NULL,
+                    'D:/Music/c.mp3', 'c.mp3', NULL, 0);
+                INSERT INTO djmdContent VALUES ('x', 'Odd', NULL, NULL, NULL, NULL, NULL, NULL,
+                    'D:/Music/d.mp3', 'd.mp3', NULL, 0);
+                ",
+            )
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn looks_tracks_up() {
+        let path = fixture("master");
+        let db = MasterDb::open(&path, KEY);
+        let _ = std::fs::remove_file(&path);
+        let db = db.unwrap();
+
+        let track = db.content(1, "").unwrap().unwrap();
+        assert_eq!(track.title.as_deref(), Some("Track"));
+        assert_eq!(track.artist_name.as_deref(), Some("Artist"));
+        assert_eq!(track.album_name.as_deref(), Some("Album"));
+        assert_eq!(track.genre_name.as_deref(), Some("House"));
+        assert_eq!(track.key_name.as_deref(), Some("8A"));
+        assert_eq!(track.bpm(), Some(128.));
+        assert_eq!(track.length, Some(240));
+        assert_eq!(track.folder_path, "D:/Music/a.mp3");
+        assert_eq!(track.file_name, "a.mp3");
+        assert_eq!(track.analysis_data_path.as_deref(), Some("/PIONEER/USBANLZ/abc/ANLZ0000.DAT"));
+
+        // Joins that find nothing are left empty
+        let track = db.content(3, "").unwrap().unwrap();
+        assert_eq!((track.title, track.artist_name, track.bpm_centi), (None, None, None));
+
+        assert!(db.content(2, "").unwrap().is_none());
+        assert!(db.content(99, "").unwrap().is_none());
+        assert_eq!(db.track_ids().unwrap(), [1, 3]);
+        assert!(!db.uses_bearer());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let path = fixture("master_key");
+        let db = MasterDb::open(&path, "not the key");
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(db, Err(Error::Database(e)) if e.ends_with("is database_key right?")));
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = std::env::temp_dir().join("rkbx_os2l_no_such_master.db");
+        let db = MasterDb::open(&path, KEY);
+        assert!(matches!(db, Err(Error::Database(e)) if e.starts_with(&path.display().to_string())));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading Rekordbox memory failed, usually a wrong version or Rekordbox was closed
+    Memory(String),
+    /// The offsets file could not be read or parsed, at a line if the problem is on one
+    Offsets { line: Option<usize>, message: String },
+    /// The running Rekordbox version could not be read or has no offsets
+    Version(String),
+    /// The config file could not be read or parsed, at a line if the problem is on one
+    Config { line: Option<usize>, message: String },
+    /// Fetching a new offsets file failed, the old one is left alone
+    Update(String),
+    /// The Rekordbox API request failed or returned something unexpected
+    Api(String),
+    /// The Rekordbox API refused the bearer, which needs reading from memory again
+    ApiUnauthorized,
+    /// The Rekordbox library database could not be opened or read
+    Database(String),
+    /// The VirtualDJ database could not be read or written
+    Export(String),
+    /// SoundSwitch could not be found on the network
+    Discovery(String),
+    /// Sending to or connecting to SoundSwitch failed
+    Os2l(std::io::Error),
+    /// A command was given the wrong arguments, or needs something that is not there yet
+    Usage(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Memory(message) => write!(f, "Memory read failed: {message}"),
+            Error::Offsets { line: None, message } => write!(f, "Offsets file: {message}"),
+            Error::Offsets { line: Some(line), message } => {
+                write!(f, "Offsets file line {line}: {message}")
+            }
+            Error::Version(message) => write!(f, "Rekordbox version: {message}"),
+            Error::Config { line: None, message } => write!(f, "Config file: {message}"),
+            Error::Config { line: Some(line), message } => {
+                write!(f, "Config file line {line}: {message}")
+            }
+            Error::Update(message) => write!(f, "Offsets update: {message}"),
+            Error::Api(message) => write!(f, "Rekordbox API: {message}"),
+            Error::ApiUnauthorized => write!(f, "Rekordbox API: the API key was refused"),
+            Error::Database(message) => write!(f, "Rekordbox database: {message}"),
+            Error::Export(message) => write!(f, "VirtualDJ database: {message}"),
+            Error::Discovery(message) => write!(f, "SoundSwitch discovery: {message}"),
+            Error::Os2l(e) => write!(f, "SoundSwitch connection: {e}"),
+            Error::Usage(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Api(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Api(e.to_string())
+    }
+}
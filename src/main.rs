@@ -1,191 +1,549 @@
-use std::process::Command;
 use std::{
     env,
-    io::{stdout, Write},
-    marker::PhantomData,
-    path::Path,
+    io::{stdin, stdout, Write},
+    path::{Path, PathBuf},
     sync::mpsc::channel,
     thread::{sleep, spawn},
     time::{Duration, Instant},
 };
-use toy_arms::external::{read, Process};
-use winapi::um::winnt::HANDLE;
+
+mod error;
+use error::{Error, Result};
+
+mod memory;
+#[cfg(target_os = "linux")]
+mod wine;
+use memory::{read, save_snapshot, FakeMemory, MemorySource, Plain, ProcessMemory};
+
+mod replay;
+use replay::{RecordingSource, ReplaySource};
+
+mod pe;
+use pe::{FileVersion, PeImage};
+
+mod signature;
+use signature::resolve_signatures;
 
 mod offsets;
-use offsets::{Pointer, RekordboxOffsets};
+use offsets::{
+    field_type, DeckOffsets, FieldType, OffsetsFile, Pointer, RekordboxOffsets, Version, MAX_DECKS,
+};
+
+mod scan;
+use scan::{Filter, PointerScan, ValueScan};
+
+mod verify;
+
+mod update;
+use update::{update_offsets, DEFAULT_MIRROR};
+
+mod api;
+use api::RekordboxApi;
+
+mod metadata;
+use metadata::{read_analysis, ContentSource, MetadataFetcher, Track};
+
+mod anlz;
+use anlz::{Analysis, GridPosition};
+
+mod beatclock;
+use beatclock::BeatClock;
+
+mod config;
+use config::{Config, MetadataSource};
+
+mod rewrite;
+use rewrite::PathRewrite;
+
+#[cfg(feature = "database")]
+mod database;
+
+mod os2l;
+use os2l::{windows_path, Os2lState};
+
+mod vdj;
+use vdj::{merge_database, VdjSong};
 
 mod soundswitch;
-use soundswitch::SoundSwitchConnector;
+use soundswitch::{SoundSwitchConnectionAddr, SoundSwitchConnector};
 
-use serde_json;
 
+#[cfg(windows)]
 extern "C" {
     fn _getch() -> core::ffi::c_char;
 }
 
+#[cfg(windows)]
 fn getch() -> i8 {
     unsafe { _getch() }
 }
 
-struct Value<T> {
-    address: usize,
-    handle: HANDLE,
-    _marker: PhantomData<T>,
+// No unbuffered console input without extra crates, so keys need Enter on other platforms
+#[cfg(not(windows))]
+fn getch() -> i8 {
+    use std::io::Read;
+    let mut buf = [0u8];
+    while std::io::stdin().read_exact(&mut buf).is_err() {
+        sleep(Duration::from_secs(1));
+    }
+    buf[0] as i8
 }
 
-impl<T> Value<T> {
-    fn new(h: HANDLE, base: usize, offsets: Pointer) -> Value<T> {
-        let mut address = base;
+/// When to walk a value's pointer chain again. Rekordbox reallocates deck objects when
+/// loading tracks or reopening panels, which leaves the cached address pointing at stale memory.
+struct ResolvePolicy<T> {
+    /// Re-walk every this many reads, 0 to never do so periodically
+    every_polls: u32,
+    /// Re-walk when the read fails
+    on_failure: bool,
+    /// Re-walk when the value read does not look right
+    sanity: Option<fn(&T) -> bool>,
+}
 
-        for offset in offsets.offsets {
-            address = read::<usize>(h, address + offset)
-                .expect("Memory read failed, check your Rekordbox version!");
+impl<T> ResolvePolicy<T> {
+    fn new(every_polls: u32, sanity: Option<fn(&T) -> bool>) -> Self {
+        Self {
+            every_polls,
+            on_failure: true,
+            sanity,
         }
-        address += offsets.final_offset;
+    }
+}
 
-        Value::<T> {
+struct Value<T> {
+    name: String,
+    base: usize,
+    pointer: Pointer,
+    policy: ResolvePolicy<T>,
+    address: Option<usize>,
+    polls: u32,
+    last: T,
+    /// Set while neither the cached nor a freshly resolved address gives a sane value
+    stale: bool,
+}
+
+impl<T: Plain + Default> Value<T> {
+    fn new(
+        name: impl Into<String>,
+        source: &dyn MemorySource,
+        base: usize,
+        pointer: Pointer,
+        policy: ResolvePolicy<T>,
+    ) -> Result<Value<T>> {
+        let name = name.into();
+        let address = Some(resolve(source, base, &pointer).ok_or_else(|| {
+            Error::Memory(format!("Could not resolve {name}, check your Rekordbox version!"))
+        })?);
+
+        Ok(Value::<T> {
+            name,
+            base,
+            pointer,
+            policy,
             address,
-            handle: h,
-            _marker: PhantomData::<T>,
+            polls: 0,
+            last: T::default(),
+            stale: false,
+        })
+    }
+
+    /// Read the value, re-resolving the pointer chain according to the policy.
+    /// Returns the last good value if that fails too.
+    fn read(&mut self, source: &dyn MemorySource) -> T {
+        self.polls += 1;
+        if self.policy.every_polls > 0 && self.polls >= self.policy.every_polls {
+            self.polls = 0;
+            self.address = resolve(source, self.base, &self.pointer);
         }
+
+        let mut value = self.try_read(source);
+        let insane = value.is_some_and(|x| !self.is_sane(&x));
+        if (value.is_none() && self.policy.on_failure) || insane {
+            self.address = resolve(source, self.base, &self.pointer);
+            value = self.try_read(source);
+        }
+
+        match value.filter(|x| self.is_sane(x)) {
+            Some(value) => {
+                self.stale = false;
+                self.last = value;
+            }
+            None => self.stale = true,
+        }
+        self.last
+    }
+
+    fn try_read(&self, source: &dyn MemorySource) -> Option<T> {
+        read::<T>(source, self.address?)
+    }
+
+    fn is_sane(&self, value: &T) -> bool {
+        self.policy.sanity.is_none_or(|sanity| sanity(value))
+    }
+}
+
+/// Walk a pointer chain from the module base to the final address.
+fn resolve(source: &dyn MemorySource, base: usize, pointer: &Pointer) -> Option<usize> {
+    let mut address = base;
+    for offset in &pointer.offsets {
+        address = read::<usize>(source, address + offset)?;
+    }
+    Some(address + pointer.final_offset)
+}
+
+/// Replace the signatures in the offsets by the static addresses they point to
+fn find_signatures(source: &dyn MemorySource, offsets: &mut RekordboxOffsets) -> Result<()> {
+    for (name, result) in resolve_signatures(offsets, source) {
+        result.map_err(|e| {
+            Error::Memory(format!("The signature for {name} was {e}, check your Rekordbox version!"))
+        })?;
     }
+    Ok(())
+}
+
+/// Pointers read for one deck
+struct DeckValues {
+    bar: Value<i32>,
+    beat: Value<i32>,
+    track_id: Option<Value<i32>>,
+    time: Option<Value<i32>>,
+}
 
-    fn read(&self) -> T {
-        read::<T>(self.handle, self.address).unwrap()
+impl DeckValues {
+    fn new(n: usize, source: &dyn MemorySource, base: usize, offsets: DeckOffsets) -> Result<Self> {
+        let periodic = || ResolvePolicy::new(RESOLVE_EVERY_POLLS, None);
+        let optional = |name: String, pointer: Option<Pointer>| {
+            pointer
+                .map(|pointer| Value::new(name, source, base, pointer, periodic()))
+                .transpose()
+        };
+        Ok(Self {
+            bar: Value::new(format!("deck{n}_bar"), source, base, offsets.bar, periodic())?,
+            beat: Value::new(format!("deck{n}_beat"), source, base, offsets.beat, periodic())?,
+            track_id: optional(format!("deck{n}_track_id"), offsets.track_id)?,
+            time: optional(format!("deck{n}_time"), offsets.time)?,
+        })
     }
 
-    fn read_bytes(&self, times: usize) -> Vec<u8> {
-        let mut byte_vec = Vec::new();
-        for _t in 0..times {
-            let read_mem_bytes = read::<u8>(self.handle, self.address + (_t)).unwrap();
-            byte_vec.push(read_mem_bytes);
+    /// Values without a pointer in the offsets read as 0
+    fn read(&mut self, source: &dyn MemorySource) -> Deck {
+        Deck {
+            beats: self.bar.read(source) * 4 + self.beat.read(source),
+            track_id: self.track_id.as_mut().map_or(0, |x| x.read(source)),
+            time: self.time.as_mut().map_or(0, |x| x.read(source)),
         }
-        return byte_vec;
     }
+
+    /// Name and staleness of every value with a pointer
+    fn stale(&self) -> Vec<(&str, bool)> {
+        [Some(&self.bar), Some(&self.beat), self.track_id.as_ref(), self.time.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|x| (x.name.as_str(), x.stale))
+            .collect()
+    }
+}
+
+/// What was last read for one deck
+#[derive(Clone, Copy, Default)]
+pub struct Deck {
+    pub beats: i32,
+    pub track_id: i32,
+    /// Elapsed time in milliseconds
+    pub time: i32,
 }
 
 pub struct Rekordbox {
+    source: Box<dyn MemorySource>,
+
     master_bpm_val: Value<f32>,
-    bar1_val: Value<i32>,
-    beat1_val: Value<i32>,
-    bar2_val: Value<i32>,
-    beat2_val: Value<i32>,
     masterdeck_index_val: Value<u8>,
+    api_bearer_val: Option<Value<[u8; 32]>>,
+    deck_vals: Vec<DeckValues>,
 
-    deck1_time_val: Value<i32>,
-    deck2_time_val: Value<i32>,
-    deck1_track_id_val: Value<i32>,
-    deck2_track_id_val: Value<i32>,
-    api_bearer_val: Value<Vec<u8>>,
-
-    pub beats1: i32,
-    pub beats2: i32,
+    /// Deck 1 first, as many as the offsets have
+    pub decks: Vec<Deck>,
     pub master_beats: i32,
     pub master_bpm: f32,
     pub masterdeck_index: u8,
-    pub deck1_time: i32,
-    pub deck2_time: i32,
-    pub deck1_track_id: i32,
-    pub deck2_track_id: i32,
     pub master_time: i32,
     pub api_bearer: String,
 }
 
-impl Rekordbox {
-    fn new(offsets: RekordboxOffsets) -> Self {
-        let rb = Process::from_process_name("rekordbox.exe")
-            .expect("Could not find Rekordbox process! ");
-        let h = rb.process_handle;
-
-        let base = rb.get_module_base("rekordbox.exe").unwrap();
-
-        let master_bpm_val: Value<f32> = Value::new(h, base, offsets.master_bpm);
-
-        let api_bearer_val: Value<Vec<u8>> = Value::new(h, base, offsets.api_bearer);
-
-        let bar1_val: Value<i32> = Value::new(h, base, offsets.deck1bar);
-        let beat1_val: Value<i32> = Value::new(h, base, offsets.deck1beat);
-        let bar2_val: Value<i32> = Value::new(h, base, offsets.deck2bar);
-        let beat2_val: Value<i32> = Value::new(h, base, offsets.deck2beat);
+/// Re-walk periodic pointer chains about once a second at the default poll rate
+const RESOLVE_EVERY_POLLS: u32 = 60;
 
+fn sane_bpm(bpm: &f32) -> bool {
+    (20.0..=999.0).contains(bpm)
+}
 
-        let deck1_track_id_val: Value<i32> = Value::new(h, base, offsets.deck1_track_id);
-        let deck1_time_val: Value<i32> = Value::new(h, base, offsets.deck1_time);
-        
-        let deck2_track_id_val: Value<i32> = Value::new(h, base, offsets.deck2_track_id);
-        let deck2_time_val: Value<i32> = Value::new(h, base, offsets.deck2_time);
+fn sane_masterdeck_index(index: &u8) -> bool {
+    (*index as usize) < MAX_DECKS
+}
 
-        let masterdeck_index_val: Value<u8> = Value::new(h, base, offsets.masterdeck_index);
+impl Rekordbox {
+    fn new(source: Box<dyn MemorySource>, mut offsets: RekordboxOffsets) -> Result<Self> {
+        let base = source
+            .module_base("rekordbox.exe")
+            .ok_or_else(|| Error::Memory("Could not find rekordbox.exe module!".to_string()))?;
+        let mem = source.as_ref();
+        find_signatures(mem, &mut offsets)?;
+
+        let master_bpm_val: Value<f32> = Value::new(
+            "master_bpm",
+            mem,
+            base,
+            offsets.master_bpm,
+            ResolvePolicy::new(RESOLVE_EVERY_POLLS, Some(sane_bpm)),
+        )?;
+
+        let api_bearer_val: Option<Value<[u8; 32]>> = offsets
+            .api_bearer
+            .map(|pointer| Value::new("api_bearer", mem, base, pointer, ResolvePolicy::new(0, None)))
+            .transpose()?;
+
+        let masterdeck_index_val: Value<u8> = Value::new(
+            "masterdeck_index",
+            mem,
+            base,
+            offsets.masterdeck_index,
+            ResolvePolicy::new(RESOLVE_EVERY_POLLS, Some(sane_masterdeck_index)),
+        )?;
+
+        let deck_vals = offsets
+            .decks
+            .into_iter()
+            .take(MAX_DECKS)
+            .enumerate()
+            .map(|(i, deck)| DeckValues::new(i + 1, mem, base, deck))
+            .collect::<Result<Vec<DeckValues>>>()?;
+
+        Ok(Self {
+            source,
 
-        Self {
             master_bpm_val,
-            bar1_val,
-            beat1_val,
-            bar2_val,
-            beat2_val,
-
-            deck1_time_val,
-            deck2_time_val,
-
-            deck1_track_id_val,
-            deck2_track_id_val,
-            api_bearer_val,
-
             masterdeck_index_val,
+            api_bearer_val,
+            decks: vec![Deck::default(); deck_vals.len()],
+            deck_vals,
 
-            beats1: -1,
-            beats2: -1,
             master_bpm: 120.0,
             masterdeck_index: 0,
             master_beats: 0,
             master_time: 0,
-            deck1_track_id: 0,
-            deck2_track_id: 0,
-            deck1_time: 0,
-            deck2_time: 0,
             api_bearer: "".to_string(),
-        }
+        })
     }
 
-    fn update(&mut self) {
-        self.master_bpm = self.master_bpm_val.read();
-        self.beats1 = self.bar1_val.read() * 4 + self.beat1_val.read();
-        self.beats2 = self.bar2_val.read() * 4 + self.beat2_val.read();
-        self.masterdeck_index = self.masterdeck_index_val.read();
+    fn update(&mut self) -> Result<()> {
+        let source = self.source.as_ref();
+        self.master_bpm = self.master_bpm_val.read(source);
+        self.masterdeck_index = self.masterdeck_index_val.read(source);
 
-        self.deck1_track_id = self.deck1_track_id_val.read();
-        self.deck2_track_id = self.deck2_track_id_val.read();
+        for (deck, values) in self.decks.iter_mut().zip(&mut self.deck_vals) {
+            *deck = values.read(source);
+        }
 
-        self.deck1_time = self.deck1_time_val.read();
-        self.deck2_time = self.deck2_time_val.read();
+        // A master deck the offsets have no pointers for keeps the last master values
+        if let Some(master) = self.decks.get(self.masterdeck_index as usize) {
+            self.master_beats = master.beats;
+            self.master_time = master.time;
+        }
 
-        if self.masterdeck_index == 0 {
-            self.master_beats = self.beats1;
-            self.master_time = self.deck1_time;
-        } else {
-            self.master_beats = self.beats2;
-            self.master_time = self.deck2_time;
-        };
+        // The bearer is only read when asked for, so it says nothing about the process
+        if self.polled_values().iter().all(|(_, stale)| *stale) {
+            return Err(Error::Memory("No values could be read, is Rekordbox still running?".to_string()));
+        }
+        Ok(())
     }
 
-    pub fn update_api_bearer(&mut self) {
-        let api_bearer_vec = self.api_bearer_val.read_bytes(32);
+    /// Name and staleness of every value read on each update
+    fn polled_values(&self) -> Vec<(&str, bool)> {
+        let mut values = vec![
+            (self.master_bpm_val.name.as_str(), self.master_bpm_val.stale),
+            (&self.masterdeck_index_val.name, self.masterdeck_index_val.stale),
+        ];
+        values.extend(self.deck_vals.iter().flat_map(|x| x.stale()));
+        values
+    }
+
+    /// Name and staleness of every value with a pointer
+    fn values(&self) -> Vec<(&str, bool)> {
+        let mut values = self.polled_values();
+        if let Some(api_bearer_val) = &self.api_bearer_val {
+            values.push((&api_bearer_val.name, api_bearer_val.stale));
+        }
+        values
+    }
+
+    /// Names of the values that could not be read, even after re-resolving their pointers
+    pub fn stale_values(&self) -> Vec<String> {
+        self.values()
+            .into_iter()
+            .filter(|(_, stale)| *stale)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    pub fn update_api_bearer(&mut self) -> Result<()> {
+        let Some(api_bearer_val) = &mut self.api_bearer_val else {
+            return Err(Error::Offsets {
+                line: None,
+                message: "No api_bearer for this version".to_string(),
+            });
+        };
+        let api_bearer_vec = api_bearer_val.read(self.source.as_ref());
         self.api_bearer = match std::str::from_utf8(&api_bearer_vec) {
             Ok(v) => v.to_string(),
-            Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+            Err(e) => return Err(Error::Memory(format!("API bearer is not valid UTF-8: {e}"))),
         };
+        Ok(())
+    }
+}
+
+/// Opens a fresh memory source for Rekordbox together with the offsets for its version, or says
+/// why it can not, eg. because Rekordbox is not running.
+pub type Attach = Box<dyn FnMut() -> Result<(Box<dyn MemorySource>, RekordboxOffsets)>>;
+
+/// The master deck counts as stopped once its time has not moved for this long. Rekordbox does
+/// not update the time on every poll.
+const PLAYING_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Try to attach to Rekordbox this often while waiting for it
+const ATTACH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A failed track lookup is tried again after this, doubling with every failure up to
+/// `MAX_LOOKUP_RETRY`
+const LOOKUP_RETRY: Duration = Duration::from_secs(1);
+const MAX_LOOKUP_RETRY: Duration = Duration::from_secs(30);
+
+/// How late a sleep can end, with the timer at 1ms on Windows
+const WAKE_MARGIN: Duration = Duration::from_millis(2);
+
+enum Connection {
+    /// Waiting for the Rekordbox process to show up
+    Waiting,
+    Attached(Box<Rekordbox>),
+    /// The process went away, goes back to waiting on the next update
+    Lost,
+}
+
+/// What the keeper knows about one deck
+#[derive(Clone)]
+pub struct TrackedDeck {
+    pub track_id: i32,
+    pub path: String,
+    pub title: String,
+    pub beats: i32,
+    pub time: i32,
+    /// Master tempo while this deck was last master with the track it has now, Rekordbox only
+    /// has the master's tempo for us
+    pub bpm: Option<f32>,
+    since_time_change: Duration,
+    /// Beatgrid and cues of the track, when its analysis could be read
+    pub analysis: Option<Analysis>,
+    /// When the track is to be looked up, in the keeper's time
+    lookup_due: Option<Duration>,
+    /// Lookups of the track that failed in a row
+    lookup_failures: u32,
+}
+
+impl Default for TrackedDeck {
+    fn default() -> Self {
+        Self {
+            track_id: 0,
+            path: "".to_string(),
+            title: "".to_string(),
+            beats: 0,
+            time: 0,
+            bpm: None,
+            since_time_change: PLAYING_TIMEOUT,
+            analysis: None,
+            lookup_due: None,
+            lookup_failures: 0,
+        }
+    }
+}
+
+impl TrackedDeck {
+    /// Take a looked up track, with the path as SoundSwitch knows it. Gives why its analysis
+    /// could not be read, beats then come from Rekordbox's counter.
+    fn set_track(&mut self, track: &Track, rewrite: &PathRewrite) -> Option<String> {
+        self.title = track.content.display_title().to_string();
+        self.path = rewrite.apply(&track.content.folder_path);
+        self.lookup_due = None;
+        self.lookup_failures = 0;
+        match &track.analysis {
+            Some(Ok(analysis)) => {
+                self.analysis = Some(analysis.clone());
+                None
+            }
+            Some(Err(e)) => {
+                self.analysis = None;
+                Some(e.clone())
+            }
+            None => {
+                self.analysis = None;
+                None
+            }
+        }
+    }
+
+    /// Where the deck is in the beatgrid, with the time carried on between Rekordbox's updates
+    fn grid_position(&self) -> Option<GridPosition> {
+        let mut time_ms = self.time as f64;
+        if self.playing() {
+            time_ms += self.since_time_change.as_secs_f64() * 1000.;
+        }
+        self.analysis.as_ref()?.position(time_ms)
+    }
+
+    /// The tempo it had as master, or the beatgrid's for a track that was not master yet. That
+    /// one is the track's own, without the pitch.
+    fn bpm(&self) -> Option<f32> {
+        if self.bpm.is_some() {
+            return self.bpm;
+        }
+        let grid = self.grid_position().map(|x| x.bpm);
+        grid.or_else(|| Some(self.analysis.as_ref()?.beats.first()?.bpm))
+    }
+
+    /// Look the track up again later, a little later with every failure
+    fn lookup_failed(&mut self, now: Duration) {
+        let backoff = LOOKUP_RETRY.saturating_mul(1 << self.lookup_failures.min(5));
+        self.lookup_failures += 1;
+        self.lookup_due = Some(now + backoff.min(MAX_LOOKUP_RETRY));
+    }
+
+    /// Rekordbox has no play flag we know of, but the time only moves while playing
+    pub fn playing(&self) -> bool {
+        self.since_time_change < PLAYING_TIMEOUT
     }
 }
 
 pub struct BeatKeeper {
-    rb: Option<Rekordbox>,
+    connection: Connection,
+    attach: Option<Attach>,
+    last_attach: Option<Instant>,
+    last_attach_error: String,
+    metadata: MetadataFetcher,
+    rewrite: PathRewrite,
+
     last_beat: i32,
     last_time: i32,
+    /// Rekordbox's beat counter on the last update, its changes are what the clock gets without
+    /// a beatgrid
+    last_counter: i32,
+    clock: BeatClock,
+    /// Time passed in updates, what the clock counts in
+    clock_time: Duration,
 
     pub api_bearer: String,
     
-    pub last_d1track: i32,
-    pub last_d2track: i32,
+    /// Deck 1 first, as many as the offsets have
+    pub decks: Vec<TrackedDeck>,
+    /// Decks whose track path changed, see `get_new_deck_tracks`
+    new_deck_tracks: Vec<usize>,
     pub last_master_track: i32,
     pub last_master_path: String,
     pub last_master_title: String,
@@ -193,20 +551,30 @@ pub struct BeatKeeper {
     pub beat_fraction: f32,
     pub last_masterdeck_index: u8,
     pub offset_micros: f32,
+    /// Tempo the clock ran at on the last beat
     pub last_bpm: f32,
     pub new_beat: bool,
     pub new_track: bool,
     pub new_time: bool,
+    pub stale_values: Vec<String>,
 }
 
 impl BeatKeeper {
-    pub fn new(offsets: RekordboxOffsets) -> Self {
+    pub fn new(attach: Attach, metadata: MetadataFetcher, rewrite: PathRewrite) -> Self {
         BeatKeeper {
-            rb: Some(Rekordbox::new(offsets)),
+            connection: Connection::Waiting,
+            attach: Some(attach),
+            last_attach: None,
+            last_attach_error: "".to_string(),
+            metadata,
+            rewrite,
             last_beat: 0,
             last_time: 0,
-            last_d1track: 0,
-            last_d2track: 0,
+            last_counter: 0,
+            clock: BeatClock::default(),
+            clock_time: Duration::ZERO,
+            decks: vec![],
+            new_deck_tracks: vec![],
             last_master_track: 0,
             last_master_path: "".to_string(),
             last_master_title: "".to_string(),
@@ -218,16 +586,25 @@ impl BeatKeeper {
             new_beat: false,
             new_track: false,
             new_time: false,
+            stale_values: vec![],
         }
     }
 
-    pub fn dummy() -> Self {
+    pub fn dummy(metadata: MetadataFetcher, rewrite: PathRewrite) -> Self {
         BeatKeeper {
-            rb: None,
+            connection: Connection::Waiting,
+            attach: None,
+            last_attach: None,
+            last_attach_error: "".to_string(),
+            metadata,
+            rewrite,
             last_beat: 0,
             last_time: 0,
-            last_d1track: 0,
-            last_d2track: 0,
+            last_counter: 0,
+            clock: BeatClock::default(),
+            clock_time: Duration::ZERO,
+            decks: vec![],
+            new_deck_tracks: vec![],
             last_master_track: 0,
             last_master_path: "".to_string(),
             last_master_title: "".to_string(),
@@ -239,104 +616,324 @@ impl BeatKeeper {
             new_beat: false,
             new_track: false,
             new_time: false,
+            stale_values: vec![],
         }
     }
 
-    pub fn update(&mut self, delta: Duration) {
-        if let Some(rb) = &mut self.rb {
-            let beats_per_micro = rb.master_bpm / 60. / 1000000.;
+    /// Memory errors are returned after advancing time, API errors after all values are updated,
+    /// so the caller can decide to carry on either way.
+    pub fn update(&mut self, delta: Duration) -> Result<()> {
+        self.update_connection();
+        self.clock_time += delta;
+        let now = self.clock_time;
+        let ahead = self.ahead(now);
+
+        if let Connection::Attached(rb) = &mut self.connection {
             let mut master_track_changed = false;
 
-            rb.update(); // Fetch values from rkbx memory
+            // Fetch values from rkbx memory
+            if let Err(e) = rb.update() {
+                // Keep the beat running on the last known tempo
+                self.beat_fraction = self.clock.phase(ahead).unwrap_or(self.beat_fraction);
+                self.connection = Connection::Lost;
+                return Err(e);
+            }
 
-            if rb.masterdeck_index != self.last_masterdeck_index {
-                self.last_masterdeck_index = rb.masterdeck_index;
-                self.last_beat = rb.master_beats;
-                if rb.masterdeck_index == 0 {
-                    self.last_master_track = rb.deck1_track_id;
+            let stale_values = rb.stale_values();
+            if stale_values != self.stale_values {
+                if stale_values.is_empty() {
+                    println!("\nAll values readable again");
                 } else {
-                    self.last_master_track = rb.deck2_track_id;
+                    println!("\nStale values, check your Rekordbox version: {}", stale_values.join(", "));
                 }
-                master_track_changed = true;
+                self.stale_values = stale_values;
             }
 
-            if rb.deck1_track_id != self.last_d1track  {
-                println!("Deck 1 track change: {}", rb.deck1_track_id);
-                self.last_d1track = rb.deck1_track_id;
-                if rb.masterdeck_index == 0 {
-                    self.last_master_track = rb.deck1_track_id;
-                    master_track_changed = true;
+            if self.decks.len() != rb.decks.len() {
+                self.decks = vec![TrackedDeck::default(); rb.decks.len()];
+            }
+            let master_index = rb.masterdeck_index as usize;
 
+            for (i, (tracked, deck)) in self.decks.iter_mut().zip(&rb.decks).enumerate() {
+                tracked.since_time_change = if deck.time != tracked.time {
+                    Duration::ZERO
+                } else {
+                    tracked.since_time_change + delta
+                };
+                tracked.time = deck.time;
+                tracked.beats = deck.beats;
+
+                if deck.track_id != tracked.track_id {
+                    println!("Deck {} track change: {}", i + 1, deck.track_id);
+                    tracked.track_id = deck.track_id;
+                    tracked.path.clear();
+                    tracked.title.clear();
+                    tracked.analysis = None;
+                    tracked.bpm = None;
+                    tracked.lookup_failures = 0;
+                    // Every new track is looked up, so SoundSwitch knows what is loaded on each
+                    // deck and the path is there by the time a deck becomes master
+                    tracked.lookup_due = (deck.track_id > 0).then_some(now);
+                    if i == master_index {
+                        master_track_changed = true;
+                    }
+                }
+                if i == master_index {
+                    tracked.bpm = Some(rb.master_bpm);
                 }
             }
 
-            if rb.deck2_track_id != self.last_d2track  {
-                println!("Deck 2 track change: {}", rb.deck2_track_id);
-                self.last_d2track = rb.deck2_track_id;
-                if rb.masterdeck_index == 1 {
-                    self.last_master_track = rb.deck2_track_id;
-                    master_track_changed = true;
+            // A new master is sent once its track is known, do not wait out a failed lookup
+            let masterdeck_changed = rb.masterdeck_index != self.last_masterdeck_index;
+            if let Some(master) = self.decks.get_mut(master_index) {
+                if masterdeck_changed && master.lookup_due.is_some() {
+                    master.lookup_due = Some(now);
+                }
+            }
+            for (i, tracked) in self.decks.iter_mut().enumerate() {
+                if tracked.lookup_due.is_none_or(|x| x > now) {
+                    continue;
+                }
+                tracked.lookup_due = None;
+                match self.metadata.get(tracked.track_id) {
+                    Some(track) => {
+                        if let Some(e) = tracked.set_track(&track, &self.rewrite) {
+                            println!("\nDeck {}: {e}, using Rekordbox's beat counter", i + 1);
+                        }
+                        self.new_deck_tracks.push(i);
+                    }
+                    None => self.metadata.request(tracked.track_id, &self.api_bearer),
+                }
+            }
+
+            let mut api_result = Ok(());
+            for lookup in self.metadata.lookups() {
+                let error = match lookup.result {
+                    Ok(Some(track)) => {
+                        // The deck may have moved on while the request was out
+                        for (i, deck) in self.decks.iter_mut().enumerate() {
+                            if deck.track_id == lookup.track_id && deck.path.is_empty() {
+                                if let Some(e) = deck.set_track(&track, &self.rewrite) {
+                                    println!("\nDeck {}: {e}, using Rekordbox's beat counter", i + 1);
+                                }
+                                self.new_deck_tracks.push(i);
+                                if i == master_index {
+                                    master_track_changed = true;
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    Ok(None) => continue,
+                    // Rekordbox makes a new bearer when it restarts its API, read it again once
+                    Err(Error::ApiUnauthorized) => {
+                        let refreshed = rb.update_api_bearer().map(|()| rb.api_bearer.clone());
+                        match refreshed {
+                            Ok(bearer) if bearer != lookup.bearer => {
+                                println!("\nAPI key refreshed: {bearer}");
+                                self.api_bearer = bearer;
+                                self.metadata.request(lookup.track_id, &self.api_bearer);
+                                continue;
+                            }
+                            Ok(_) => Error::ApiUnauthorized,
+                            Err(e) => e,
+                        }
+                    }
+                    Err(e) => e,
+                };
+                for deck in &mut self.decks {
+                    if deck.track_id == lookup.track_id && deck.path.is_empty() {
+                        deck.lookup_failed(now);
+                    }
                 }
+                api_result = Err(error);
             }
 
-            if (rb.master_beats - self.last_beat).abs() > 0 {
-                self.last_beat = rb.master_beats;
-                self.beat_fraction = 0.;
-                self.new_beat = true;
+            // The beatgrid gives the beat to the millisecond, the counter only changes some time
+            // after the beat
+            let mut master_position = None;
+            for (i, tracked) in self.decks.iter_mut().enumerate() {
+                if let Some(position) = tracked.grid_position() {
+                    tracked.beats = position.beats;
+                    if i == master_index {
+                        master_position = Some(position);
+                    }
+                }
+            }
+
+            if masterdeck_changed {
+                self.last_masterdeck_index = rb.masterdeck_index;
+                master_track_changed = true;
+                self.clock.reset();
+            }
+            self.clock.set_bpm(now, rb.master_bpm);
+            if self.decks.get(master_index).is_some_and(|x| x.playing()) {
+                self.clock.resume(now);
+            } else {
+                self.clock.hold(now);
+            }
+            match master_position {
+                Some(position) => {
+                    self.clock.observe(now, position.beats as f64 + position.phase as f64)
+                }
+                // The counter changed somewhere between the last poll and this one
+                None if rb.master_beats != self.last_counter && !masterdeck_changed => {
+                    self.clock.observe(now.saturating_sub(delta / 2), rb.master_beats as f64)
+                }
+                None if self.clock.position(now).is_none() || masterdeck_changed => {
+                    self.clock.observe(now, rb.master_beats as f64)
+                }
+                None => (),
+            }
+            self.last_counter = rb.master_beats;
+
+            // Beats go out early by the offset, so lights that react late are on the beat
+            let position = self.clock.position(ahead).unwrap_or(rb.master_beats as f64);
+            self.beat_fraction = position.rem_euclid(1.) as f32;
+            if let Some(beat) = self.clock.beat(ahead) {
+                self.last_beat = beat;
+                self.last_bpm = self.clock.bpm().unwrap_or(rb.master_bpm);
+                // A new master deck starts out where it is, that is not a beat
+                if !masterdeck_changed {
+                    self.new_beat = true;
+                }
             }
 
             if rb.master_time != self.last_time {
                 self.last_time = rb.master_time;
                 self.new_time = true;
             }
-            
+
             if master_track_changed {
-                if self.last_master_track > 0 {
-                    let res = new_master_track(self.last_master_track, &self.api_bearer);
-                    if res["code"] != 404 {
-                        self.last_master_path = res["item"]["FolderPath"].as_str().unwrap().to_string();
-                        self.last_master_title = res["item"]["FileNameL"].as_str().unwrap().to_string();
+                if let Some(master) = self.decks.get(master_index) {
+                    self.last_master_track = master.track_id;
+                    if master.track_id > 0 && !master.path.is_empty() {
+                        self.last_master_path = master.path.clone();
+                        self.last_master_title = master.title.clone();
                         self.new_track = true;
                     }
                 }
             }
-
-            self.beat_fraction =
-                (self.beat_fraction + delta.as_micros() as f32 * beats_per_micro) % 1.;
+            api_result?;
         } else {
             self.beat_fraction = (self.beat_fraction + delta.as_secs_f32() * 130. / 60.) % 1.;
         }
+        Ok(())
+    }
+
+    /// State to answer SoundSwitch's queries with, decks numbered as in Rekordbox
+    pub fn os2l_state(&self) -> Os2lState {
+        let mut state = Os2lState {
+            master_deck: self.master_deck(),
+            ..Default::default()
+        };
+        let attached = matches!(self.connection, Connection::Attached(_));
+        for (deck, tracked) in state.decks.iter_mut().zip(&self.decks) {
+            deck.bpm = tracked.bpm();
+            deck.playing = attached && tracked.playing();
+            deck.filepath = tracked.path.clone();
+            deck.time = tracked.time;
+            deck.beatpos = tracked.beats;
+        }
+        // The master's beat is the beat clock's, as sent on every beat
+        if let Some(master) = state.decks.get_mut(self.master_deck() - 1) {
+            master.beatpos = self.last_beat;
+        }
+        state
+    }
+
+    /// OS2L number of the master deck, starting at 1
+    pub fn master_deck(&self) -> usize {
+        match self.last_masterdeck_index {
+            u8::MAX => 1,
+            index => index as usize + 1,
+        }
+    }
+
+    /// Step the connection state machine, attaching to Rekordbox when it shows up.
+    fn update_connection(&mut self) {
+        match self.connection {
+            Connection::Attached(_) => (),
+            Connection::Lost => {
+                println!("\nLost connection to Rekordbox, waiting for it to start again");
+                self.connection = Connection::Waiting;
+                self.last_attach = None;
+            }
+            Connection::Waiting => {
+                let Some(attach) = &mut self.attach else {
+                    return;
+                };
+                if self.last_attach.is_some_and(|x| x.elapsed() < ATTACH_INTERVAL) {
+                    return;
+                }
+                self.last_attach = Some(Instant::now());
+
+                let rb = attach().and_then(|(source, offsets)| Rekordbox::new(source, offsets));
+                match rb {
+                    Ok(rb) => {
+                        println!("\nAttached to Rekordbox");
+                        self.last_attach_error.clear();
+                        self.connection = Connection::Attached(Box::new(rb));
+                        self.reset_tracking();
+                        if self.metadata.uses_bearer() {
+                            match self.update_api_bearer() {
+                                Ok(()) => println!("API key: {}", self.api_bearer),
+                                Err(e) => println!("{e}, track paths will not be sent"),
+                            }
+                        }
+                    }
+                    // Rekordbox fills in its pointers while starting up, so keep trying
+                    Err(e) => {
+                        let e = e.to_string();
+                        if e != self.last_attach_error {
+                            println!("\n{e}");
+                            self.last_attach_error = e;
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    pub fn update_api_bearer(&mut self) {
-        if let Some(rb) = &mut self.rb {
+    /// Forget what was last seen, so the master track and position are sent again after attaching
+    fn reset_tracking(&mut self) {
+        self.decks.clear();
+        self.new_deck_tracks.clear();
+        self.last_master_track = 0;
+        self.last_masterdeck_index = u8::MAX;
+        self.stale_values.clear();
+    }
+
+    pub fn update_api_bearer(&mut self) -> Result<()> {
+        if let Connection::Attached(rb) = &mut self.connection {
 
-            rb.update_api_bearer(); // Fetch values from rkbx memory
+            rb.update_api_bearer()?; // Fetch values from rkbx memory
             self.api_bearer = rb.api_bearer.clone();
 
         }
+        Ok(())
     }
 
+    /// How far into the beat the master is, with the offset already in
     pub fn get_beat_faction(&mut self) -> f32 {
-        (self.beat_fraction
-            + if let Some(rb) = &self.rb {
-                let beats_per_micro = rb.master_bpm / 60. / 1000000.;
-                self.offset_micros * beats_per_micro
-            } else {
-                0.
-            }
-            + 1.)
-            % 1.
+        self.beat_fraction
     }
 
-    pub fn get_bpm_changed(&mut self) -> Option<f32> {
-        if let Some(rb) = &self.rb {
-            if rb.master_bpm != self.last_bpm {
-                self.last_bpm = rb.master_bpm;
-                return Some(rb.master_bpm);
-            }
+    /// The clock time `offset_micros` after `time`, where the beats sent are
+    fn ahead(&self, time: Duration) -> Duration {
+        let offset = Duration::from_secs_f32(self.offset_micros.abs() / 1000000.);
+        if self.offset_micros >= 0. {
+            time + offset
+        } else {
+            time.saturating_sub(offset)
         }
-        None
+    }
+
+    /// How long until the next beat is to be sent, in the time `update` is given
+    pub fn next_beat_in(&self) -> Option<Duration> {
+        let ahead = self.ahead(self.clock_time);
+        let next = self.clock.next_beat(ahead)?;
+        Some(next - ahead)
     }
 
     pub fn get_new_beat(&mut self) -> bool {
@@ -355,6 +952,11 @@ impl BeatKeeper {
         false
     }
 
+    /// Decks that got a new track path since the last call
+    pub fn get_new_deck_tracks(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.new_deck_tracks)
+    }
+
     pub fn get_new_master_track(&mut self) -> bool {
         if self.new_track {
             self.new_track = false;
@@ -370,26 +972,451 @@ impl BeatKeeper {
 
 const CHARS: [&str; 4] = ["|", "/", "-", "\\"];
 
-pub fn new_master_track(track_id: i32, api_key: &String) -> serde_json::Value {
-    let client = reqwest::blocking::Client::new();
+/// Where to look tracks up, as the config says
+fn open_content_source(config: &Config) -> Result<Box<dyn ContentSource>> {
+    match config.metadata {
+        MetadataSource::Api => Ok(Box::new(RekordboxApi::new(&config.api_url)?)),
+        MetadataSource::Database => open_database(config),
+    }
+}
+
+#[cfg(feature = "database")]
+fn open_database(config: &Config) -> Result<Box<dyn ContentSource>> {
+    let error = |message: &str| Error::Config {
+        line: None,
+        message: message.to_string(),
+    };
+    let path = config
+        .database
+        .clone()
+        .or_else(database::default_path)
+        .ok_or_else(|| error("Set database to the path of master.db"))?;
+    let key = config
+        .database_key
+        .as_deref()
+        .ok_or_else(|| error("Reading the database needs its key in database_key"))?;
+    println!("Reading tracks from {}", path.display());
+    Ok(Box::new(database::MasterDb::open(&path, key)?))
+}
+
+#[cfg(not(feature = "database"))]
+fn open_database(_config: &Config) -> Result<Box<dyn ContentSource>> {
+    Err(Error::Config {
+        line: None,
+        message: "This build can not read the database, build it with --features database"
+            .to_string(),
+    })
+}
+
+/// Version of the running rekordbox.exe, from its mapped image or else from the file on disk
+fn detect_version(source: &dyn MemorySource) -> Option<FileVersion> {
+    let base = source.module_base("rekordbox.exe")?;
+    PeImage::from_memory(source, base)
+        .and_then(|x| x.file_version())
+        .or_else(|| PeImage::from_file(&source.module_path("rekordbox.exe")?)?.file_version())
+}
+
+/// Pick the offsets for the running Rekordbox, refusing versions the offsets file does not have
+fn detect_offsets(
+    source: &dyn MemorySource,
+    version_offsets: &OffsetsFile,
+) -> Result<(FileVersion, RekordboxOffsets)> {
+    let version = detect_version(source).ok_or_else(|| {
+        Error::Version("Could not read it from rekordbox.exe, pass it with -v".to_string())
+    })?;
+    let offsets = version_offsets
+        .get(&Version(version.0.map(u32::from).to_vec()))
+        .ok_or_else(|| {
+            Error::Version(format!(
+                "{version} is not in the offsets file, update it with -u or force a version with -v"
+            ))
+        })?;
+    Ok((version, offsets.clone()))
+}
+
+/// Where dumped images are loaded, the default base of 64 bit executables
+const DUMP_BASE: usize = 0x140000000;
+
+/// Resolve the signatures of a version against a rekordbox.exe image dumped from memory, and
+/// print its pointers with the static addresses filled in
+fn check_signatures(path: &str, version: Option<&str>, version_offsets: &OffsetsFile) -> Result<()> {
+    let mut image = FakeMemory::new();
+    image
+        .load_module("rekordbox.exe", DUMP_BASE, Path::new(path))
+        .map_err(|e| Error::Memory(format!("Could not load {path}: {e}")))?;
+
+    let mut offsets = match version {
+        Some(version) => Version::parse(version)
+            .and_then(|x| version_offsets.get(&x))
+            .cloned()
+            .ok_or_else(|| Error::Version(format!("{version} is not in the offsets file")))?,
+        None => detect_offsets(&image, version_offsets)?.1,
+    };
+    find_signatures(&image, &mut offsets)?;
+
+    println!("{}", offsets.rbversion);
+    for (name, pointer) in offsets.pointers_mut() {
+        println!("{name} = {pointer}");
+    }
+    Ok(())
+}
+
+/// Pointer scanning takes a while for each target, narrow down to this many first
+const MAX_POINTER_TARGETS: usize = 20;
+
+/// `scan <field or type> [-f snapshot]... [-w prefix] [-l levels] [-o max offset]`, walks the user
+/// through finding a value in Rekordbox memory and prints offsets lines for it
+fn run_scan(args: &[String]) -> Result<()> {
+    let usage = || {
+        Error::Usage(
+            "Usage: scan <field or type> [-f snapshot]... [-w prefix] [-l levels] [-o max offset]"
+                .to_string(),
+        )
+    };
+    let mut args_iter = args.iter();
+    let kind = args_iter.next().ok_or_else(usage)?;
+    let (name, field_type) = match field_type(kind) {
+        Some(field_type) => (kind.as_str(), field_type),
+        None => ("value", FieldType::parse(kind).ok_or_else(usage)?),
+    };
+
+    let mut snapshots: Vec<PathBuf> = vec![];
+    let mut snapshot_prefix: Option<String> = None;
+    let mut pointer_scan = PointerScan::default();
+    while let Some(arg) = args_iter.next() {
+        let value = args_iter.next().ok_or_else(usage)?;
+        match arg.as_str() {
+            "-f" => snapshots.push(PathBuf::from(value)),
+            "-w" => snapshot_prefix = Some(value.clone()),
+            "-l" => pointer_scan.max_depth = value.parse().map_err(|_| usage())?,
+            "-o" => {
+                pointer_scan.max_offset = usize::from_str_radix(value, 16).map_err(|_| usage())?;
+            }
+            _ => return Err(usage()),
+        }
+    }
+
+    // Either the running Rekordbox, or one snapshot for each value entered
+    let process = if snapshots.is_empty() {
+        let process = ProcessMemory::attach("rekordbox.exe")
+            .ok_or_else(|| Error::Usage("Start Rekordbox first".to_string()))?;
+        Some(process)
+    } else {
+        None
+    };
+    let mut snapshot: Option<FakeMemory> = None;
+
+    let mut scan = ValueScan::new(field_type).map_err(Error::Memory)?;
+    println!("Scanning for {name} ({field_type})");
+    println!("Enter the value Rekordbox shows, then change it in Rekordbox and enter the new one.");
+    println!("Instead of a number: a range like 61000..62000, or + increased, - decreased,");
+    println!("= unchanged and ! changed.");
+    println!("Enter nothing to pointer scan what is left.");
+
+    let mut steps = 0;
+    loop {
+        print!("> ");
+        let _ = stdout().flush();
+        let mut line = String::new();
+        if stdin().read_line(&mut line).is_err() || line.trim().is_empty() {
+            match scan.candidates().len() {
+                0 => return Err(Error::Memory("No candidates left".to_string())),
+                n if n > MAX_POINTER_TARGETS => {
+                    println!("{n} candidates left, narrow down to {MAX_POINTER_TARGETS} first");
+                    continue;
+                }
+                _ => break,
+            }
+        }
+        let Some(filter) = Filter::parse(line.trim()) else {
+            println!("Expected a number, a range or one of + - = !");
+            continue;
+        };
+
+        if let Some(path) = snapshots.get(steps) {
+            snapshot = Some(
+                FakeMemory::load_snapshot(path)
+                    .map_err(|e| Error::Memory(format!("Could not load {}: {e}", path.display())))?,
+            );
+        } else if process.is_none() {
+            println!("No snapshots left, enter nothing to pointer scan");
+            continue;
+        }
+        let Some(source) = scan_source(&process, &snapshot) else {
+            continue;
+        };
+
+        if let Err(e) = scan.step(source, &filter) {
+            println!("{e}");
+            continue;
+        }
+        steps += 1;
+        if let Some(prefix) = &snapshot_prefix {
+            let path = PathBuf::from(format!("{prefix}{steps}"));
+            save_snapshot(source, "rekordbox.exe", &path)
+                .map_err(|e| Error::Memory(format!("Could not save {}: {e}", path.display())))?;
+        }
+
+        let candidates = scan.candidates();
+        println!("{} candidates", candidates.len());
+        if candidates.len() <= MAX_POINTER_TARGETS {
+            for (address, value) in candidates {
+                println!("  {address:X} = {value}");
+            }
+        }
+    }
+
+    let source = scan_source(&process, &snapshot)
+        .ok_or_else(|| Error::Memory("Nothing to scan".to_string()))?;
+    println!(
+        "Pointer scanning, up to {} levels and offsets up to {:X}...",
+        pointer_scan.max_depth, pointer_scan.max_offset
+    );
+    let targets: Vec<usize> = scan.candidates().iter().map(|(address, _)| *address).collect();
+    let pointers = pointer_scan.run(source, "rekordbox.exe", &targets).map_err(Error::Memory)?;
+    if pointers.is_empty() {
+        println!("No pointer paths found, try more levels with -l or a larger offset with -o");
+    }
+    for pointer in pointers.iter().take(MAX_POINTER_TARGETS) {
+        println!("{name} = {pointer}");
+    }
+    Ok(())
+}
+
+/// The running Rekordbox, or else the snapshot of the current step
+fn scan_source<'a>(
+    process: &'a Option<ProcessMemory>,
+    snapshot: &'a Option<FakeMemory>,
+) -> Option<&'a dyn MemorySource> {
+    match (process, snapshot) {
+        (Some(process), _) => Some(process),
+        (None, Some(snapshot)) => Some(snapshot),
+        (None, None) => None,
+    }
+}
+
+/// The running Rekordbox or a recorded session, with the offsets for its version
+fn open_rekordbox(
+    mut target_version: Option<String>,
+    replay_path: Option<String>,
+) -> Result<(Box<dyn MemorySource>, RekordboxOffsets)> {
+    let version_offsets = RekordboxOffsets::from_file("offsets")?;
+    let source: Box<dyn MemorySource> = match &replay_path {
+        Some(path) => {
+            let replay = ReplaySource::open(path, 1.).map_err(Error::Memory)?;
+            target_version = target_version.or(replay.rbversion.clone());
+            Box::new(replay)
+        }
+        None => Box::new(
+            ProcessMemory::attach("rekordbox.exe")
+                .ok_or_else(|| Error::Usage("Start Rekordbox first".to_string()))?,
+        ),
+    };
+    let offsets = match &target_version {
+        Some(version) => Version::parse(version)
+            .and_then(|x| version_offsets.get(&x))
+            .cloned()
+            .ok_or_else(|| Error::Version(format!("{version} is not in the offsets file")))?,
+        None => detect_offsets(source.as_ref(), &version_offsets)?.1,
+    };
+    Ok((source, offsets))
+}
+
+/// How long `verify` keeps reading the values
+const VERIFY_DURATION: Duration = Duration::from_secs(3);
+
+/// `verify [-v version] [-l session]`, reads every pointer of the offsets for a few seconds and
+/// prints what looks wrong. Returns whether all of them looked right.
+fn run_verify(args: &[String]) -> Result<bool> {
+    let mut target_version: Option<String> = None;
+    let mut replay_path: Option<String> = None;
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        let value = args_iter.next().cloned();
+        match arg.as_str() {
+            "-v" => target_version = value,
+            "-l" => replay_path = value,
+            _ => return Err(Error::Usage("Usage: verify [-v version] [-l session]".to_string())),
+        }
+    }
+
+    let (source, offsets) = open_rekordbox(target_version, replay_path)?;
+    println!(
+        "Checking the offsets for {} for {} seconds...",
+        offsets.rbversion,
+        VERIFY_DURATION.as_secs()
+    );
+    println!("Load a track on every deck and play the master deck meanwhile.");
+    let reports = verify::verify(source.as_ref(), &offsets, VERIFY_DURATION);
+    for report in &reports {
+        match &report.result {
+            Ok(value) => println!("PASS  {:<18} {value}", report.name),
+            Err(problem) => println!("FAIL  {:<18} {problem}", report.name),
+        }
+    }
+
+    let failed = reports.iter().filter(|x| x.result.is_err()).count();
+    match failed {
+        0 => println!("All {} fields look right", reports.len()),
+        _ => println!("{failed} of {} fields look wrong", reports.len()),
+    }
+    Ok(failed == 0)
+}
+
+/// `path <track id or path> [-v version] [-l session]`, shows what the path of a track becomes
+/// with the rewrite rules of the config, without sending anything
+fn run_path(args: &[String]) -> Result<()> {
+    let usage = || {
+        Error::Usage("Usage: path <track id or path> [-v version] [-l session]".to_string())
+    };
+    let (track, args) = args.split_first().ok_or_else(usage)?;
+    let mut target_version: Option<String> = None;
+    let mut replay_path: Option<String> = None;
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        let value = args_iter.next().cloned();
+        match arg.as_str() {
+            "-v" => target_version = value,
+            "-l" => replay_path = value,
+            _ => return Err(usage()),
+        }
+    }
+
+    let config = Config::from_file("config")?;
+    let path = match track.parse::<i32>() {
+        // Anything that is not a track id is taken as a path, to try rules without Rekordbox
+        Err(_) => track.clone(),
+        Ok(track_id) => {
+            let (content_source, bearer) = open_lookups(&config, target_version, replay_path)?;
+            let content = content_source
+                .content(track_id, &bearer)?
+                .ok_or_else(|| Error::Api(format!("There is no track {track_id}")))?;
+            println!("Title:     {}", content.display_title());
+            content.folder_path
+        }
+    };
 
-    let response = client
-    .get(format!("http://127.0.0.1:30001/api/v1/data/djmdContents/{}/", track_id))
-    .header("User-Agent", "rekordbox/6.8.4.0001 Windows 11(64bit)")
-    .header("Accept", "*/*")
-    .header("Authorization", format!("Bearer {}", api_key))
-    .send().expect("failed to get response").text().expect("failed to get payload");
+    println!("Rekordbox: {path}");
+    let mut rewritten = path;
+    for rule in &config.rewrite.rules {
+        let next = rule.apply(&rewritten);
+        if next != rewritten {
+            println!("{rule}");
+            println!("        -> {next}");
+        }
+        rewritten = next;
+    }
+    let rewritten = config.rewrite.normalize(&rewritten);
+    if config.rewrite.rules.is_empty() {
+        println!("No rewrite rules in the config, the path is sent as it is");
+    }
+    println!("Sent:      {}", windows_path(&rewritten));
+    Ok(())
+}
+
+/// Where the config says to look tracks up, with the bearer read from Rekordbox if it needs one
+fn open_lookups(
+    config: &Config,
+    target_version: Option<String>,
+    replay_path: Option<String>,
+) -> Result<(Box<dyn ContentSource>, String)> {
+    let content_source = open_content_source(config)?;
+    if !content_source.uses_bearer() {
+        return Ok((content_source, String::new()));
+    }
+    let (source, offsets) = open_rekordbox(target_version, replay_path)?;
+    let mut rb = Rekordbox::new(source, offsets)?;
+    rb.update_api_bearer()?;
+    Ok((content_source, rb.api_bearer))
+}
+
+/// `export-vdj <database.xml> [track id]... [-v version] [-l session]`, writes the tracks into a
+/// VirtualDJ database for SoundSwitch to autoscript, every track of the library without ids
+fn run_export_vdj(args: &[String]) -> Result<()> {
+    let usage = || {
+        Error::Usage(
+            "Usage: export-vdj <database.xml> [track id]... [-v version] [-l session]".to_string(),
+        )
+    };
+    let (database, args) = args.split_first().ok_or_else(usage)?;
+    let mut track_ids: Vec<i32> = vec![];
+    let mut target_version: Option<String> = None;
+    let mut replay_path: Option<String> = None;
+    let mut args_iter = args.iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "-v" => target_version = args_iter.next().cloned(),
+            "-l" => replay_path = args_iter.next().cloned(),
+            id => track_ids.push(id.parse().map_err(|_| usage())?),
+        }
+    }
 
-    let res: serde_json::Value = serde_json::from_str(&response).unwrap();
+    let config = Config::from_file("config")?;
+    let analysis_folder = config.analysis_folder();
+    let (content_source, bearer) = open_lookups(&config, target_version, replay_path)?;
+    if track_ids.is_empty() {
+        track_ids = content_source.track_ids()?;
+    }
 
-    return res;
+    let mut songs = vec![];
+    for track_id in track_ids {
+        let Some(content) = content_source.content(track_id, &bearer)? else {
+            println!("Track {track_id} is not in the library");
+            continue;
+        };
+        // Streaming tracks have no file
+        if content.folder_path.is_empty() {
+            continue;
+        }
+        let analysis = match read_analysis(&content, analysis_folder.as_deref()) {
+            Some(Ok(analysis)) => Some(analysis),
+            Some(Err(e)) => {
+                println!("Track {track_id} has no beatgrid, {e}");
+                None
+            }
+            None => None,
+        };
+        songs.push(VdjSong {
+            path: windows_path(&config.rewrite.apply(&content.folder_path)),
+            content,
+            analysis,
+        });
+    }
 
+    let replaced = merge_database(Path::new(database), &songs)?;
+    println!(
+        "Wrote {} tracks to {database}, {replaced} of them were there already",
+        songs.len()
+    );
+    Ok(())
 }
 
 fn main() {
-    if !Path::new("./offsets").exists() {
-        println!("Offsets not found, downloading from repo...");
-        download_offsets();
+    let args: Vec<String> = env::args().collect();
+    // These run by themselves, and read their own input
+    if args.get(1).is_some_and(|x| x == "scan") {
+        exit_on_error(run_scan(&args[2..]));
+        return;
+    }
+    if args.get(1).is_some_and(|x| x == "export-vdj") {
+        exit_on_error(run_export_vdj(&args[2..]));
+        return;
+    }
+    if args.get(1).is_some_and(|x| x == "path") {
+        exit_on_error(run_path(&args[2..]));
+        return;
+    }
+    if args.get(1).is_some_and(|x| x == "verify") {
+        // 0 when all fields look right, 1 when some do not, 2 when they could not be checked
+        let code = match run_verify(&args[2..]) {
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(e) => {
+                println!("{e}");
+                2
+            }
+        };
+        std::process::exit(code);
     }
 
     let (tx, rx) = channel::<i8>();
@@ -397,14 +1424,19 @@ fn main() {
         tx.send(getch()).unwrap();
     });
 
-    let args: Vec<String> = env::args().collect();
-
-    let version_offsets = RekordboxOffsets::from_file("offsets");
-    let mut versions: Vec<String> = version_offsets.keys().map(|x| x.to_string()).collect();
-    versions.sort();
-    versions.reverse();
-    let mut target_version = versions[0].clone();
+    let mut update = false;
+    let mut mirror = DEFAULT_MIRROR.to_string();
+    let mut show_help = false;
+    // Read from rekordbox.exe when attaching unless given
+    let mut target_version: Option<String> = None;
     let mut poll_rate: u64 = 60;
+    let mut record_path: Option<String> = None;
+    let mut replay_path: Option<String> = None;
+    let mut replay_speed: f64 = 1.;
+    let mut soundswitch_addr: Option<SoundSwitchConnectionAddr> = None;
+    let mut image_path: Option<String> = None;
+    let mut api_url: Option<String> = None;
+    let mut lookahead: Option<f32> = None;
 
     let mut args_iter = args.iter();
     args_iter.next();
@@ -415,9 +1447,7 @@ fn main() {
                 if let Some(flag) = chars.next() {
                     match flag.to_string().as_str() {
                         "u" => {
-                            println!("Updating offsets...");
-                            download_offsets();
-                            return;
+                            update = true;
                         }
                         "p" => {
                             if let Some(poll_arg) = args_iter.next() {
@@ -432,36 +1462,63 @@ fn main() {
                             }
                         }
                         "v" => {
-                            target_version = args_iter.next().unwrap().to_string();
+                            if let Some(version) = args_iter.next() {
+                                target_version = Some(version.to_string());
+                            }
+                        }
+                        "d" => {
+                            record_path = args_iter.next().map(|x| x.to_string());
+                        }
+                        "l" => {
+                            replay_path = args_iter.next().map(|x| x.to_string());
+                        }
+                        "s" => {
+                            if let Some(speed_arg) = args_iter.next() {
+                                match speed_arg.parse::<f64>() {
+                                    Ok(value) if value > 0. => {
+                                        replay_speed = value;
+                                    }
+                                    _ => {
+                                        println!("Invalid input for replay speed. Using default value: {}", replay_speed);
+                                    }
+                                }
+                            }
+                        }
+                        "i" => {
+                            image_path = args_iter.next().map(|x| x.to_string());
+                        }
+                        "a" => {
+                            if let Some(addr_arg) = args_iter.next() {
+                                soundswitch_addr = SoundSwitchConnectionAddr::parse(addr_arg);
+                                if soundswitch_addr.is_none() {
+                                    println!("Invalid SoundSwitch address {addr_arg}, discovering it instead");
+                                }
+                            }
                         }
                         "h" => {
-                            println!(
-                                " - Rekordbox OS2L v{} -
-A tool for sending Rekordbox track name, time and bpm to soundswitch (based on virtualdj communication)
-
-Flags:
-
- -h  Print this help
- -u  Fetch latest offset list from GitHub and exit
- -v  Rekordbox version to target, eg. 6.7.3
-
- -p  Change poll value
-
-Use r to resend master path/track to soundswitch.
-Use y to reset and resend master path/track to soundswitch (useful for changing to Autoloop override during a song).
-
-Current default version: {}
-Available versions:",
-                                env!("CARGO_PKG_VERSION"),
-                                versions[0]
-                            );
-                            println!("{}", versions.join(", "));
-
-                            /*for v in  {
-                                print!("{v}, ");
-                            }*/
-                            println!();
-                            return;
+                            show_help = true;
+                        }
+                        "m" => {
+                            if let Some(mirror_arg) = args_iter.next() {
+                                mirror = mirror_arg.to_string();
+                            }
+                        }
+                        "r" => {
+                            if let Some(url_arg) = args_iter.next() {
+                                api_url = Some(url_arg.to_string());
+                            }
+                        }
+                        "o" => {
+                            if let Some(lookahead_arg) = args_iter.next() {
+                                match lookahead_arg.parse::<f32>() {
+                                    Ok(value) => {
+                                        lookahead = Some(value);
+                                    }
+                                    Err(_) => {
+                                        println!("Invalid input for lookahead, sending beats on time");
+                                    }
+                                }
+                            }
                         }
 
                         c => {
@@ -473,13 +1530,75 @@ Available versions:",
         }
     }
 
-    let offsets = if let Some(offsets) = version_offsets.get(target_version.as_str()) {
-        offsets
-    } else {
-        println!("Unsupported version! {target_version}");
+    // Updating does not need the old file, which may be what is broken
+    if update || !Path::new("./offsets").exists() {
+        if update {
+            println!("Updating offsets from {mirror}...");
+        } else {
+            println!("Offsets not found, downloading from {mirror}...");
+        }
+        let versions = exit_on_error(update_offsets(&mirror, "offsets"));
+        println!("Done! Available versions: {}", versions.join(", "));
+        if update {
+            return;
+        }
+    }
+
+    let version_offsets = exit_on_error(RekordboxOffsets::from_file("offsets"));
+    let versions = version_offsets.versions();
+    if show_help {
+        print_help(&versions);
+        return;
+    }
+    if versions.is_empty() {
+        println!("No versions in the offsets file, update it with -u");
+        return;
+    }
+
+    let mut config = exit_on_error(Config::from_file("config"));
+    if let Some(lookahead) = lookahead {
+        config.lookahead = lookahead;
+    }
+    if let Some(api_url) = api_url {
+        config.api_url = api_url;
+    }
+
+    if let Some(path) = image_path {
+        exit_on_error(check_signatures(&path, target_version.as_deref(), &version_offsets));
         return;
+    }
+
+    let replay = replay_path.map(|path| match ReplaySource::open(&path, replay_speed) {
+        Ok(replay) => replay,
+        Err(e) => {
+            println!("Could not load session: {e}");
+            std::process::exit(1);
+        }
+    });
+    if let Some(replay) = &replay {
+        if target_version.is_none() {
+            target_version = replay.rbversion.clone();
+        }
+    }
+
+    let offsets = match &target_version {
+        Some(version) => {
+            let Some(offsets) = Version::parse(version).and_then(|x| version_offsets.get(&x)) else {
+                println!("Unsupported version! {version}");
+                return;
+            };
+            println!("Targeting Rekordbox version {version}");
+            Some(offsets.clone())
+        }
+        None if replay.is_some() => {
+            println!("The session has no Rekordbox version, pass it with -v");
+            return;
+        }
+        None => {
+            println!("Rekordbox version will be read from rekordbox.exe");
+            None
+        }
     };
-    println!("Targeting Rekordbox version {target_version}");
 
     println!();
     println!(
@@ -487,10 +1606,67 @@ Available versions:",
     );
     println!();
 
-    let mut keeper = BeatKeeper::new(offsets.clone());
+    let mut replay_duration = None;
+    let attach: Attach = if let Some(replay) = replay {
+        println!("Replaying session at {replay_speed}x speed");
+        replay_duration = Some(Duration::from_micros(replay.duration_micros));
+        // A replay can only be attached once, afterwards it has ended
+        let mut replay = Some((replay, offsets));
+        Box::new(move || match replay.take() {
+            Some((replay, Some(offsets))) => Ok((Box::new(replay) as Box<dyn MemorySource>, offsets)),
+            _ => Err(Error::Memory("The replay has ended".to_string())),
+        })
+    } else {
+        let mut sessions = 0;
+        let mut last_detected = None;
+        Box::new(move || {
+            let process: Box<dyn MemorySource> = Box::new(
+                ProcessMemory::attach("rekordbox.exe")
+                    .ok_or_else(|| Error::Memory("Waiting for Rekordbox to start".to_string()))?,
+            );
+            // Sessions store the version itself, the offsets may be for a whole range of them
+            let (rbversion, offsets) = match (&target_version, &offsets) {
+                (Some(version), Some(offsets)) => (version.clone(), offsets.clone()),
+                _ => {
+                    let (version, offsets) = detect_offsets(process.as_ref(), &version_offsets)?;
+                    // Attaching is retried while Rekordbox starts up, only say this once
+                    if last_detected != Some(version) {
+                        println!("\nDetected Rekordbox {version}, using offsets for {}", offsets.rbversion);
+                        last_detected = Some(version);
+                    }
+                    (version.to_string(), offsets)
+                }
+            };
+            let Some(path) = &record_path else {
+                return Ok((process, offsets));
+            };
+
+            // Every Rekordbox restart gets a new session file, as addresses change
+            sessions += 1;
+            let path = if sessions == 1 {
+                path.clone()
+            } else {
+                format!("{path}.{sessions}")
+            };
+            match RecordingSource::create(&path, process, &rbversion, "rekordbox.exe") {
+                Ok(recording) => {
+                    println!("\nRecording session to {path}");
+                    Ok((Box::new(recording), offsets))
+                }
+                Err(e) => Err(Error::Memory(format!("Could not create session file {path}: {e}"))),
+            }
+        })
+    };
+    let content_source = exit_on_error(open_content_source(&config));
+    let metadata = MetadataFetcher::new(content_source, config.analysis_folder());
+    let mut keeper = BeatKeeper::new(attach, metadata, config.rewrite);
+    if config.lookahead != 0. {
+        println!("Sending beats {}ms early", config.lookahead);
+        keeper.change_beat_offset(config.lookahead * 1000.);
+    }
 
-    let connection = SoundSwitchConnector::discover_soundswitch();
-    let mut os2l_stream = SoundSwitchConnector::initial_connect(connection);
+    let mut soundswitch = SoundSwitchConnector::new(soundswitch_addr);
+    exit_on_error(soundswitch.connect());
     
 
     // Due to Windows timers having a default resolution 0f 15.6ms, we need to use a "too high"
@@ -498,6 +1674,7 @@ Available versions:",
     let period = Duration::from_micros(1000000 / poll_rate);
 
     let mut last_instant = Instant::now();
+    let start_instant = last_instant;
 
     let mut count = 0;
     let mut step = 0;
@@ -506,41 +1683,73 @@ Available versions:",
 
     let mut first_send = false;
 
-    // Get API bearer key
-    keeper.update_api_bearer();
-    println!("API key: {}",keeper.api_bearer);
+    let mut last_error = String::new();
+    let mut last_os2l_error = String::new();
+
+    // Windows timers tick every 15.6ms unless asked for more, which is too coarse for beats.
+    // This holds until the process ends.
+    #[cfg(windows)]
+    unsafe {
+        winapi::um::timeapi::timeBeginPeriod(1);
+    }
 
     println!("Entering loop");
     loop {
         let delta = Instant::now() - last_instant; // Is this timer accurate enough?
         last_instant = Instant::now();
 
-        keeper.update(delta); // Get values, advance time
+        let result = if let Some(duration) = replay_duration {
+            if start_instant.elapsed().mul_f64(replay_speed) > duration {
+                println!();
+                println!("Replay finished");
+                return;
+            }
+            keeper.update(delta.mul_f64(replay_speed))
+        } else {
+            keeper.update(delta) // Get values, advance time
+        };
+
+        // Memory and API errors are usually temporary, keep polling and only report changes
+        report_error(&mut last_error, result);
 
 
+        let master_deck = keeper.master_deck();
+
         if keeper.get_new_beat() {
-            SoundSwitchConnector::send_beatpos(&mut os2l_stream, keeper.last_beat);
+            // SoundSwitch reconnects by itself, keep going meanwhile
+            report_error(&mut last_os2l_error, soundswitch.send_beatpos(master_deck, keeper.last_beat));
 
             if keeper.last_beat % 4 == 1 {
-                SoundSwitchConnector::send_beat(&mut os2l_stream, keeper.last_beat, keeper.last_bpm);
+                report_error(&mut last_os2l_error, soundswitch.send_beat(keeper.last_beat, keeper.last_bpm));
             }
 
             if keeper.get_new_master_track() {
                 println!("Path: {:?}", keeper.last_master_path);
                 println!("Title: {:?}", keeper.last_master_title);
-                SoundSwitchConnector::send_track(&mut os2l_stream, &mut keeper.last_master_path);
+                report_error(&mut last_os2l_error, soundswitch.send_track(master_deck, &keeper.last_master_path));
             }
 
         }
 
+        // The master track goes out on the beat above, other decks as soon as they are loaded
+        for deck in keeper.get_new_deck_tracks() {
+            if deck + 1 != master_deck {
+                let path = &keeper.decks[deck].path;
+                report_error(&mut last_os2l_error, soundswitch.send_track(deck + 1, path));
+            }
+        }
+
         if keeper.get_new_time() {
-            if first_send == false {
+            if !first_send {
                 first_send = true;
             } else {
-                SoundSwitchConnector::send_time(&mut os2l_stream, keeper.last_time);
+                report_error(&mut last_os2l_error, soundswitch.send_time(master_deck, keeper.last_time));
             }
         }
 
+        // Answer what SoundSwitch asked for, after the regular sends so nothing goes out twice
+        report_error(&mut last_os2l_error, soundswitch.update(&keeper.os2l_state()));
+
         while let Ok(key) = rx.try_recv() {
             match key {
                 99 => {
@@ -551,14 +1760,14 @@ Available versions:",
                     //"r"
                     println!("Path: {:?}", keeper.last_master_path);
                     println!("Title: {:?}", keeper.last_master_title);
-                    SoundSwitchConnector::send_track(&mut os2l_stream, &mut keeper.last_master_path);
+                    report_error(&mut last_os2l_error, soundswitch.send_track(master_deck, &keeper.last_master_path));
                 }
                 121 => {
                     //"y"
                     println!("Resetting playing track");
-                    SoundSwitchConnector::send_track(&mut os2l_stream, &mut "".to_string());
+                    report_error(&mut last_os2l_error, soundswitch.send_track(master_deck, ""));
                     sleep(Duration::from_millis(50));
-                    SoundSwitchConnector::send_track(&mut os2l_stream, &mut keeper.last_master_path);
+                    report_error(&mut last_os2l_error, soundswitch.send_track(master_deck, &keeper.last_master_path));
                 }
                 _ => (),
             }
@@ -572,35 +1781,440 @@ Available versions:",
                 CHARS[step],
                 (keeper.last_time/1000) / 60,
                 (keeper.last_time/1000) % 60,
-                keeper.last_masterdeck_index,
+                keeper.master_deck(),
                 1000000 / (delta.as_micros().max(1)),
                 );
             print!(
                 "Master title: {}",
                 keeper.last_master_title
             );
-            stdout.flush().unwrap();
+            stdout.flush().ok();
         }
         count = (count + 1) % 120;
 
-        sleep(period);
+        // Wake up when the next beat is due rather than up to a poll after it
+        let beat_due = keeper
+            .next_beat_in()
+            .map(|x| last_instant + x.div_f64(replay_speed));
+        match beat_due {
+            Some(due) if due < Instant::now() + period => sleep_until(due),
+            _ => sleep(period),
+        }
+    }
+}
+
+/// Sleep until `deadline`. A sleep can end a little late, so the last bit is waited out awake.
+fn sleep_until(deadline: Instant) {
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if left > WAKE_MARGIN {
+            sleep(left - WAKE_MARGIN);
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
+fn print_help(versions: &[String]) {
+    println!(
+        " - Rekordbox OS2L v{} -
+A tool for sending Rekordbox track name, time and bpm to soundswitch (based on virtualdj communication)
+
+Flags:
+
+ -h  Print this help
+ -u  Fetch latest offset list from GitHub, check it and exit
+ -m  Fetch the offsets from a mirror instead of GitHub, eg. -m http://localhost:8000/
+ -v  Rekordbox version to target, eg. 6.7.3. Read from rekordbox.exe by default
+
+ -p  Change poll value
+
+ -d  Record every memory read to a session file, eg. -d session.txt
+ -l  Replay a recorded session file instead of reading from Rekordbox
+ -s  Replay speed, eg. 4 for four times faster
+
+ -a  Connect to SoundSwitch at a fixed address instead of discovering it, eg. 127.0.0.1:4444
+ -r  Ask the Rekordbox API at another address for track paths, eg. http://127.0.0.1:30001
+ -o  Send beats this many milliseconds early, for lights that react late, eg. -o 40
+
+ -i  Find the signatures of a version in a rekordbox.exe image dumped from memory and exit,
+     eg. -i rekordbox.dmp -v 7.0.0
+
+rkbx_os2l scan <field or type> finds the pointers for a value in the running Rekordbox instead,
+eg. scan master_bpm. See the README for its flags.
+rkbx_os2l verify [-v version] [-l session] checks every pointer of the offsets and exits with 0
+when all look right, 1 when some do not and 2 when it could not check.
+rkbx_os2l path <track id> [-v version] [-l session] shows the path a track is sent with, after the
+rewrite rules of the config.
+rkbx_os2l export-vdj <database.xml> [track id]... writes tracks with their beatgrid and cues into a
+VirtualDJ database, so SoundSwitch can autoscript them without VirtualDJ.
+
+Use r to resend master path/track to soundswitch.
+Use y to reset and resend master path/track to soundswitch (useful for changing to Autoloop override during a song).
+
+Available versions:",
+        env!("CARGO_PKG_VERSION"),
+    );
+    println!("{}", versions.join(", "));
+    println!();
+}
+
+/// Print an error only when it differs from the last one, so a lasting problem is not
+/// printed on every poll.
+fn report_error(last_error: &mut String, result: Result<()>) {
+    let error = result.err().map(|e| e.to_string()).unwrap_or_default();
+    if error != *last_error {
+        if !error.is_empty() {
+            println!("\n{error}");
+        }
+        *last_error = error;
+    }
+}
+
+/// Errors there is no recovering from here, like a broken offsets file, end the bridge with a
+/// message instead of a panic.
+fn exit_on_error<T>(result: Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            println!();
+            println!("{e}");
+            std::process::exit(1);
+        }
     }
 }
 
-fn download_offsets() {
-    match Command::new("curl")
-        .args([
-            "-o",
-            "offsets",
-            "https://raw.githubusercontent.com/fjel/rkbx_os2l/master/offsets",
-        ])
-        .output()
-    {
-        Ok(output) => {
-            println!("{}", String::from_utf8(output.stdout).unwrap());
-            println!("{}", String::from_utf8(output.stderr).unwrap());
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        rc::Rc,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+    use crate::signature::Signature;
+
+    const BASE: usize = 0x140000000;
+
+    /// Fake Rekordbox memory whose reads can be made to fail, as when the process exits
+    struct Exiting {
+        memory: FakeMemory,
+        exited: Arc<AtomicBool>,
+    }
+
+    impl MemorySource for Exiting {
+        fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+            match self.exited.load(Ordering::SeqCst) {
+                true => None,
+                false => self.memory.read_bytes(address, len),
+            }
+        }
+
+        fn module_base(&self, name: &str) -> Option<usize> {
+            self.memory.module_base(name)
+        }
+
+        fn find_signatures(
+            &self,
+            module: &str,
+            signatures: &[Signature],
+        ) -> Vec<std::result::Result<usize, String>> {
+            self.memory.find_signatures(module, signatures)
         }
-        Err(error) => println!("{}", error),
     }
-    println!("Done!");
+
+    struct NoContent;
+
+    impl ContentSource for NoContent {
+        fn content(&self, _track_id: i32, _bearer: &str) -> Result<Option<api::DjmdContent>> {
+            Ok(None)
+        }
+    }
+
+    fn offsets() -> RekordboxOffsets {
+        RekordboxOffsets {
+            rbversion: "6.8.5".to_string(),
+            decks: vec![DeckOffsets {
+                bar: Pointer::new(vec![], 0x20),
+                beat: Pointer::new(vec![], 0x24),
+                track_id: None,
+                time: None,
+            }],
+            master_bpm: Pointer::new(vec![], 0x10),
+            masterdeck_index: Pointer::new(vec![], 0x14),
+            api_bearer: Some(Pointer::new(vec![], 0x40)),
+        }
+    }
+
+    fn memory() -> FakeMemory {
+        let mut memory = FakeMemory::new();
+        memory.add_module("rekordbox.exe", BASE, 0x100);
+        memory.write(BASE + 0x10, 128f32);
+        memory.write(BASE + 0x20, 1i32);
+        memory.write(BASE + 0x24, 3i32);
+        memory.write_bytes(BASE + 0x40, &[b'a'; 32]);
+        memory
+    }
+
+    #[test]
+    fn reattaches_after_rekordbox_exits() {
+        let exited = Arc::new(AtomicBool::new(false));
+        let attach_exited = exited.clone();
+        let attach: Attach = Box::new(move || {
+            if attach_exited.load(Ordering::SeqCst) {
+                return Err(Error::Memory("Waiting for Rekordbox to start".to_string()));
+            }
+            let source = Exiting {
+                memory: memory(),
+                exited: attach_exited.clone(),
+            };
+            Ok((Box::new(source) as Box<dyn MemorySource>, offsets()))
+        });
+        let metadata = MetadataFetcher::new(Box::new(NoContent), None);
+        let mut keeper = BeatKeeper::new(attach, metadata, PathRewrite::default());
+        let poll = Duration::from_millis(16);
+
+        keeper.update(poll).unwrap();
+        assert!(matches!(keeper.connection, Connection::Attached(_)));
+        // The bearer was read on attaching and is not stale, which must not keep us attached
+        assert_eq!(keeper.api_bearer, "a".repeat(32));
+        assert_eq!(keeper.decks[0].beats, 7);
+        // Beats go out with the clock's tempo
+        assert_eq!(keeper.last_bpm, 128.);
+
+        exited.store(true, Ordering::SeqCst);
+        assert!(keeper.update(poll).is_err());
+        assert!(matches!(keeper.connection, Connection::Lost));
+
+        keeper.update(poll).unwrap();
+        assert!(matches!(keeper.connection, Connection::Waiting));
+
+        exited.store(false, Ordering::SeqCst);
+        keeper.last_attach = None;
+        keeper.update(poll).unwrap();
+        assert!(matches!(keeper.connection, Connection::Attached(_)));
+    }
+
+    /// Fake Rekordbox memory that can be changed while the keeper reads it
+    struct Shared(Arc<std::sync::Mutex<FakeMemory>>);
+
+    impl MemorySource for Shared {
+        fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().read_bytes(address, len)
+        }
+
+        fn module_base(&self, name: &str) -> Option<usize> {
+            self.0.lock().unwrap().module_base(name)
+        }
+
+        fn find_signatures(
+            &self,
+            module: &str,
+            signatures: &[Signature],
+        ) -> Vec<std::result::Result<usize, String>> {
+            self.0.lock().unwrap().find_signatures(module, signatures)
+        }
+    }
+
+    /// Two decks with their track ids at 0x60 and 0x64
+    fn two_decks(memory: &Arc<std::sync::Mutex<FakeMemory>>) -> Attach {
+        let memory = memory.clone();
+        Box::new(move || {
+            let mut offsets = offsets();
+            offsets.decks = (0..2)
+                .map(|i| DeckOffsets {
+                    bar: Pointer::new(vec![], 0x20),
+                    beat: Pointer::new(vec![], 0x24),
+                    track_id: Some(Pointer::new(vec![], 0x60 + i * 4)),
+                    time: None,
+                })
+                .collect();
+            Ok((Box::new(Shared(memory.clone())) as Box<dyn MemorySource>, offsets))
+        })
+    }
+
+    #[test]
+    fn bpm_only_for_the_track_it_was_read_with() {
+        let memory = Arc::new(std::sync::Mutex::new(memory()));
+        let metadata = MetadataFetcher::new(Box::new(NoContent), None);
+        let mut keeper = BeatKeeper::new(two_decks(&memory), metadata, PathRewrite::default());
+        let poll = Duration::from_millis(16);
+
+        // Deck 2 is master at 128
+        memory.lock().unwrap().write(BASE + 0x60, 1i32);
+        memory.lock().unwrap().write(BASE + 0x64, 2i32);
+        memory.lock().unwrap().write(BASE + 0x14, 1u8);
+        keeper.update(poll).unwrap();
+        assert_eq!(keeper.os2l_state().decks[1].bpm, Some(128.));
+        assert_eq!(keeper.os2l_state().decks[0].bpm, None);
+
+        // Deck 1 takes over at another tempo, deck 2 keeps its track and the tempo it had
+        memory.lock().unwrap().write(BASE + 0x14, 0u8);
+        memory.lock().unwrap().write(BASE + 0x10, 124f32);
+        keeper.update(poll).unwrap();
+        assert_eq!(keeper.os2l_state().decks[0].bpm, Some(124.));
+        assert_eq!(keeper.os2l_state().decks[1].bpm, Some(128.));
+
+        // A new track on deck 2 has no tempo until its grid or Rekordbox tells
+        memory.lock().unwrap().write(BASE + 0x64, 3i32);
+        keeper.update(poll).unwrap();
+        assert_eq!(keeper.os2l_state().decks[1].bpm, None);
+
+        keeper.decks[1].analysis = Some(Analysis {
+            beats: vec![anlz::Beat {
+                number: 1,
+                bpm: 126.,
+                time_ms: 500,
+                bar: 1,
+            }],
+            ..Default::default()
+        });
+        assert_eq!(keeper.os2l_state().decks[1].bpm, Some(126.));
+    }
+
+    /// Track 2 fails the first `failures` times it is looked up, the others are found
+    struct Flaky {
+        failures: usize,
+        requests: Arc<std::sync::Mutex<Vec<i32>>>,
+    }
+
+    impl ContentSource for Flaky {
+        fn content(&self, track_id: i32, _bearer: &str) -> Result<Option<api::DjmdContent>> {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(track_id);
+            if track_id == 2 && requests.iter().filter(|x| **x == 2).count() <= self.failures {
+                return Err(Error::Api("Status 500 for track 2".to_string()));
+            }
+            Ok(Some(api::DjmdContent {
+                folder_path: format!("D:/Music/{track_id}.mp3"),
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[test]
+    fn failed_lookups_are_retried() {
+        let memory = Arc::new(std::sync::Mutex::new(memory()));
+        memory.lock().unwrap().write(BASE + 0x60, 1i32);
+        memory.lock().unwrap().write(BASE + 0x64, 2i32);
+        let requests = Arc::new(std::sync::Mutex::new(vec![]));
+        let flaky = Flaky {
+            failures: 2,
+            requests: requests.clone(),
+        };
+        let metadata = MetadataFetcher::new(Box::new(flaky), None);
+        let mut keeper = BeatKeeper::new(two_decks(&memory), metadata, PathRewrite::default());
+        let lookups = |id| requests.lock().unwrap().iter().filter(|x| **x == id).count();
+        let poll = Duration::from_millis(16);
+
+        // Update until `done`, the lookups are answered in the background
+        let until = |keeper: &mut BeatKeeper, delta: Duration, done: &dyn Fn(&BeatKeeper) -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let _ = keeper.update(delta);
+            while !done(keeper) {
+                assert!(Instant::now() < deadline, "timed out");
+                sleep(Duration::from_millis(1));
+                let _ = keeper.update(poll);
+            }
+        };
+
+        until(&mut keeper, poll, &|keeper| {
+            !keeper.decks[0].path.is_empty() && keeper.decks[1].lookup_failures == 1
+        });
+        assert!(keeper.get_new_master_track());
+        assert_eq!(keeper.last_master_path, "D:/Music/1.mp3");
+
+        // Not again before the backoff is over
+        for _ in 0..10 {
+            keeper.update(poll).unwrap();
+        }
+        assert_eq!(lookups(2), 1);
+        until(&mut keeper, LOOKUP_RETRY, &|keeper| keeper.decks[1].lookup_failures == 2);
+        assert_eq!(lookups(2), 2);
+
+        // Deck 2 becoming master is looked up right away
+        memory.lock().unwrap().write(BASE + 0x14, 1u8);
+        until(&mut keeper, poll, &|keeper| !keeper.decks[1].path.is_empty());
+        assert_eq!(lookups(2), 3);
+        assert!(keeper.get_new_master_track());
+        assert_eq!(keeper.last_master_path, "D:/Music/2.mp3");
+        assert_eq!(lookups(1), 1);
+    }
+
+    /// Updates a keeper every 10ms for 4s, with `step` run before each update. Returns the beats,
+    /// the phase and whether a beat went out on every update.
+    fn run_session(
+        attach: Attach,
+        now: &Rc<Cell<Duration>>,
+        step: impl Fn(Duration),
+    ) -> Vec<(i32, u32, bool)> {
+        let metadata = MetadataFetcher::new(Box::new(NoContent), None);
+        let mut keeper = BeatKeeper::new(attach, metadata, PathRewrite::default());
+        let delta = Duration::from_millis(10);
+        (0..400)
+            .map(|_| {
+                step(now.get());
+                keeper.update(delta).unwrap();
+                now.set(now.get() + delta);
+                (keeper.decks[0].beats, keeper.beat_fraction.to_bits(), keeper.get_new_beat())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_are_the_same_every_run() {
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_session_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let memory = Arc::new(std::sync::Mutex::new(memory()));
+        let now = Rc::new(Cell::new(Duration::ZERO));
+
+        let recording: Attach = {
+            let (memory, now, path) = (memory.clone(), now.clone(), path.clone());
+            let mut attached = false;
+            Box::new(move || {
+                assert!(!attached, "attached twice");
+                attached = true;
+                let now = now.clone();
+                let shared = Box::new(Shared(memory.clone()));
+                let source = RecordingSource::create(&path, shared, "6.8.5", "rekordbox.exe")
+                    .unwrap()
+                    .with_clock(Box::new(move || now.get()));
+                Ok((Box::new(source) as Box<dyn MemorySource>, offsets()))
+            })
+        };
+        // Rekordbox's counter moves on every 468.75ms, seen a little late now and then
+        let recorded = run_session(recording, &now, |now| {
+            let seen = now.saturating_sub(Duration::from_millis(now.as_millis() as u64 * 7 % 13));
+            let counter = (seen.as_micros() / 468_750) as i32;
+            let mut memory = memory.lock().unwrap();
+            memory.write(BASE + 0x20, counter / 4 + 1);
+            memory.write(BASE + 0x24, counter % 4 + 1);
+        });
+
+        let replay = |now: &Rc<Cell<Duration>>| -> Attach {
+            let now = now.clone();
+            let replay = ReplaySource::open(&path, 1.).unwrap();
+            let mut replay = Some(replay.with_clock(Box::new(move || now.get())));
+            Box::new(move || match replay.take() {
+                Some(replay) => Ok((Box::new(replay) as Box<dyn MemorySource>, offsets())),
+                None => Err(Error::Memory("The replay has ended".to_string())),
+            })
+        };
+        let runs: Vec<_> = (0..2)
+            .map(|_| {
+                let now = Rc::new(Cell::new(Duration::ZERO));
+                run_session(replay(&now), &now, |_| ())
+            })
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(runs[0], runs[1]);
+        // What the keeper read is what it reads back, so it sends the same beats
+        assert_eq!(runs[0], recorded);
+        let beats = recorded.iter().filter(|x| x.2).count();
+        assert!((7..=9).contains(&beats), "{beats} beats in 4s at 128");
+    }
 }
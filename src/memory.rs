@@ -0,0 +1,367 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+#[cfg(windows)]
+use toy_arms::external::Process;
+#[cfg(windows)]
+use winapi::{
+    shared::minwindef::{LPCVOID, LPVOID},
+    um::{
+        memoryapi::{ReadProcessMemory, VirtualQueryEx},
+        winnt::{
+            HANDLE, MEMORY_BASIC_INFORMATION, MEM_COMMIT, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY,
+            PAGE_GUARD, PAGE_READWRITE, PAGE_WRITECOPY,
+        },
+    },
+};
+
+#[cfg(target_os = "linux")]
+pub use crate::wine::ProcessMemory;
+
+use crate::signature::{self, Signature};
+
+/// Somewhere we can read Rekordbox memory from.
+pub trait MemorySource {
+    /// Read `len` bytes starting at `address`, or `None` if any of them are unreadable.
+    fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>>;
+
+    /// Base address of a loaded module, eg. "rekordbox.exe".
+    fn module_base(&self, name: &str) -> Option<usize>;
+
+    /// Where the module was loaded from, if known.
+    fn module_path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// RVA of the static address each signature points to, see [`signature::scan`].
+    fn find_signatures(&self, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>>;
+
+    /// Committed, writable memory as (address, size), which is where Rekordbox keeps its state.
+    /// Empty if the source can not list its memory.
+    fn writable_regions(&self) -> Vec<(usize, usize)> {
+        vec![]
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Types without padding for which any bytes are a valid value, so they can be read from memory.
+/// Sealed, as a wrong implementation would make [`read`] unsound.
+pub trait Plain: Copy + sealed::Sealed {}
+
+macro_rules! plain {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl Plain for $t {}
+        )*
+    };
+}
+
+plain!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+impl<const N: usize> sealed::Sealed for [u8; N] {}
+impl<const N: usize> Plain for [u8; N] {}
+
+/// Read a plain value of type `T` from a memory source.
+pub fn read<T: Plain>(source: &dyn MemorySource, address: usize) -> Option<T> {
+    let bytes = source.read_bytes(address, size_of::<T>())?;
+    // Safety: T is plain data, valid for any bytes, and the length matches T
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// A live process, read through the Windows API.
+#[cfg(windows)]
+pub struct ProcessMemory {
+    handle: HANDLE,
+    modules: HashMap<String, usize>,
+}
+
+#[cfg(windows)]
+impl ProcessMemory {
+    pub fn attach(process_name: &str) -> Option<Self> {
+        let process = Process::from_process_name(process_name).ok()?;
+        let base = process.get_module_base(process_name).ok()?;
+
+        let mut modules = HashMap::new();
+        modules.insert(process_name.to_string(), base);
+
+        Some(Self {
+            handle: process.process_handle,
+            modules,
+        })
+    }
+}
+
+#[cfg(windows)]
+impl MemorySource for ProcessMemory {
+    fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; len];
+        let mut bytes_read = 0;
+        let ok = unsafe {
+            ReadProcessMemory(
+                self.handle,
+                address as LPCVOID,
+                buffer.as_mut_ptr() as LPVOID,
+                len,
+                &mut bytes_read,
+            )
+        };
+        if ok == 0 || bytes_read != len {
+            return None;
+        }
+        Some(buffer)
+    }
+
+    fn module_base(&self, name: &str) -> Option<usize> {
+        self.modules.get(name).copied()
+    }
+
+    fn find_signatures(&self, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>> {
+        signature::scan(self, module, signatures)
+    }
+
+    fn writable_regions(&self) -> Vec<(usize, usize)> {
+        let writable = PAGE_READWRITE | PAGE_WRITECOPY | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY;
+        let mut regions = vec![];
+        let mut address = 0usize;
+        loop {
+            // Safety: all zeroes is a valid MEMORY_BASIC_INFORMATION, and it is the size we pass
+            let mut info: MEMORY_BASIC_INFORMATION = unsafe { std::mem::zeroed() };
+            let written = unsafe {
+                VirtualQueryEx(
+                    self.handle,
+                    address as LPCVOID,
+                    &mut info,
+                    size_of::<MEMORY_BASIC_INFORMATION>(),
+                )
+            };
+            if written == 0 {
+                break;
+            }
+            if info.State == MEM_COMMIT && info.Protect & writable != 0 && info.Protect & PAGE_GUARD == 0 {
+                regions.push((info.BaseAddress as usize, info.RegionSize));
+            }
+            match (info.BaseAddress as usize).checked_add(info.RegionSize) {
+                Some(next) => address = next,
+                None => break,
+            }
+        }
+        regions
+    }
+}
+
+/// Memory made up in-process, for running the reader without Rekordbox.
+/// Regions are placed at fixed addresses and pointer chains can be built by writing
+/// the address of one region into another, or loaded from a module dumped from memory.
+#[derive(Default)]
+pub struct FakeMemory {
+    regions: Vec<(usize, Vec<u8>)>,
+    modules: HashMap<String, usize>,
+}
+
+impl FakeMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a module at `base`, backed by a zeroed region of `size` bytes.
+    #[cfg(test)]
+    pub fn add_module(&mut self, name: &str, base: usize, size: usize) {
+        self.modules.insert(name.to_string(), base);
+        self.add_region(base, size);
+    }
+
+    /// Register a module at `base`, backed by an image dumped from memory, eg. with x64dbg.
+    pub fn load_module(&mut self, name: &str, base: usize, path: &Path) -> io::Result<()> {
+        self.modules.insert(name.to_string(), base);
+        self.regions.push((base, fs::read(path)?));
+        Ok(())
+    }
+
+    /// Load memory saved with [`save_snapshot`].
+    pub fn load_snapshot(path: &Path) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; SNAPSHOT_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a snapshot file"));
+        }
+
+        let mut memory = FakeMemory::new();
+        for _ in 0..read_u64(&mut file)? {
+            let mut name = vec![0; read_u64(&mut file)? as usize];
+            file.read_exact(&mut name)?;
+            let base = read_u64(&mut file)? as usize;
+            memory.modules.insert(String::from_utf8_lossy(&name).to_string(), base);
+        }
+        for _ in 0..read_u64(&mut file)? {
+            let address = read_u64(&mut file)? as usize;
+            let mut data = vec![0; read_u64(&mut file)? as usize];
+            file.read_exact(&mut data)?;
+            if !data.is_empty() {
+                memory.regions.push((address, data));
+            }
+        }
+        Ok(memory)
+    }
+
+    /// Add a zeroed region of `size` bytes at `address`.
+    #[cfg(test)]
+    pub fn add_region(&mut self, address: usize, size: usize) {
+        self.regions.push((address, vec![0; size]));
+    }
+
+    /// Write raw bytes at `address`. Panics if the range is not inside a single region.
+    #[cfg(test)]
+    pub fn write_bytes(&mut self, address: usize, bytes: &[u8]) {
+        let (start, data) = self
+            .regions
+            .iter_mut()
+            .find(|(start, data)| address >= *start && address + bytes.len() <= *start + data.len())
+            .expect("Write outside of fake memory regions");
+        let offset = address - *start;
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Write a plain value of type `T` at `address`.
+    #[cfg(test)]
+    pub fn write<T: Plain>(&mut self, address: usize, value: T) {
+        // Safety: T is plain data without padding, viewed as its raw bytes
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>())
+        };
+        self.write_bytes(address, bytes);
+    }
+}
+
+impl MemorySource for FakeMemory {
+    fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+        self.regions.iter().find_map(|(start, data)| {
+            if address >= *start && address + len <= *start + data.len() {
+                let offset = address - start;
+                Some(data[offset..offset + len].to_vec())
+            } else {
+                None
+            }
+        })
+    }
+
+    fn module_base(&self, name: &str) -> Option<usize> {
+        self.modules.get(name).copied()
+    }
+
+    fn find_signatures(&self, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>> {
+        signature::scan(self, module, signatures)
+    }
+
+    fn writable_regions(&self) -> Vec<(usize, usize)> {
+        self.regions.iter().map(|(address, data)| (*address, data.len())).collect()
+    }
+}
+
+// Snapshots keep the writable memory of a process, to work out offsets without it running:
+//
+//   "rkbxsnap"                 Magic
+//   count, then for each       Modules, as name length, name, base
+//   count, then for each       Regions, as address, length, bytes
+//
+// All numbers are little endian u64.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"rkbxsnap";
+const SNAPSHOT_HEADER_SIZE: usize = 0x1000;
+
+/// Save the writable memory, and the base and headers of `module`, of a source. Regions that
+/// can not be read are saved empty. See [`FakeMemory::load_snapshot`].
+pub fn save_snapshot(source: &dyn MemorySource, module: &str, path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(SNAPSHOT_MAGIC)?;
+
+    let base = source.module_base(module);
+    file.write_all(&(base.is_some() as u64).to_le_bytes())?;
+    if let Some(base) = base {
+        file.write_all(&(module.len() as u64).to_le_bytes())?;
+        file.write_all(module.as_bytes())?;
+        file.write_all(&(base as u64).to_le_bytes())?;
+    }
+
+    // The headers are read only, but say how big the module is
+    let mut regions = source.writable_regions();
+    regions.extend(base.map(|base| (base, SNAPSHOT_HEADER_SIZE)));
+    file.write_all(&(regions.len() as u64).to_le_bytes())?;
+    for (address, size) in regions {
+        let data = source.read_bytes(address, size).unwrap_or_default();
+        file.write_all(&(address as u64).to_le_bytes())?;
+        file.write_all(&(data.len() as u64).to_le_bytes())?;
+        file.write_all(&data)?;
+    }
+    file.flush()
+}
+
+fn read_u64(file: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    file.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0x140000000;
+    const HEAP: usize = 0x20000;
+
+    fn memory() -> FakeMemory {
+        let mut memory = FakeMemory::new();
+        memory.add_module("rekordbox.exe", BASE, 0x2000);
+        memory.write_bytes(BASE, b"MZ");
+        memory.add_region(HEAP, 0x100);
+        memory.write(HEAP + 0x10, 128f32);
+        memory.write(HEAP + 0xFC, 0xDEADBEEFu32);
+        memory
+    }
+
+    #[test]
+    fn reads_inside_regions_only() {
+        let memory = memory();
+        assert_eq!(read::<f32>(&memory, HEAP + 0x10), Some(128.));
+        assert_eq!(read::<u32>(&memory, HEAP + 0xFC), Some(0xDEADBEEF));
+        assert_eq!(read::<[u8; 4]>(&memory, HEAP + 0xFC), Some([0xEF, 0xBE, 0xAD, 0xDE]));
+        // Running off the end of a region, or outside of them all
+        assert_eq!(read::<u64>(&memory, HEAP + 0xFC), None);
+        assert_eq!(read::<u32>(&memory, HEAP - 2), None);
+        assert_eq!(read::<u32>(&memory, 0x1000), None);
+        assert_eq!(memory.module_base("rekordbox.exe"), Some(BASE));
+        assert_eq!(memory.module_base("other.dll"), None);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_snapshot_{}.bin", std::process::id()));
+        save_snapshot(&memory(), "rekordbox.exe", &path).unwrap();
+        let loaded = FakeMemory::load_snapshot(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.module_base("rekordbox.exe"), Some(BASE));
+        assert_eq!(loaded.read_bytes(BASE, 2), Some(b"MZ".to_vec()));
+        assert_eq!(read::<f32>(&loaded, HEAP + 0x10), Some(128.));
+        assert_eq!(read::<u32>(&loaded, HEAP + 0xFC), Some(0xDEADBEEF));
+        assert_eq!(loaded.writable_regions().len(), memory().writable_regions().len() + 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_not_snapshot_{}.bin", std::process::id()));
+        fs::write(&path, b"not a snapshot").unwrap();
+        let loaded = FakeMemory::load_snapshot(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread::spawn,
+};
+
+use crate::{
+    anlz::Analysis,
+    api::DjmdContent,
+    error::{Error, Result},
+};
+
+/// Tracks kept after their lookup, a few sets' worth
+const CACHE_SIZE: usize = 256;
+
+/// Somewhere tracks can be looked up by their id
+pub trait ContentSource: Send {
+    /// The track, or `None` if there is no such track
+    fn content(&self, track_id: i32, bearer: &str) -> Result<Option<DjmdContent>>;
+
+    /// Ids of every track in the library
+    fn track_ids(&self) -> Result<Vec<i32>> {
+        Err(Error::Api(
+            "Only the database can list every track, give track ids instead".to_string(),
+        ))
+    }
+
+    /// Whether lookups need the bearer read from Rekordbox memory
+    fn uses_bearer(&self) -> bool {
+        true
+    }
+}
+
+/// A track that was found, with its analysis
+#[derive(Clone)]
+pub struct Track {
+    pub content: DjmdContent,
+    /// `None` when the track has no analysis or there is no folder to find it in
+    pub analysis: Option<std::result::Result<Analysis, String>>,
+}
+
+/// Answer for one track
+pub struct Lookup {
+    pub track_id: i32,
+    /// The bearer the request was made with, to tell whether a refused one has changed since
+    pub bearer: String,
+    pub result: Result<Option<Track>>,
+}
+
+/// Looks a track up in the API or the database and reads its ANLZ analysis in a thread of its
+/// own, so a slow answer or a slow disk does not hold up the beat. Tracks that were found are
+/// cached by their id, so a deck becoming master or a track being loaded again needs no request.
+pub struct MetadataFetcher {
+    requests: Sender<(i32, String)>,
+    lookups: Receiver<Lookup>,
+    /// Most recently used last
+    cache: VecDeque<(i32, Track)>,
+    pending: HashSet<i32>,
+    uses_bearer: bool,
+}
+
+impl MetadataFetcher {
+    /// Analysis paths are taken from `analysis_folder`, without one tracks have no analysis
+    pub fn new(source: Box<dyn ContentSource>, analysis_folder: Option<PathBuf>) -> Self {
+        let uses_bearer = source.uses_bearer();
+        let (requests, requests_rx) = channel::<(i32, String)>();
+        let (lookups_tx, lookups) = channel();
+        // Ends when the fetcher is dropped and the requests channel closes
+        spawn(move || {
+            for (track_id, bearer) in requests_rx {
+                let result = source.content(track_id, &bearer).map(|content| {
+                    content.map(|content| Track {
+                        analysis: read_analysis(&content, analysis_folder.as_deref()),
+                        content,
+                    })
+                });
+                let lookup = Lookup {
+                    track_id,
+                    bearer,
+                    result,
+                };
+                if lookups_tx.send(lookup).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests,
+            lookups,
+            cache: VecDeque::new(),
+            pending: HashSet::new(),
+            uses_bearer,
+        }
+    }
+
+    pub fn uses_bearer(&self) -> bool {
+        self.uses_bearer
+    }
+
+    /// The track if it was looked up before
+    pub fn get(&mut self, track_id: i32) -> Option<Track> {
+        let index = self.cache.iter().position(|(id, _)| *id == track_id)?;
+        let entry = self.cache.remove(index)?;
+        let track = entry.1.clone();
+        self.cache.push_back(entry);
+        Some(track)
+    }
+
+    /// Ask for a track in the background, unless it is already on its way
+    pub fn request(&mut self, track_id: i32, bearer: &str) {
+        if self.pending.insert(track_id) {
+            // The thread only ends with the fetcher, so this can not fail
+            let _ = self.requests.send((track_id, bearer.to_string()));
+        }
+    }
+
+    /// Answers that arrived since the last call. Found tracks are cached as well.
+    pub fn lookups(&mut self) -> Vec<Lookup> {
+        let lookups: Vec<Lookup> = self.lookups.try_iter().collect();
+        for lookup in &lookups {
+            self.pending.remove(&lookup.track_id);
+            if let Ok(Some(track)) = &lookup.result {
+                self.cache.retain(|(id, _)| *id != lookup.track_id);
+                if self.cache.len() == CACHE_SIZE {
+                    self.cache.pop_front();
+                }
+                self.cache.push_back((lookup.track_id, track.clone()));
+            }
+        }
+        lookups
+    }
+}
+
+/// The analysis of a track, from the folder the analysis paths start from
+pub fn read_analysis(
+    content: &DjmdContent,
+    folder: Option<&Path>,
+) -> Option<std::result::Result<Analysis, String>> {
+    let path = content.analysis_data_path.as_deref().filter(|x| !x.is_empty())?;
+    // The path starts with a /, which would replace the folder when joined
+    let path = folder?.join(path.trim_start_matches(['/', '\\']));
+    Some(Analysis::from_file(&path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    /// Tracks with a positive id exist, 0 does not, and a negative id fails
+    struct Library {
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl ContentSource for Library {
+        fn content(&self, track_id: i32, _bearer: &str) -> Result<Option<DjmdContent>> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            match track_id {
+                1.. => Ok(Some(DjmdContent {
+                    folder_path: format!("D:/Music/{track_id}.mp3"),
+                    ..Default::default()
+                })),
+                0 => Ok(None),
+                _ => Err(Error::Api(format!("Status 500 for track {track_id}"))),
+            }
+        }
+    }
+
+    fn fetcher() -> (MetadataFetcher, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let library = Library {
+            requests: requests.clone(),
+        };
+        (MetadataFetcher::new(Box::new(library), None), requests)
+    }
+
+    /// Wait for `count` answers
+    fn answers(fetcher: &mut MetadataFetcher, count: usize) -> Vec<Lookup> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut lookups = vec![];
+        while lookups.len() < count {
+            assert!(Instant::now() < deadline, "got {} of {count} answers", lookups.len());
+            lookups.extend(fetcher.lookups());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        lookups
+    }
+
+    #[test]
+    fn found_tracks_are_cached() {
+        let (mut fetcher, requests) = fetcher();
+        assert!(fetcher.get(1).is_none());
+        fetcher.request(1, "bearer");
+        let lookups = answers(&mut fetcher, 1);
+        assert_eq!((lookups[0].track_id, lookups[0].bearer.as_str()), (1, "bearer"));
+        assert_eq!(fetcher.get(1).unwrap().content.folder_path, "D:/Music/1.mp3");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn duplicate_requests_are_one() {
+        let (mut fetcher, requests) = fetcher();
+        for _ in 0..3 {
+            fetcher.request(1, "bearer");
+        }
+        answers(&mut fetcher, 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(fetcher.lookups().is_empty());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Once answered it can be asked again
+        fetcher.request(1, "bearer");
+        answers(&mut fetcher, 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn missing_and_failed_tracks_are_not_cached() {
+        let (mut fetcher, _) = fetcher();
+        fetcher.request(0, "");
+        fetcher.request(-1, "");
+        let lookups = answers(&mut fetcher, 2);
+        let result = |id| &lookups.iter().find(|x| x.track_id == id).unwrap().result;
+        assert!(matches!(result(0), Ok(None)));
+        assert!(matches!(result(-1), Err(Error::Api(e)) if e == "Status 500 for track -1"));
+        assert!(fetcher.get(0).is_none());
+        assert!(fetcher.get(-1).is_none());
+
+        // A failed track is not pending any more, so it can be retried
+        fetcher.request(-1, "");
+        assert_eq!(answers(&mut fetcher, 1)[0].track_id, -1);
+    }
+
+    #[test]
+    fn least_recently_used_goes_first() {
+        let (mut fetcher, _) = fetcher();
+        for id in 1..=CACHE_SIZE as i32 {
+            fetcher.request(id, "");
+        }
+        answers(&mut fetcher, CACHE_SIZE);
+        assert!(fetcher.get(1).is_some());
+
+        // 1 was just used, so 2 is the oldest
+        fetcher.request(1000, "");
+        answers(&mut fetcher, 1);
+        assert_eq!(fetcher.cache.len(), CACHE_SIZE);
+        assert!(fetcher.get(2).is_none());
+        assert!(fetcher.get(1).is_some());
+        assert!(fetcher.get(3).is_some());
+        assert!(fetcher.get(1000).is_some());
+    }
+}
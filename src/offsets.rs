@@ -1,69 +1,429 @@
-use std::{collections::HashMap, fs::File, io::Read};
+use std::{collections::HashMap, fmt, fs};
+
+use crate::{
+    error::{Error, Result},
+    signature::Signature,
+};
+
+/// Rekordbox has at most four decks
+pub const MAX_DECKS: usize = 4;
+
+/// Type of the value at the end of a pointer chain
+#[derive(Clone, Copy, PartialEq)]
+pub enum FieldType {
+    F32,
+    I32,
+    U8,
+    String,
+}
+
+impl FieldType {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input {
+            "f32" => Some(FieldType::F32),
+            "i32" => Some(FieldType::I32),
+            "u8" => Some(FieldType::U8),
+            "string" => Some(FieldType::String),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FieldType::F32 => "f32",
+            FieldType::I32 => "i32",
+            FieldType::U8 => "u8",
+            FieldType::String => "string",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Type of a field in the keyed format, or `None` if there is no such field
+pub fn field_type(key: &str) -> Option<FieldType> {
+    match key {
+        "master_bpm" => return Some(FieldType::F32),
+        "masterdeck_index" => return Some(FieldType::U8),
+        "api_bearer" => return Some(FieldType::String),
+        _ => (),
+    }
+    let (deck, field) = key.split_once('.')?;
+    let n = deck.strip_prefix("deck")?.parse::<usize>().ok()?;
+    if !(1..=MAX_DECKS).contains(&n) {
+        return None;
+    }
+    match field {
+        "bar" | "beat" | "track_id" | "time" => Some(FieldType::I32),
+        _ => None,
+    }
+}
+
+/// A Rekordbox version like 6.8.5, compared part by part as numbers so 6.10 is newer than 6.9
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub Vec<u32>);
+
+impl Version {
+    pub fn parse(input: &str) -> Option<Self> {
+        input
+            .split('.')
+            .map(|x| x.parse::<u32>().ok())
+            .collect::<Option<Vec<u32>>>()
+            .map(Version)
+    }
+
+    /// The first `parts` parts, so 6.8.5.1 can be compared with 6.8.5
+    fn truncated(&self, parts: usize) -> Version {
+        Version(self.0.iter().take(parts).copied().collect())
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|x| x.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+/// The versions a block of the offsets file is for
+#[derive(Clone)]
+pub enum VersionSpec {
+    /// This version, and any more specific one, eg. 6.8.5 covers 6.8.5.1
+    Exact(Version),
+    /// Every version from the first to the last, both included, eg. 7.0.0..7.0.3
+    Range(Version, Version),
+}
+
+impl VersionSpec {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.split_once("..") {
+            Some((first, last)) => {
+                let (first, last) = (Version::parse(first)?, Version::parse(last)?);
+                (first <= last).then_some(VersionSpec::Range(first, last))
+            }
+            None => Version::parse(input).map(VersionSpec::Exact),
+        }
+    }
+
+    pub fn contains(&self, version: &Version) -> bool {
+        match self {
+            VersionSpec::Exact(own) => version.0.len() >= own.0.len() && version.truncated(own.0.len()) == *own,
+            VersionSpec::Range(first, last) => {
+                *first <= version.truncated(first.0.len()) && version.truncated(last.0.len()) <= *last
+            }
+        }
+    }
+
+    /// Exact versions win over ranges, then the most specific one
+    fn specificity(&self) -> (bool, usize) {
+        match self {
+            VersionSpec::Exact(version) => (true, version.0.len()),
+            VersionSpec::Range(first, _) => (false, first.0.len()),
+        }
+    }
+
+    /// Newest version covered, for sorting
+    fn newest(&self) -> &Version {
+        match self {
+            VersionSpec::Exact(version) | VersionSpec::Range(_, version) => version,
+        }
+    }
+}
+
+/// Index of the block that `version` should use: the most specific one, or the last one
+/// defined if several are equally specific
+fn best_match<'a>(specs: impl Iterator<Item = &'a VersionSpec>, version: &Version) -> Option<usize> {
+    specs
+        .enumerate()
+        .filter(|(_, spec)| spec.contains(version))
+        .max_by_key(|(_, spec)| spec.specificity())
+        .map(|(i, _)| i)
+}
+
+/// Fields of a keyed block, with the line each was set on
+type KeyedFields = HashMap<String, (usize, Pointer)>;
+
+/// One block of the offsets file before inheritance is resolved
+struct Block {
+    line: usize,
+    name: String,
+    spec: VersionSpec,
+    inherits: Option<Version>,
+    fields: KeyedFields,
+}
+
+/// Every block of an offsets file, newest version first
+pub struct OffsetsFile {
+    entries: Vec<(VersionSpec, RekordboxOffsets)>,
+}
+
+impl OffsetsFile {
+    /// Offsets for a version, eg. 6.8.5 or the 6.8.5.1 read from rekordbox.exe
+    pub fn get(&self, version: &Version) -> Option<&RekordboxOffsets> {
+        let index = best_match(self.entries.iter().map(|(spec, _)| spec), version)?;
+        Some(&self.entries[index].1)
+    }
+
+    /// Versions and ranges as written in the file, newest first
+    pub fn versions(&self) -> Vec<String> {
+        self.entries.iter().map(|(_, x)| x.rbversion.clone()).collect()
+    }
+}
 
 impl RekordboxOffsets {
-    pub fn from_lines(lines: &[String]) -> RekordboxOffsets {
-        let mut rows = lines.iter();
-        RekordboxOffsets {
-            rbversion: rows.next().unwrap().to_string(),
-            deck1bar: Pointer::from_string(rows.next().unwrap()),
-            deck1beat: Pointer::from_string(rows.next().unwrap()),
-            deck2bar: Pointer::from_string(rows.next().unwrap()),
-            deck2beat: Pointer::from_string(rows.next().unwrap()),
-            master_bpm: Pointer::from_string(rows.next().unwrap()),
-            masterdeck_index: Pointer::from_string(rows.next().unwrap()),
-            // track_path_baseoffset: hexparse(rows.next().unwrap()),
-            deck1_track_id: Pointer::from_string(rows.next().unwrap()),
-            deck2_track_id: Pointer::from_string(rows.next().unwrap()),
-            api_bearer: Pointer::from_string(rows.next().unwrap()),
-            deck1_time: Pointer::from_string(rows.next().unwrap()),
-            deck2_time: Pointer::from_string(rows.next().unwrap()),
-        }
-    }
-
-    pub fn from_file(name: &str) -> HashMap<String, RekordboxOffsets> {
-        let mut file = File::open(name).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        drop(file);
-
-        let mut map = HashMap::new();
+    /// Parse one version block, given as (line number, line) pairs. The header is a version or
+    /// range, optionally followed by `inherits <version>`. Then come either `name = pointer`
+    /// rows, or the rows of the original positional format.
+    fn parse_block(lines: &[(usize, String)]) -> Result<Block> {
+        let (line, header) = lines.first().ok_or(Error::Offsets {
+            line: None,
+            message: "Empty version block".to_string(),
+        })?;
+        let error = |message: String| Error::Offsets { line: Some(*line), message };
+
+        let words: Vec<&str> = header.split_whitespace().collect();
+        let (name, inherits) = match words.as_slice() {
+            [name] => (*name, None),
+            [name, "inherits", parent] => {
+                let parent = Version::parse(parent).ok_or_else(|| error(format!("Invalid version '{parent}'")))?;
+                (*name, Some(parent))
+            }
+            _ => return Err(error(format!("Expected 'version [inherits version]', got '{header}'"))),
+        };
+        let spec = VersionSpec::parse(name).ok_or_else(|| error(format!("Invalid version '{name}'")))?;
+
+        let rows = &lines[1..];
+        // A block that only inherits does not need any rows
+        let fields = if rows.first().is_none_or(|(_, row)| row.contains('=')) {
+            RekordboxOffsets::keyed_fields(rows)?
+        } else {
+            RekordboxOffsets::positional_fields(rows)?
+        };
+
+        Ok(Block {
+            line: *line,
+            name: name.to_string(),
+            spec,
+            inherits,
+            fields,
+        })
+    }
+
+    /// `name[: type] = pointer` rows in any order, see `field_type` for the names
+    fn keyed_fields(rows: &[(usize, String)]) -> Result<KeyedFields> {
+        let mut fields = KeyedFields::new();
+        for (line, row) in rows {
+            let error = |message: String| Error::Offsets { line: Some(*line), message };
+            let row = row.split('#').next().unwrap_or_default();
+            let (key, value) = row
+                .split_once('=')
+                .ok_or_else(|| error(format!("Expected 'name = pointer', got '{}'", row.trim())))?;
+            let (key, given_type) = match key.split_once(':') {
+                Some((key, given_type)) => (key.trim(), Some(given_type.trim())),
+                None => (key.trim(), None),
+            };
+
+            let expected = field_type(key).ok_or_else(|| error(format!("Unknown field '{key}'")))?;
+            if let Some(given_type) = given_type {
+                let given = FieldType::parse(given_type)
+                    .ok_or_else(|| error(format!("{key}: unknown type '{given_type}'")))?;
+                if given != expected {
+                    return Err(error(format!("{key} is {expected}, not {given}")));
+                }
+            }
+
+            let pointer = Pointer::from_string(value).map_err(|message| error(format!("{key}: {message}")))?;
+            if fields.insert(key.to_string(), (*line, pointer)).is_some() {
+                return Err(error(format!("{key} is set twice")));
+            }
+        }
+        Ok(fields)
+    }
+
+    /// The original format: one row per value in a fixed order
+    fn positional_fields(rows: &[(usize, String)]) -> Result<KeyedFields> {
+        // The original two deck layout, with rows for the first two decks spread around
+        let mut keys: Vec<String> = [
+            "deck1.bar",
+            "deck1.beat",
+            "deck2.bar",
+            "deck2.beat",
+            "master_bpm",
+            "masterdeck_index",
+            // "track_path_baseoffset",
+            "deck1.track_id",
+            "deck2.track_id",
+            "api_bearer",
+            "deck1.time",
+            "deck2.time",
+        ]
+        .map(|x| x.to_string())
+        .to_vec();
+
+        // Any further decks follow as groups of bar, beat, track id and time rows
+        let extra_decks = rows.len().saturating_sub(keys.len()).div_ceil(4);
+        for n in 3..3 + extra_decks {
+            keys.extend(["bar", "beat", "track_id", "time"].map(|x| format!("deck{n}.{x}")));
+        }
+
+        let mut fields = KeyedFields::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let (line, row) = rows.get(i).ok_or_else(|| Error::Offsets {
+                line: rows.last().map(|x| x.0),
+                message: format!("Missing row for {key}"),
+            })?;
+            let pointer = Pointer::from_string(row).map_err(|message| Error::Offsets {
+                line: Some(*line),
+                message: format!("{key}: {message}"),
+            })?;
+            fields.insert(key, (*line, pointer));
+        }
+        Ok(fields)
+    }
+
+    /// Build the offsets from all fields of a block, including inherited ones
+    fn from_fields(block: &Block, mut fields: KeyedFields) -> Result<RekordboxOffsets> {
+        let required = |fields: &mut KeyedFields, key: &str| {
+            fields.remove(key).map(|(_, pointer)| pointer).ok_or_else(|| Error::Offsets {
+                line: Some(block.line),
+                message: format!("{} is missing {key}", block.name),
+            })
+        };
+        let optional = |fields: &mut KeyedFields, key: &str| fields.remove(key).map(|(_, pointer)| pointer);
+
+        let master_bpm = required(&mut fields, "master_bpm")?;
+        let masterdeck_index = required(&mut fields, "masterdeck_index")?;
+        let api_bearer = optional(&mut fields, "api_bearer");
+
+        // Decks count up from deck 1, a deck is there as soon as any of its fields is
+        let mut decks = vec![];
+        for n in 1..=MAX_DECKS {
+            if n > 1 && !fields.keys().any(|x| x.starts_with(&format!("deck{n}."))) {
+                break;
+            }
+            decks.push(DeckOffsets {
+                bar: required(&mut fields, &format!("deck{n}.bar"))?,
+                beat: required(&mut fields, &format!("deck{n}.beat"))?,
+                track_id: optional(&mut fields, &format!("deck{n}.track_id")),
+                time: optional(&mut fields, &format!("deck{n}.time")),
+            });
+        }
+        // Whatever is left belongs to a deck after a missing one
+        if let Some((key, (line, _))) = fields.iter().min_by_key(|(_, (line, _))| *line) {
+            return Err(Error::Offsets {
+                line: Some(*line),
+                message: format!("{key} is set, but deck {} is missing", decks.len() + 1),
+            });
+        }
+
+        Ok(RekordboxOffsets {
+            rbversion: block.name.clone(),
+            decks,
+            master_bpm,
+            masterdeck_index,
+            api_bearer,
+        })
+    }
+
+    /// Fields of a block with those of the blocks it inherits from underneath
+    fn resolve(blocks: &[Block], index: usize, depth: usize) -> Result<KeyedFields> {
+        let block = &blocks[index];
+        let Some(parent) = &block.inherits else {
+            return Ok(block.fields.clone());
+        };
+        let error = |message: String| Error::Offsets { line: Some(block.line), message };
+
+        if depth > blocks.len() {
+            return Err(error(format!("{} inherits from itself through {parent}", block.name)));
+        }
+        let parent_index = best_match(blocks.iter().map(|x| &x.spec), parent)
+            .ok_or_else(|| error(format!("{} inherits {parent}, which is not in the file", block.name)))?;
+
+        let mut fields = RekordboxOffsets::resolve(blocks, parent_index, depth + 1)?;
+        fields.extend(block.fields.clone());
+        Ok(fields)
+    }
+
+    pub fn from_file(name: &str) -> Result<OffsetsFile> {
+        let contents = fs::read_to_string(name).map_err(|e| Error::Offsets {
+            line: None,
+            message: format!("{name}: {e}"),
+        })?;
+        RekordboxOffsets::parse(&contents)
+    }
+
+    /// Blocks are separated by empty lines, lines starting with # are comments
+    fn parse(contents: &str) -> Result<OffsetsFile> {
+        let mut blocks = vec![];
 
         let mut lines = vec![];
-        for line in contents.lines() {
+        // Chain an empty line so the last block is parsed even without a trailing newline
+        for (i, line) in contents.lines().chain([""]).enumerate() {
             if line.is_empty() {
                 if !lines.is_empty() {
-                    let o = RekordboxOffsets::from_lines(&lines);
-                    map.insert(o.rbversion.clone(), o);
+                    blocks.push(RekordboxOffsets::parse_block(&lines)?);
                     lines.clear();
                 }
             } else if !line.starts_with('#') {
-                lines.push(line.to_string());
+                lines.push((i + 1, line.to_string()));
             }
         }
 
-        map
+        // Blocks can inherit from blocks further down the file, so resolve once all are parsed
+        let mut entries = vec![];
+        for (i, block) in blocks.iter().enumerate() {
+            let fields = RekordboxOffsets::resolve(&blocks, i, 0)?;
+            entries.push((block.spec.clone(), RekordboxOffsets::from_fields(block, fields)?));
+        }
+        entries.sort_by(|(a, _), (b, _)| b.newest().cmp(a.newest()));
+
+        Ok(OffsetsFile { entries })
     }
 }
 
 #[derive(Clone)]
 pub struct RekordboxOffsets {
     pub rbversion: String,
-    pub deck1bar: Pointer,
-    pub deck1beat: Pointer,
-    pub deck2bar: Pointer,
-    pub deck2beat: Pointer,
+    /// Deck 1 first
+    pub decks: Vec<DeckOffsets>,
     pub master_bpm: Pointer,
     pub masterdeck_index: Pointer,
-    // pub track_path_baseoffset: Pointer,
-    pub deck1_track_id: Pointer,
-    pub deck2_track_id: Pointer,
-    pub api_bearer: Pointer,
-    pub deck1_time: Pointer,
-    pub deck2_time: Pointer,  // CHANGE
+    /// Without it track paths can not be looked up
+    pub api_bearer: Option<Pointer>,
+}
+
+impl RekordboxOffsets {
+    /// Every pointer with its field name, eg. deck1.bar
+    pub fn pointers_mut(&mut self) -> Vec<(String, &mut Pointer)> {
+        let mut pointers = vec![
+            ("master_bpm".to_string(), &mut self.master_bpm),
+            ("masterdeck_index".to_string(), &mut self.masterdeck_index),
+        ];
+        pointers.extend(self.api_bearer.as_mut().map(|x| ("api_bearer".to_string(), x)));
+        for (i, deck) in self.decks.iter_mut().enumerate() {
+            let n = i + 1;
+            pointers.push((format!("deck{n}.bar"), &mut deck.bar));
+            pointers.push((format!("deck{n}.beat"), &mut deck.beat));
+            pointers.extend(deck.track_id.as_mut().map(|x| (format!("deck{n}.track_id"), x)));
+            pointers.extend(deck.time.as_mut().map(|x| (format!("deck{n}.time"), x)));
+        }
+        pointers
+    }
+}
+
+#[derive(Clone)]
+pub struct DeckOffsets {
+    pub bar: Pointer,
+    pub beat: Pointer,
+    pub track_id: Option<Pointer>,
+    pub time: Option<Pointer>,
 }
 
 #[derive(Clone)]
 pub struct Pointer {
+    /// Finds the static address instead of the first number, see `Pointer::rebase`
+    pub signature: Option<Signature>,
     pub offsets: Vec<usize>,
     pub final_offset: usize,
 }
@@ -71,17 +431,260 @@ pub struct Pointer {
 impl Pointer {
     pub fn new(offests: Vec<usize>, final_offset: usize) -> Pointer {
         Pointer {
+            signature: None,
             offsets: offests,
             final_offset,
         }
     }
 
-    pub fn from_string(input: &str) -> Self {
-        let split = input.split(' ').map(hexparse).collect::<Vec<usize>>();
-        Self::new(split[0..split.len() - 1].to_vec(), *split.last().unwrap())
+    /// Hex offsets, the first one from the module base. It can be replaced by a signature,
+    /// eg. `[48 8B 05 ?? ?? ?? ??]+3 0 40 BA0`
+    pub fn from_string(input: &str) -> std::result::Result<Self, String> {
+        let input = input.trim_start();
+        let (signature, input) = if input.starts_with('[') {
+            let end = input.find(']').ok_or("Missing ] after the signature")?;
+            let end = input[end..].find(char::is_whitespace).map_or(input.len(), |x| end + x);
+            (Some(Signature::parse(&input[..end])?), &input[end..])
+        } else {
+            (None, input)
+        };
+
+        let split = input
+            .split_whitespace()
+            .map(hexparse)
+            .collect::<std::result::Result<Vec<usize>, String>>()?;
+        let (final_offset, offsets) = split.split_last().ok_or("Empty pointer")?;
+        Ok(Self {
+            signature,
+            ..Self::new(offsets.to_vec(), *final_offset)
+        })
+    }
+
+    /// Add the RVA a signature was found at to the first number, which makes
+    /// `[...]+3 0 40 BA0` the same as `52EA410 40 BA0` when the signature points at 52EA410
+    pub fn rebase(&mut self, rva: usize) {
+        self.signature = None;
+        match self.offsets.first_mut() {
+            Some(first) => *first += rva,
+            None => self.final_offset += rva,
+        }
+    }
+}
+
+/// Back to the offsets file form
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(signature) = &self.signature {
+            write!(f, "{signature} ")?;
+        }
+        for offset in &self.offsets {
+            write!(f, "{offset:X} ")?;
+        }
+        write!(f, "{:X}", self.final_offset)
     }
 }
 
-fn hexparse(input: &str) -> usize {
-    usize::from_str_radix(input, 16).unwrap()
+fn hexparse(input: &str) -> std::result::Result<usize, String> {
+    usize::from_str_radix(input, 16).map_err(|_| format!("'{input}' is not a hex number"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSITIONAL: &str = "\
+6.8.5
+052EA410 28 0 48 2468
+052EA410 28 0 48 246C
+052EA410 28 0 50 2468
+052EA410 28 0 50 246C
+0544A460 28 180 0 140
+052413A8 20 278 124
+0442C0F8 1FC
+0442C0F8 200
+04436DB0 0
+0443F5D0 120 1AC
+0443F5D0 128 1AC
+";
+
+    fn parse(contents: &str) -> OffsetsFile {
+        RekordboxOffsets::parse(contents).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Line and message of the error `contents` gives
+    fn error(contents: &str) -> (usize, String) {
+        match RekordboxOffsets::parse(contents) {
+            Err(Error::Offsets { line: Some(line), message }) => (line, message),
+            Err(e) => panic!("not an offsets error: {e}"),
+            Ok(_) => panic!("parsed"),
+        }
+    }
+
+    fn get(file: &OffsetsFile, version: &str) -> RekordboxOffsets {
+        file.get(&Version::parse(version).unwrap()).unwrap().clone()
+    }
+
+    #[test]
+    fn positional() {
+        let offsets = get(&parse(POSITIONAL), "6.8.5");
+        assert_eq!(offsets.rbversion, "6.8.5");
+        assert_eq!(offsets.decks.len(), 2);
+        assert_eq!(offsets.decks[1].beat.to_string(), "52EA410 28 0 50 246C");
+        assert_eq!(offsets.master_bpm.to_string(), "544A460 28 180 0 140");
+        assert_eq!(offsets.masterdeck_index.to_string(), "52413A8 20 278 124");
+        assert_eq!(offsets.decks[0].track_id.as_ref().unwrap().to_string(), "442C0F8 1FC");
+        assert_eq!(offsets.api_bearer.unwrap().to_string(), "4436DB0 0");
+        assert_eq!(offsets.decks[1].time.as_ref().unwrap().to_string(), "443F5D0 128 1AC");
+    }
+
+    #[test]
+    fn positional_extra_decks() {
+        let four_decks = format!("{POSITIONAL}1 2\n3 4\n5\n6 7\n8 9\nA B\nC\nD E\n");
+        let offsets = get(&parse(&four_decks), "6.8.5");
+        assert_eq!(offsets.decks.len(), 4);
+        assert_eq!(offsets.decks[2].bar.to_string(), "1 2");
+        assert_eq!(offsets.decks[3].time.as_ref().unwrap().to_string(), "D E");
+
+        // A deck is all four rows or none
+        let (line, message) = error(&format!("{POSITIONAL}1 2\n3 4\n"));
+        assert_eq!((line, message.as_str()), (14, "Missing row for deck3.track_id"));
+    }
+
+    #[test]
+    fn keyed() {
+        let offsets = get(
+            &parse(
+                "\
+# Comments and fields in any order
+7.0.0
+deck1.beat = 52EA410 28 0 48 246C
+deck1.bar: i32 = 52EA410 28 0 48 2468   # with a type
+masterdeck_index = 52413A8 20 278 124
+master_bpm: f32 = [48 8B 05 ?? ?? ?? ??]+3 0 40 BA0
+deck2.bar = 1
+deck2.beat = 2
+",
+            ),
+            "7.0.0",
+        );
+        assert_eq!(offsets.decks.len(), 2);
+        assert_eq!(offsets.decks[0].bar.to_string(), "52EA410 28 0 48 2468");
+        assert_eq!(offsets.master_bpm.to_string(), "[48 8B 05 ?? ?? ?? ??]+3 0 40 BA0");
+        assert!(offsets.api_bearer.is_none());
+        assert!(offsets.decks[1].track_id.is_none());
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let block = "7.0.0\nmaster_bpm = 1\nmasterdeck_index = 2\ndeck1.bar = 3\ndeck1.beat = 4\n";
+        assert_eq!(
+            error(&format!("{block}deck1.pitch = 5\n")),
+            (6, "Unknown field 'deck1.pitch'".to_string())
+        );
+        assert_eq!(
+            error(&format!("{block}deck1.time: f32 = 5\n")),
+            (6, "deck1.time is i32, not f32".to_string())
+        );
+        assert_eq!(
+            error(&format!("{block}deck1.bar = 5\n")),
+            (6, "deck1.bar is set twice".to_string())
+        );
+        assert_eq!(
+            error(&format!("{block}deck3.bar = 5\n")),
+            (6, "deck3.bar is set, but deck 2 is missing".to_string())
+        );
+        assert_eq!(error(&format!("# first\n\n{block}deck2.time = 5 G\n")).0, 8);
+        assert_eq!(
+            error("7.0.0\nmaster_bpm = 1\n"),
+            (1, "7.0.0 is missing masterdeck_index".to_string())
+        );
+        assert_eq!(error("7.0.x\n").1, "Invalid version '7.0.x'");
+        assert_eq!(
+            error("7.0.0 extends 6.8.5\n").1,
+            "Expected 'version [inherits version]', got '7.0.0 extends 6.8.5'"
+        );
+    }
+
+    #[test]
+    fn versions_compare_as_numbers() {
+        let v = |x: &str| Version::parse(x).unwrap();
+        assert!(v("6.10.0") > v("6.9.9"));
+        assert!(v("7.0.0") > v("6.8.5.1"));
+        assert!(Version::parse("6.x").is_none());
+
+        let file = parse(&format!("{POSITIONAL}\n6.10.0 inherits 6.8.5\n\n6.9.0 inherits 6.8.5\n"));
+        assert_eq!(file.versions(), vec!["6.10.0", "6.9.0", "6.8.5"]);
+    }
+
+    #[test]
+    fn inherits() {
+        // A block can inherit from one further down, and only sets what differs
+        let file = parse(&format!(
+            "7.0.1 inherits 7.0.0\nmaster_bpm = 0544A460 40 170 28 BA0\n\n7.0.0 inherits 6.8.5\n\n{POSITIONAL}"
+        ));
+        let offsets = get(&file, "7.0.1");
+        assert_eq!(offsets.rbversion, "7.0.1");
+        assert_eq!(offsets.master_bpm.to_string(), "544A460 40 170 28 BA0");
+        assert_eq!(offsets.decks[1].bar.to_string(), "52EA410 28 0 50 2468");
+        assert_eq!(get(&file, "7.0.0").master_bpm.to_string(), "544A460 28 180 0 140");
+
+        // Adding a deck to an inherited one
+        let file = parse(&format!("{POSITIONAL}\n7.0.0 inherits 6.8.5\ndeck3.bar = 1\ndeck3.beat = 2\n"));
+        assert_eq!(get(&file, "7.0.0").decks.len(), 3);
+
+        assert_eq!(
+            error(&format!("{POSITIONAL}\n7.0.0 inherits 6.9.0\n")),
+            (14, "7.0.0 inherits 6.9.0, which is not in the file".to_string())
+        );
+        let (_, message) = error("7.0.0 inherits 7.0.1\n\n7.0.1 inherits 7.0.0\n");
+        assert!(message.contains("inherits from itself"), "{message}");
+    }
+
+    #[test]
+    fn ranges() {
+        let file = parse(&format!(
+            "{POSITIONAL}\n7.0.0..7.0.3 inherits 6.8.5\nmaster_bpm = 1\n\n7.0.2 inherits 6.8.5\nmaster_bpm = 2\n"
+        ));
+        let bpm = |version: &str| {
+            let version = Version::parse(version).unwrap();
+            file.get(&version).map(|x| x.master_bpm.to_string())
+        };
+        assert_eq!(bpm("7.0.0").as_deref(), Some("1"));
+        assert_eq!(bpm("7.0.3.4").as_deref(), Some("1"));
+        // An exact version wins over a range
+        assert_eq!(bpm("7.0.2").as_deref(), Some("2"));
+        assert_eq!(bpm("7.0.2.1").as_deref(), Some("2"));
+        assert_eq!(bpm("6.8.5.1").as_deref(), Some("544A460 28 180 0 140"));
+        assert_eq!(bpm("7.0.4"), None);
+        assert_eq!(bpm("6.8"), None);
+
+        assert_eq!(error("7.0.3..7.0.0\n").1, "Invalid version '7.0.3..7.0.0'");
+    }
+
+    #[test]
+    fn shipped_file() {
+        let file = parse(include_str!("../offsets"));
+        assert_eq!(file.versions()[0], "6.8.5");
+        let offsets = get(&file, "6.8.5");
+        assert_eq!(offsets.decks.len(), 2);
+        assert_eq!(offsets.decks[0].bar.to_string(), "443F630 120 1E18");
+    }
+
+    #[test]
+    fn pointers() {
+        let pointer = Pointer::from_string("  [48 8B 05 ?? ?? ?? ??]+3 0 40 BA0").unwrap();
+        assert_eq!(pointer.offsets, vec![0, 0x40]);
+        assert_eq!(pointer.final_offset, 0xBA0);
+        let mut rebased = pointer.clone();
+        rebased.rebase(0x52EA410);
+        assert_eq!(rebased.to_string(), "52EA410 40 BA0");
+
+        let mut direct = Pointer::from_string("[48 8B 05 ?? ?? ?? ??]+3 10").unwrap();
+        direct.rebase(0x1000);
+        assert_eq!(direct.to_string(), "1010");
+
+        assert!(Pointer::from_string("").is_err());
+        assert!(Pointer::from_string("12 XY").is_err());
+        assert!(Pointer::from_string("[48 8B 05 ?? ?? ?? ??+3 10").is_err());
+    }
 }
@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Loop length reported while we have no way of reading it from Rekordbox
+const DEFAULT_LOOP_BEATS: f32 = 8.;
+
+/// What VirtualDJ reports for a track without a SoundSwitch id
+pub const NO_SOUNDSWITCH_ID: &str = "{00000000-0000-0000-0000-000000000000}";
+
+/// Paths as VirtualDJ has them, Rekordbox gives them with /
+pub fn windows_path(path: &str) -> String {
+    path.replace('/', "\\")
+}
+
+/// A message in the OS2L protocol, as sent between VirtualDJ and SoundSwitch.
+/// Each message is one JSON object on its own line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "evt", rename_all = "lowercase")]
+pub enum Os2lMessage {
+    /// Beat tick, `pos` counts beats since the start of the track
+    Beat {
+        change: bool,
+        pos: i32,
+        bpm: f32,
+        strength: i32,
+    },
+    /// Current value of a trigger SoundSwitch asked for, eg. "deck 1 get_bpm"
+    Subscribed { trigger: String, value: Value },
+    /// Button press
+    Btn {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page: Option<String>,
+        state: ButtonState,
+    },
+    /// Numbered command with a parameter
+    Cmd { id: i32, param: f64 },
+    /// Button state reported back
+    Feedback {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page: Option<String>,
+        state: ButtonState,
+    },
+    /// Ask to be sent the value of a trigger now and whenever it changes
+    Subscribe { trigger: String },
+    /// Stop sending a trigger
+    Unsubscribe { trigger: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ButtonState {
+    On,
+    Off,
+}
+
+impl Os2lMessage {
+    pub fn subscribed(trigger: impl Into<String>, value: impl Into<Value>) -> Self {
+        Os2lMessage::Subscribed {
+            trigger: trigger.into(),
+            value: value.into(),
+        }
+    }
+
+    pub fn beat(pos: i32, bpm: f32) -> Self {
+        Os2lMessage::Beat {
+            change: false,
+            pos,
+            bpm,
+            strength: 0,
+        }
+    }
+
+    /// Parse one line received from the socket
+    pub fn from_line(line: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(line.trim())
+    }
+
+    /// Serialise to a newline terminated line, ready to write to the socket
+    pub fn to_line(&self) -> String {
+        // Serialising plain structs of strings and numbers can not fail
+        let mut line = serde_json::to_string(self).unwrap_or_default();
+        line.push('\n');
+        line
+    }
+}
+
+/// What SoundSwitch can ask about one deck
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeckState {
+    /// `None` while we do not know the tempo of the track loaded
+    pub bpm: Option<f32>,
+    pub playing: bool,
+    pub looping: bool,
+    pub loop_beats: f32,
+    pub filepath: String,
+    /// Elapsed time in milliseconds
+    pub time: i32,
+    pub beatpos: i32,
+}
+
+impl Default for DeckState {
+    fn default() -> Self {
+        Self {
+            bpm: None,
+            playing: false,
+            looping: false,
+            loop_beats: DEFAULT_LOOP_BEATS,
+            filepath: "".to_string(),
+            time: 0,
+            beatpos: 0,
+        }
+    }
+}
+
+/// Mixer state as seen over OS2L, used to answer SoundSwitch's queries
+#[derive(Debug, Clone, PartialEq)]
+pub struct Os2lState {
+    /// OS2L decks 1 to 4
+    pub decks: [DeckState; 4],
+    /// OS2L number of the master deck, starting at 1
+    pub master_deck: usize,
+    pub crossfader: f32,
+}
+
+impl Default for Os2lState {
+    fn default() -> Self {
+        Self {
+            decks: Default::default(),
+            master_deck: 1,
+            crossfader: 0.5,
+        }
+    }
+}
+
+impl Os2lState {
+    /// Current value of a trigger like "deck 2 get_bpm", or `None` if we do not know the trigger.
+    /// A value we do not know yet is null, and is not sent.
+    pub fn value(&self, trigger: &str) -> Option<Value> {
+        if trigger == "crossfader" {
+            return Some(self.crossfader.into());
+        }
+
+        let rest = trigger.strip_prefix("deck ")?;
+        let (deck, query) = rest.split_once(' ')?;
+        let deck = self.decks.get(deck.parse::<usize>().ok()?.checked_sub(1)?)?;
+        let on_off = |x: bool| if x { "on" } else { "off" };
+
+        let value: Value = match query {
+            "get_bpm" => deck.bpm.into(),
+            "play" => on_off(deck.playing).into(),
+            "loop" => on_off(deck.looping).into(),
+            "get_loop" => deck.loop_beats.into(),
+            "get_filepath" => windows_path(&deck.filepath).into(),
+            "get_beatpos" => deck.beatpos.into(),
+            "level" => 1.into(),
+            // Ids are stored in VirtualDJ's own database, Rekordbox tracks never have one
+            "get_text '%SOUNDSWITCH_ID'" => NO_SOUNDSWITCH_ID.into(),
+            x if x.starts_with("get_time") => deck.time.into(),
+            // Loop rolls are not read from Rekordbox
+            x if x.starts_with("loop_roll") => 0.into(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn serialises_like_virtualdj() {
+        assert_eq!(
+            Os2lMessage::beat(17, 128.).to_line(),
+            "{\"evt\":\"beat\",\"change\":false,\"pos\":17,\"bpm\":128.0,\"strength\":0}\n"
+        );
+        assert_eq!(
+            Os2lMessage::subscribed("deck 1 get_beatpos", 3).to_line(),
+            "{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_beatpos\",\"value\":3}\n"
+        );
+        // No page is left out instead of sent as null
+        let button = Os2lMessage::Btn {
+            name: "strobe".to_string(),
+            page: None,
+            state: ButtonState::On,
+        };
+        assert_eq!(button.to_line(), "{\"evt\":\"btn\",\"name\":\"strobe\",\"state\":\"on\"}\n");
+    }
+
+    #[test]
+    fn parses_what_soundswitch_sends() {
+        assert_eq!(
+            Os2lMessage::from_line("{\"evt\":\"subscribe\",\"trigger\":\"deck 2 get_bpm\"}\r\n").unwrap(),
+            Os2lMessage::Subscribe {
+                trigger: "deck 2 get_bpm".to_string()
+            }
+        );
+        assert_eq!(
+            Os2lMessage::from_line("{\"evt\":\"feedback\",\"name\":\"fog\",\"page\":\"fx\",\"state\":\"off\"}")
+                .unwrap(),
+            Os2lMessage::Feedback {
+                name: "fog".to_string(),
+                page: Some("fx".to_string()),
+                state: ButtonState::Off,
+            }
+        );
+        assert_eq!(
+            Os2lMessage::from_line("{\"evt\":\"cmd\",\"id\":4,\"param\":0.5}").unwrap(),
+            Os2lMessage::Cmd { id: 4, param: 0.5 }
+        );
+        assert!(Os2lMessage::from_line("{\"evt\":\"dance\"}").is_err());
+        assert!(Os2lMessage::from_line("{\"evt\":\"cmd\",\"id\":4}").is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let messages = [
+            Os2lMessage::beat(-1, 174.5),
+            Os2lMessage::subscribed("crossfader", 0.25),
+            Os2lMessage::subscribed("deck 3 get_filepath", "C:\\Music\\a.mp3"),
+            Os2lMessage::Unsubscribe {
+                trigger: "deck 1 play".to_string(),
+            },
+        ];
+        for message in messages {
+            assert_eq!(Os2lMessage::from_line(&message.to_line()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn answers_triggers() {
+        let mut state = Os2lState::default();
+        state.decks[1].bpm = Some(128.);
+        state.decks[1].playing = true;
+        state.decks[1].filepath = "/Music/a.mp3".to_string();
+        state.decks[1].beatpos = 33;
+        state.decks[1].time = 12_500;
+
+        assert_eq!(state.value("deck 2 get_bpm"), Some(json!(128.)));
+        assert_eq!(state.value("deck 1 get_bpm"), Some(Value::Null));
+        assert_eq!(state.value("deck 2 play"), Some(json!("on")));
+        assert_eq!(state.value("deck 1 play"), Some(json!("off")));
+        assert_eq!(state.value("deck 2 get_filepath"), Some(json!("\\Music\\a.mp3")));
+        assert_eq!(state.value("deck 2 get_beatpos"), Some(json!(33)));
+        assert_eq!(state.value("deck 2 get_time elapsed absolute"), Some(json!(12_500)));
+        assert_eq!(state.value("deck 4 get_loop"), Some(json!(DEFAULT_LOOP_BEATS)));
+        assert_eq!(state.value("crossfader"), Some(json!(0.5)));
+        assert_eq!(state.value("deck 5 get_bpm"), None);
+        assert_eq!(state.value("deck 0 get_bpm"), None);
+        assert_eq!(state.value("deck 1 get_key"), None);
+    }
+}
@@ -0,0 +1,314 @@
+use std::{fmt, fs, path::Path};
+
+use crate::memory::MemorySource;
+
+// Just enough of the PE format to find the version resource and the code sections:
+//
+//   DOS header      e_lfanew at 0x3C points to the NT headers
+//   NT headers      "PE\0\0", COFF header, optional header with the data directories
+//   Section table   maps RVAs to file offsets, and says which sections hold code
+//   Resources       type / name / language directories, RT_VERSION leads to VS_VERSIONINFO
+
+const RT_VERSION: u32 = 16;
+const RESOURCE_DIRECTORY: usize = 2;
+const FIXED_FILE_INFO_SIGNATURE: u32 = 0xFEEF04BD;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+
+/// Version resources are small, anything bigger is not one
+const MAX_VERSION_INFO_SIZE: usize = 64 * 1024;
+
+/// Fixed file version from the version resource, eg. 6.8.5.1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileVersion(pub [u16; 4]);
+
+impl fmt::Display for FileVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+struct Section {
+    virtual_address: usize,
+    virtual_size: usize,
+    raw_offset: usize,
+    raw_size: usize,
+    characteristics: u32,
+}
+
+enum Image<'a> {
+    /// Mapped by the loader, RVAs are offsets from the module base
+    Memory {
+        source: &'a dyn MemorySource,
+        base: usize,
+    },
+    /// The file on disk, RVAs go through the section table
+    File { data: Vec<u8>, sections: Vec<Section> },
+}
+
+/// A PE image, mapped into a process or read from disk.
+pub struct PeImage<'a> {
+    image: Image<'a>,
+    optional_header: usize,
+    pe32_plus: bool,
+}
+
+impl<'a> PeImage<'a> {
+    /// The image of a module loaded at `base`
+    pub fn from_memory(source: &'a dyn MemorySource, base: usize) -> Option<Self> {
+        PeImage::new(Image::Memory { source, base })
+    }
+
+    pub fn from_file(path: &Path) -> Option<PeImage<'static>> {
+        let data = fs::read(path).ok()?;
+        let mut pe = PeImage::new(Image::File { data, sections: vec![] })?;
+        let sections = pe.sections()?;
+        if let Image::File { sections: x, .. } = &mut pe.image {
+            *x = sections;
+        }
+        Some(pe)
+    }
+
+    fn new(image: Image<'a>) -> Option<PeImage<'a>> {
+        let mut pe = PeImage {
+            image,
+            optional_header: 0,
+            pe32_plus: false,
+        };
+        if pe.read(0, 2)? != b"MZ" {
+            return None;
+        }
+        let nt_headers = pe.u32(0x3C)? as usize;
+        if pe.read(nt_headers, 4)? != b"PE\0\0" {
+            return None;
+        }
+        pe.optional_header = nt_headers + 24;
+        pe.pe32_plus = match pe.u16(pe.optional_header)? {
+            0x10B => false,
+            0x20B => true,
+            _ => return None,
+        };
+        Some(pe)
+    }
+
+    /// Size of the module once mapped, from the optional header
+    pub fn size_of_image(&self) -> Option<usize> {
+        Some(self.u32(self.optional_header + 56)? as usize)
+    }
+
+    pub fn file_version(&self) -> Option<FileVersion> {
+        let (rva, size) = self.version_resource()?;
+        let data = self.read(rva, size.min(MAX_VERSION_INFO_SIZE))?;
+
+        // VS_FIXEDFILEINFO follows the variable length "VS_VERSION_INFO" key, find it by its
+        // signature instead of working out the padding
+        let fixed = data
+            .windows(4)
+            .position(|x| x == FIXED_FILE_INFO_SIGNATURE.to_le_bytes())?;
+        let ms = u32::from_le_bytes(data.get(fixed + 8..fixed + 12)?.try_into().ok()?);
+        let ls = u32::from_le_bytes(data.get(fixed + 12..fixed + 16)?.try_into().ok()?);
+        Some(FileVersion([
+            (ms >> 16) as u16,
+            ms as u16,
+            (ls >> 16) as u16,
+            ls as u16,
+        ]))
+    }
+
+    /// RVA and size of the first RT_VERSION resource
+    fn version_resource(&self) -> Option<(usize, usize)> {
+        let directories = self.optional_header + if self.pe32_plus { 112 } else { 96 };
+        let resources = self.u32(directories + RESOURCE_DIRECTORY * 8)? as usize;
+        if resources == 0 {
+            return None;
+        }
+
+        // Type, then the first name and the first language under it
+        let mut entry = self.resource_entry(resources, resources, Some(RT_VERSION))?;
+        for _ in 0..2 {
+            entry = self.resource_entry(resources, entry, None)?;
+        }
+
+        // Leaf: IMAGE_RESOURCE_DATA_ENTRY
+        let rva = self.u32(entry)? as usize;
+        let size = self.u32(entry + 4)? as usize;
+        Some((rva, size))
+    }
+
+    /// Follow an entry of the resource directory at `directory`, the one with `id` or the
+    /// first one. Returns the RVA of what it points to.
+    fn resource_entry(&self, resources: usize, directory: usize, id: Option<u32>) -> Option<usize> {
+        let named = self.u16(directory + 12)? as usize;
+        let ids = self.u16(directory + 14)? as usize;
+        let entries = directory + 16;
+        let index = match id {
+            // Named entries come first, then the ones with numeric ids
+            Some(id) => (named..named + ids).find(|i| self.u32(entries + i * 8) == Some(id))?,
+            None if named + ids > 0 => 0,
+            None => return None,
+        };
+        let offset = self.u32(entries + index * 8 + 4)?;
+        Some(resources + (offset & 0x7FFFFFFF) as usize)
+    }
+
+    /// RVA and size of every section with code in it
+    pub fn executable_sections(&self) -> Option<Vec<(usize, usize)>> {
+        let sections = self.sections()?;
+        Some(
+            sections
+                .iter()
+                .filter(|x| x.characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+                .map(|x| (x.virtual_address, x.virtual_size))
+                .collect(),
+        )
+    }
+
+    fn sections(&self) -> Option<Vec<Section>> {
+        let file_header = self.optional_header - 20;
+        let count = self.u16(file_header + 2)? as usize;
+        let optional_size = self.u16(file_header + 16)? as usize;
+        let table = self.optional_header + optional_size;
+        (0..count)
+            .map(|i| {
+                let section = table + i * 40;
+                Some(Section {
+                    virtual_size: self.u32(section + 8)? as usize,
+                    virtual_address: self.u32(section + 12)? as usize,
+                    raw_size: self.u32(section + 16)? as usize,
+                    raw_offset: self.u32(section + 20)? as usize,
+                    characteristics: self.u32(section + 36)?,
+                })
+            })
+            .collect()
+    }
+
+    fn read(&self, rva: usize, len: usize) -> Option<Vec<u8>> {
+        match &self.image {
+            Image::Memory { source, base } => source.read_bytes(base + rva, len),
+            Image::File { data, sections } => {
+                // Headers are not in a section and sit at the same offset in the file
+                let offset = match sections.iter().find(|x| {
+                    rva >= x.virtual_address && rva < x.virtual_address + x.virtual_size.max(x.raw_size)
+                }) {
+                    Some(section) => section.raw_offset + (rva - section.virtual_address),
+                    None => rva,
+                };
+                data.get(offset..offset + len).map(|x| x.to_vec())
+            }
+        }
+    }
+
+    fn u16(&self, rva: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(self.read(rva, 2)?.try_into().ok()?))
+    }
+
+    fn u32(&self, rva: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.read(rva, 4)?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    const BASE: usize = 0x140000000;
+    /// Where `image` puts the code, and the resources after it
+    pub const CODE_RVA: usize = 0x1000;
+    const RESOURCES_RVA: usize = 0x3000;
+    const SIZE: usize = 0x4000;
+
+    fn put(image: &mut [u8], at: usize, bytes: &[u8]) {
+        image[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn put_u32(image: &mut [u8], at: usize, value: u32) {
+        put(image, at, &value.to_le_bytes());
+    }
+
+    /// A 64 bit image with `code` in an executable section at `CODE_RVA`, and a version resource
+    /// if given. Sections sit at the same offset in the file as once mapped, so it works as both.
+    pub fn image(code: &[u8], version: Option<[u16; 4]>) -> Vec<u8> {
+        let mut image = vec![0u8; SIZE];
+        put(&mut image, 0, b"MZ");
+        put_u32(&mut image, 0x3C, 0x80);
+        put(&mut image, 0x80, b"PE\0\0");
+        // COFF header: two sections, optional header of 0xF0 bytes
+        put(&mut image, 0x84 + 2, &2u16.to_le_bytes());
+        put(&mut image, 0x84 + 16, &0xF0u16.to_le_bytes());
+        let optional = 0x98;
+        put(&mut image, optional, &0x20Bu16.to_le_bytes());
+        put_u32(&mut image, optional + 56, SIZE as u32);
+
+        let sections = optional + 0xF0;
+        let section = |image: &mut Vec<u8>, i: usize, name: &[u8], rva: usize, size: usize, flags: u32| {
+            let at = sections + i * 40;
+            put(image, at, name);
+            for (field, value) in [(8, size), (12, rva), (16, size), (20, rva)] {
+                put_u32(image, at + field, value as u32);
+            }
+            put_u32(image, at + 36, flags);
+        };
+        section(&mut image, 0, b".text", CODE_RVA, code.len(), IMAGE_SCN_MEM_EXECUTE);
+        put(&mut image, CODE_RVA, code);
+        section(&mut image, 1, b".rsrc", RESOURCES_RVA, 0x100, 0);
+
+        if let Some([a, b, c, d]) = version {
+            put_u32(&mut image, optional + 112 + RESOURCE_DIRECTORY * 8, RESOURCES_RVA as u32);
+            // Type, name and language directories with one entry each, then the data entry
+            let resources = RESOURCES_RVA;
+            for (level, id) in [RT_VERSION, 1, 0x409].into_iter().enumerate() {
+                let directory = resources + level * 0x18;
+                put(&mut image, directory + 14, &1u16.to_le_bytes());
+                put_u32(&mut image, directory + 16, id);
+                let next = (level + 1) as u32 * 0x18;
+                put_u32(&mut image, directory + 20, if level < 2 { next | 0x80000000 } else { next });
+            }
+            put_u32(&mut image, resources + 0x48, (resources + 0x60) as u32);
+            put_u32(&mut image, resources + 0x4C, 0x40);
+            // VS_VERSIONINFO, its key left out
+            let fixed = resources + 0x70;
+            put_u32(&mut image, fixed, FIXED_FILE_INFO_SIGNATURE);
+            put_u32(&mut image, fixed + 8, (a as u32) << 16 | b as u32);
+            put_u32(&mut image, fixed + 12, (c as u32) << 16 | d as u32);
+        }
+        image
+    }
+
+    fn mapped(image: &[u8]) -> FakeMemory {
+        let mut memory = FakeMemory::new();
+        memory.add_module("rekordbox.exe", BASE, image.len());
+        memory.write_bytes(BASE, image);
+        memory
+    }
+
+    #[test]
+    fn version_from_memory() {
+        let memory = mapped(&image(&[0xC3], Some([6, 8, 5, 1])));
+        let pe = PeImage::from_memory(&memory, BASE).unwrap();
+        assert_eq!(pe.file_version(), Some(FileVersion([6, 8, 5, 1])));
+        assert_eq!(pe.file_version().unwrap().to_string(), "6.8.5.1");
+        assert_eq!(pe.size_of_image(), Some(SIZE));
+        assert_eq!(pe.executable_sections(), Some(vec![(CODE_RVA, 1)]));
+    }
+
+    #[test]
+    fn version_from_file() {
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_pe_{}.exe", std::process::id()));
+        fs::write(&path, image(&[0xC3], Some([7, 0, 1, 12]))).unwrap();
+        let version = PeImage::from_file(&path).and_then(|x| x.file_version());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(version, Some(FileVersion([7, 0, 1, 12])));
+    }
+
+    #[test]
+    fn not_an_image() {
+        let memory = mapped(&image(&[0xC3], None));
+        assert_eq!(PeImage::from_memory(&memory, BASE).unwrap().file_version(), None);
+
+        let mut broken = image(&[0xC3], None);
+        put(&mut broken, 0x80, b"NE\0\0");
+        assert!(PeImage::from_memory(&mapped(&broken), BASE).is_none());
+        assert!(PeImage::from_memory(&memory, BASE + 0x10).is_none());
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{memory::MemorySource, signature::Signature};
+
+// Session files are plain text, one line per memory read that returned something new:
+//
+//   version 6.8.5                      Rekordbox version the session was recorded with
+//   module rekordbox.exe 7FF6A0000000  Module base
+//   signature rekordbox.exe 52EA410 [48 8B 05 ?? ?? ?? ??]+3
+//                                      RVA a signature was found at, replays can not scan
+//   1234567 7FF6A52EA410 4 00000000    Microseconds since start, address, length, bytes
+//   1234590 1E4C3A58 4 -               Failed read
+
+/// Every value read from one address, as (microseconds since start, bytes).
+type ReadHistory = Vec<(u64, Option<Vec<u8>>)>;
+type LastReads = HashMap<(usize, usize), Option<Vec<u8>>>;
+
+/// Time since any fixed point, only differences are used. Tests step it by hand.
+pub type Clock = Box<dyn Fn() -> Duration>;
+
+fn wall_clock() -> Clock {
+    let start = Instant::now();
+    Box::new(move || start.elapsed())
+}
+
+/// Wraps another memory source and writes every read to a session file.
+pub struct RecordingSource {
+    inner: Box<dyn MemorySource>,
+    out: RefCell<BufWriter<File>>,
+    clock: Clock,
+    /// Set by the first read
+    start: OnceCell<Duration>,
+    last: RefCell<LastReads>,
+}
+
+impl RecordingSource {
+    pub fn create(
+        path: &str,
+        inner: Box<dyn MemorySource>,
+        rbversion: &str,
+        module_name: &str,
+    ) -> std::io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "version {rbversion}")?;
+        if let Some(base) = inner.module_base(module_name) {
+            writeln!(out, "module {module_name} {base:X}")?;
+        }
+        out.flush()?;
+
+        Ok(Self {
+            inner,
+            out: RefCell::new(out),
+            clock: wall_clock(),
+            start: OnceCell::new(),
+            last: RefCell::new(HashMap::new()),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl MemorySource for RecordingSource {
+    fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+        let result = self.inner.read_bytes(address, len);
+
+        // Only write values that changed, a session would otherwise grow by ~100MB per hour
+        let mut last = self.last.borrow_mut();
+        if last.get(&(address, len)) != Some(&result) {
+            let bytes = match &result {
+                Some(bytes) => bytes.iter().map(|x| format!("{x:02X}")).collect::<String>(),
+                None => "-".to_string(),
+            };
+            let now = (self.clock)();
+            let micros = (now - *self.start.get_or_init(|| now)).as_micros();
+            let mut out = self.out.borrow_mut();
+            // A full disk should not take the show down with it
+            let _ = writeln!(out, "{micros} {address:X} {len} {bytes}");
+            let _ = out.flush();
+            last.insert((address, len), result.clone());
+        }
+
+        result
+    }
+
+    fn module_base(&self, name: &str) -> Option<usize> {
+        self.inner.module_base(name)
+    }
+
+    fn module_path(&self, name: &str) -> Option<PathBuf> {
+        self.inner.module_path(name)
+    }
+
+    /// The scan reads straight from the inner source, only what it found goes in the session
+    fn find_signatures(&self, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>> {
+        let found = self.inner.find_signatures(module, signatures);
+        let mut out = self.out.borrow_mut();
+        for (signature, rva) in signatures.iter().zip(&found) {
+            if let Ok(rva) = rva {
+                let _ = writeln!(out, "signature {module} {rva:X} {signature}");
+            }
+        }
+        let _ = out.flush();
+        found
+    }
+}
+
+/// Plays back a session file recorded by [`RecordingSource`], at original speed or faster.
+pub struct ReplaySource {
+    reads: HashMap<(usize, usize), ReadHistory>,
+    modules: HashMap<String, usize>,
+    /// RVAs by module and signature
+    signatures: HashMap<(String, String), usize>,
+    pub rbversion: Option<String>,
+    pub duration_micros: u64,
+    clock: Clock,
+    /// Set by the first read, so finding SoundSwitch and attaching do not eat into the session
+    start: OnceCell<Duration>,
+    speed: f64,
+}
+
+impl ReplaySource {
+    pub fn open(path: &str, speed: f64) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("{path}: {e}"))?;
+
+        let mut reads: HashMap<(usize, usize), ReadHistory> = HashMap::new();
+        let mut modules = HashMap::new();
+        let mut signatures = HashMap::new();
+        let mut rbversion = None;
+        let mut duration_micros = 0;
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("{path}: {e}"))?;
+            let fields: Vec<&str> = line.split(' ').collect();
+            let parse_error = || format!("{path}:{}: invalid line '{line}'", i + 1);
+
+            match fields.as_slice() {
+                ["version", version] => rbversion = Some(version.to_string()),
+                ["module", name, base] => {
+                    let base = usize::from_str_radix(base, 16).map_err(|_| parse_error())?;
+                    modules.insert(name.to_string(), base);
+                }
+                ["signature", module, rva, signature @ ..] => {
+                    let rva = usize::from_str_radix(rva, 16).map_err(|_| parse_error())?;
+                    signatures.insert((module.to_string(), signature.join(" ")), rva);
+                }
+                [micros, address, len, bytes] => {
+                    let micros = micros.parse::<u64>().map_err(|_| parse_error())?;
+                    let address = usize::from_str_radix(address, 16).map_err(|_| parse_error())?;
+                    let len = len.parse::<usize>().map_err(|_| parse_error())?;
+                    let bytes = if *bytes == "-" {
+                        None
+                    } else {
+                        Some(parse_hex_bytes(bytes).ok_or_else(parse_error)?)
+                    };
+                    reads.entry((address, len)).or_default().push((micros, bytes));
+                    duration_micros = duration_micros.max(micros);
+                }
+                [""] => (),
+                _ => return Err(parse_error()),
+            }
+        }
+
+        Ok(Self {
+            reads,
+            modules,
+            signatures,
+            rbversion,
+            duration_micros,
+            clock: wall_clock(),
+            start: OnceCell::new(),
+            speed,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn now_micros(&self) -> u64 {
+        let now = (self.clock)();
+        ((now - *self.start.get_or_init(|| now)).as_micros() as f64 * self.speed) as u64
+    }
+}
+
+impl MemorySource for ReplaySource {
+    fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+        let history = self.reads.get(&(address, len))?;
+        let now = self.now_micros();
+        // Latest value read at or before now, or the first one if the recording has not got there yet
+        let index = history
+            .partition_point(|(micros, _)| *micros <= now)
+            .saturating_sub(1);
+        history[index].1.clone()
+    }
+
+    fn module_base(&self, name: &str) -> Option<usize> {
+        self.modules.get(name).copied()
+    }
+
+    fn find_signatures(&self, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>> {
+        signatures
+            .iter()
+            .map(|signature| {
+                self.signatures
+                    .get(&(module.to_string(), signature.to_string()))
+                    .copied()
+                    .ok_or_else(|| "not found in the session".to_string())
+            })
+            .collect()
+    }
+}
+
+fn parse_hex_bytes(input: &str) -> Option<Vec<u8>> {
+    let pairs = input.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, thread::sleep, time::Duration};
+
+    use super::*;
+    use crate::memory::FakeMemory;
+
+    const BASE: usize = 0x140000000;
+
+    /// Lets the test change memory the recording reads through
+    struct Shared(Rc<RefCell<FakeMemory>>);
+
+    impl MemorySource for Shared {
+        fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+            self.0.borrow().read_bytes(address, len)
+        }
+
+        fn module_base(&self, name: &str) -> Option<usize> {
+            self.0.borrow().module_base(name)
+        }
+
+        fn find_signatures(&self, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>> {
+            self.0.borrow().find_signatures(module, signatures)
+        }
+    }
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_replay_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let memory = Rc::new(RefCell::new(FakeMemory::new()));
+        memory.borrow_mut().add_module("rekordbox.exe", BASE, 0x100);
+
+        let recording =
+            RecordingSource::create(path, Box::new(Shared(memory.clone())), "6.8.5", "rekordbox.exe").unwrap();
+        // Starting late should not shift the recording
+        sleep(Duration::from_millis(20));
+        let mut recorded = vec![];
+        for value in [1u32, 1, 2, 3] {
+            memory.borrow_mut().write(BASE + 0x10, value);
+            recorded.push(recording.read_bytes(BASE + 0x10, 4));
+            sleep(Duration::from_millis(5));
+        }
+        recorded.push(recording.read_bytes(BASE + 0x200, 4));
+        drop(recording);
+
+        let replay = ReplaySource::open(path, 1.).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(replay.rbversion.as_deref(), Some("6.8.5"));
+        assert_eq!(replay.module_base("rekordbox.exe"), Some(BASE));
+
+        // The repeated read is left out, the rest come back in order
+        let history = &replay.reads[&(BASE + 0x10, 4)];
+        let values: Vec<_> = history.iter().map(|(_, bytes)| bytes.clone()).collect();
+        let expected: Vec<_> = [1u32, 2, 3].map(|x| Some(x.to_le_bytes().to_vec())).into();
+        assert_eq!(values, expected);
+        assert!(history.windows(2).all(|x| x[0].0 < x[1].0));
+        assert!(history[0].0 < 5_000, "recording started at {}us", history[0].0);
+        assert_eq!(replay.reads[&(BASE + 0x200, 4)][0].1, None);
+
+        // Replay starts with the first read, however long after opening that is
+        sleep(Duration::from_millis(20));
+        assert_eq!(replay.read_bytes(BASE + 0x10, 4), recorded[0]);
+        sleep(Duration::from_micros(replay.duration_micros));
+        assert_eq!(replay.read_bytes(BASE + 0x10, 4), recorded[3]);
+        assert_eq!(replay.read_bytes(BASE + 0x200, 4), None);
+        assert_eq!(replay.read_bytes(BASE + 0x20, 4), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt;
+
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+
+// SoundSwitch finds autoscripts by the path VirtualDJ had for the track, so when the library is
+// somewhere else on that machine the path Rekordbox gives has to be changed to match. Rules go
+// in the config file and apply in order, each to what the one before made of the path:
+//
+//   rewrite = prefix D:/Music => //nas/music      a folder that moved, ignoring case
+//   rewrite = drive D => E                        another drive letter
+//   rewrite = regex ^(.*)\.m4a$ => $1.mp3         anything else, replacing the first match
+//   normalize = nfc                               Unicode form of the result, nfc or nfd
+//
+// Paths are written with / like Rekordbox does, \ in rules is read as / as well.
+
+/// One way of changing a path
+pub enum Rule {
+    Prefix { from: String, to: String },
+    Drive { from: char, to: char },
+    Regex { regex: Regex, to: String },
+}
+
+impl Rule {
+    /// `prefix from => to`, `drive D => E` or `regex pattern => replacement`
+    pub fn parse(input: &str) -> Result<Rule, String> {
+        let (kind, rest) = input.trim().split_once(' ').unwrap_or((input.trim(), ""));
+        let (from, to) = rest
+            .split_once("=>")
+            .map(|(from, to)| (from.trim(), to.trim()))
+            .ok_or_else(|| format!("Expected '{kind} from => to', got '{input}'"))?;
+        match kind {
+            "prefix" if !from.is_empty() => Ok(Rule::Prefix {
+                from: from.replace('\\', "/"),
+                to: to.replace('\\', "/"),
+            }),
+            "drive" => {
+                let letter = |x: &str| {
+                    let mut chars = x.trim_end_matches(':').chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(letter), None) if letter.is_ascii_alphabetic() => {
+                            Ok(letter.to_ascii_uppercase())
+                        }
+                        _ => Err(format!("'{x}' is not a drive letter")),
+                    }
+                };
+                Ok(Rule::Drive {
+                    from: letter(from)?,
+                    to: letter(to)?,
+                })
+            }
+            "regex" => Ok(Rule::Regex {
+                regex: Regex::new(from).map_err(|e| e.to_string())?,
+                to: to.to_string(),
+            }),
+            "prefix" => Err("A prefix rule needs something to replace".to_string()),
+            _ => Err(format!("Unknown rule '{kind}', expected prefix, drive or regex")),
+        }
+    }
+
+    pub fn apply(&self, path: &str) -> String {
+        match self {
+            // Only whole folders, D:/Music is not a prefix of D:/Musicals
+            Rule::Prefix { from, to } => match (path.get(..from.len()), path.get(from.len()..)) {
+                (Some(start), Some(rest))
+                    if start.eq_ignore_ascii_case(from)
+                        && (from.ends_with('/') || rest.is_empty() || rest.starts_with('/')) =>
+                {
+                    format!("{to}{rest}")
+                }
+                _ => path.to_string(),
+            },
+            Rule::Drive { from, to } => match path.split_once(':') {
+                Some((letter, rest)) if letter.eq_ignore_ascii_case(&from.to_string()) => {
+                    format!("{to}:{rest}")
+                }
+                _ => path.to_string(),
+            },
+            Rule::Regex { regex, to } => regex.replace(path, to.as_str()).to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Prefix { from, to } => write!(f, "prefix {from} => {to}"),
+            Rule::Drive { from, to } => write!(f, "drive {from} => {to}"),
+            Rule::Regex { regex, to } => write!(f, "regex {regex} => {to}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+impl Normalization {
+    pub fn parse(input: &str) -> Option<Normalization> {
+        match input.to_lowercase().as_str() {
+            "nfc" => Some(Normalization::Nfc),
+            "nfd" => Some(Normalization::Nfd),
+            _ => None,
+        }
+    }
+}
+
+/// All rules of the config, none by default
+#[derive(Default)]
+pub struct PathRewrite {
+    pub rules: Vec<Rule>,
+    pub normalization: Option<Normalization>,
+}
+
+impl PathRewrite {
+    pub fn apply(&self, path: &str) -> String {
+        let path = self.rules.iter().fold(path.to_string(), |path, rule| rule.apply(&path));
+        self.normalize(&path)
+    }
+
+    pub fn normalize(&self, path: &str) -> String {
+        match self.normalization {
+            Some(Normalization::Nfc) => path.nfc().collect(),
+            Some(Normalization::Nfd) => path.nfd().collect(),
+            None => path.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(rule: &str, path: &str) -> String {
+        Rule::parse(rule).unwrap().apply(path)
+    }
+
+    #[test]
+    fn prefix() {
+        let rule = "prefix D:\\Music => //nas/music";
+        assert_eq!(apply(rule, "d:/music/a.mp3"), "//nas/music/a.mp3");
+        assert_eq!(apply(rule, "D:/Music"), "//nas/music");
+        assert_eq!(apply(rule, "D:/Musicals/a.mp3"), "D:/Musicals/a.mp3");
+        assert_eq!(apply(rule, "E:/Music/a.mp3"), "E:/Music/a.mp3");
+        assert_eq!(apply(rule, "D:/"), "D:/");
+        assert_eq!(apply("prefix D:/ => E:/", "D:/Music/a.mp3"), "E:/Music/a.mp3");
+    }
+
+    #[test]
+    fn prefix_non_ascii() {
+        // The rule ends inside the ü, two bytes in UTF-8
+        assert_eq!(apply("prefix D:/M\u{e4} => X:", "D:/Müsic/a.mp3"), "D:/Müsic/a.mp3");
+        assert_eq!(apply("prefix D:/Mu => X:", "D:/Müsic/a.mp3"), "D:/Müsic/a.mp3");
+        // Only ASCII ignores case
+        assert_eq!(apply("prefix D:/Müsic => X:", "d:/müsic/a.mp3"), "X:/a.mp3");
+        assert_eq!(apply("prefix D:/Müsic => X:", "D:/MÜsic/a.mp3"), "D:/MÜsic/a.mp3");
+    }
+
+    #[test]
+    fn drive() {
+        let rule = "drive d: => e";
+        assert_eq!(apply(rule, "D:/Music/a.mp3"), "E:/Music/a.mp3");
+        assert_eq!(apply(rule, "d:/Music/a.mp3"), "E:/Music/a.mp3");
+        assert_eq!(apply(rule, "C:/Music/a.mp3"), "C:/Music/a.mp3");
+        assert_eq!(apply(rule, "/Volumes/Music/a.mp3"), "/Volumes/Music/a.mp3");
+    }
+
+    #[test]
+    fn regex() {
+        let rule = r"regex ^(.*)\.m4a$ => $1.mp3";
+        assert_eq!(apply(rule, "D:/Music/a.m4a"), "D:/Music/a.mp3");
+        assert_eq!(apply(rule, "D:/Music/a.flac"), "D:/Music/a.flac");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |input: &str| Rule::parse(input).err().unwrap();
+        assert_eq!(error("prefix D:/Music"), "Expected 'prefix from => to', got 'prefix D:/Music'");
+        assert_eq!(error("prefix => E:/"), "A prefix rule needs something to replace");
+        assert_eq!(error("drive DE => F"), "'DE' is not a drive letter");
+        assert_eq!(error("drive 1 => F"), "'1' is not a drive letter");
+        assert!(Rule::parse("regex ( => x").is_err());
+        assert_eq!(error("move a => b"), "Unknown rule 'move', expected prefix, drive or regex");
+    }
+
+    #[test]
+    fn display() {
+        let rule = Rule::parse("prefix D:\\Music => E:\\Music").unwrap();
+        assert_eq!(rule.to_string(), "prefix D:/Music => E:/Music");
+        assert_eq!(Rule::parse("drive d => e").unwrap().to_string(), "drive D => E");
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let rewrite = PathRewrite {
+            rules: vec![
+                Rule::parse("drive D => E").unwrap(),
+                Rule::parse("prefix E:/Music => F:/Tracks").unwrap(),
+            ],
+            normalization: Some(Normalization::Nfc),
+        };
+        // A decomposed ü comes out composed
+        assert_eq!(rewrite.apply("D:/Music/Mu\u{308}sic.mp3"), "F:/Tracks/M\u{fc}sic.mp3");
+    }
+
+    #[test]
+    fn normalization() {
+        assert!(matches!(Normalization::parse("NFD"), Some(Normalization::Nfd)));
+        assert!(Normalization::parse("nfkc").is_none());
+        let nfd = PathRewrite {
+            rules: vec![],
+            normalization: Some(Normalization::Nfd),
+        };
+        assert_eq!(nfd.normalize("\u{fc}"), "u\u{308}");
+        assert_eq!(PathRewrite::default().normalize("\u{fc}"), "\u{fc}");
+    }
+}
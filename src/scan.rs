@@ -0,0 +1,416 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    memory::MemorySource,
+    offsets::{FieldType, Pointer},
+    pe::PeImage,
+};
+
+// Finding the offsets for a new Rekordbox version, the way the README describes doing it with
+// Cheat Engine:
+//
+//   1. Search writable memory for the value Rekordbox shows, eg. the master BPM
+//   2. Change it in Rekordbox and keep the candidates that follow, until only a few are left
+//   3. Search backwards from those for pointers, up to the module, which gives the offsets
+
+/// Regions are read in pieces of at most this size
+const CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Stop the first search beyond this many candidates, the value was too common
+const MAX_CANDIDATES: usize = 10_000_000;
+
+/// Rekordbox shows the BPM with two decimals
+const F32_TOLERANCE: f64 = 0.005;
+
+/// Which candidates to keep, by their value now and at the previous step
+pub enum Filter {
+    Exact(f64),
+    Range(f64, f64),
+    Increased,
+    Decreased,
+    Unchanged,
+    Changed,
+}
+
+impl Filter {
+    /// A number, an inclusive range like `61000..62000`, or one of `+ - = !`
+    pub fn parse(input: &str) -> Option<Filter> {
+        match input {
+            "+" => Some(Filter::Increased),
+            "-" => Some(Filter::Decreased),
+            "=" => Some(Filter::Unchanged),
+            "!" => Some(Filter::Changed),
+            _ => match input.split_once("..") {
+                Some((first, last)) => Some(Filter::Range(first.parse().ok()?, last.parse().ok()?)),
+                None => Some(Filter::Exact(input.parse().ok()?)),
+            },
+        }
+    }
+
+    fn keeps(&self, field_type: FieldType, previous: f64, value: f64) -> bool {
+        let tolerance = if field_type == FieldType::F32 {
+            F32_TOLERANCE
+        } else {
+            0.
+        };
+        match self {
+            Filter::Exact(x) => (value - x).abs() <= tolerance,
+            Filter::Range(first, last) => (*first..=*last).contains(&value),
+            Filter::Increased => value > previous,
+            Filter::Decreased => value < previous,
+            Filter::Unchanged => value == previous,
+            Filter::Changed => value != previous,
+        }
+    }
+}
+
+/// Searching memory for a value and narrowing the results down step by step
+pub struct ValueScan {
+    field_type: FieldType,
+    /// Address and the value it had at the last step, ordered by address
+    candidates: Vec<(usize, f64)>,
+    started: bool,
+}
+
+impl ValueScan {
+    pub fn new(field_type: FieldType) -> Result<Self, String> {
+        if field_type == FieldType::String {
+            return Err("Strings can not be scanned for".to_string());
+        }
+        Ok(Self {
+            field_type,
+            candidates: vec![],
+            started: false,
+        })
+    }
+
+    pub fn candidates(&self) -> &[(usize, f64)] {
+        &self.candidates
+    }
+
+    /// Search all writable memory the first time, then only keep the candidates that match
+    pub fn step(&mut self, source: &dyn MemorySource, filter: &Filter) -> Result<(), String> {
+        if self.started {
+            self.narrow(source, filter);
+            return Ok(());
+        }
+        if !matches!(filter, Filter::Exact(_) | Filter::Range(..)) {
+            return Err(
+                "Start with a number or a range, there is nothing to compare with yet".to_string(),
+            );
+        }
+        self.search(source, filter)?;
+        self.started = true;
+        Ok(())
+    }
+
+    fn search(&mut self, source: &dyn MemorySource, filter: &Filter) -> Result<(), String> {
+        let regions = source.writable_regions();
+        if regions.is_empty() {
+            return Err("Could not list the memory of Rekordbox".to_string());
+        }
+
+        let (width, align) = self.layout();
+        let mut candidates = vec![];
+        for chunk in chunks(&regions) {
+            let Some(data) = source.read_bytes(chunk.start, chunk.len()) else {
+                continue;
+            };
+            for offset in (0..data.len().saturating_sub(width - 1)).step_by(align) {
+                let value = self.decode(&data[offset..offset + width]);
+                if filter.keeps(self.field_type, value, value) {
+                    candidates.push((chunk.start + offset, value));
+                }
+            }
+            if candidates.len() > MAX_CANDIDATES {
+                return Err(format!(
+                    "More than {MAX_CANDIDATES} candidates, start with a less common value"
+                ));
+            }
+        }
+        self.candidates = candidates;
+        Ok(())
+    }
+
+    fn narrow(&mut self, source: &dyn MemorySource, filter: &Filter) {
+        let (width, _) = self.layout();
+        let mut kept = vec![];
+        // Read whole chunks around the candidates, a read for each of millions would take ages
+        for chunk in chunks(&source.writable_regions()) {
+            let first = self
+                .candidates
+                .partition_point(|(address, _)| *address < chunk.start);
+            let last = self
+                .candidates
+                .partition_point(|(address, _)| *address + width <= chunk.end);
+            if first >= last {
+                continue;
+            }
+            let Some(data) = source.read_bytes(chunk.start, chunk.len()) else {
+                continue;
+            };
+            for (address, previous) in &self.candidates[first..last] {
+                let offset = address - chunk.start;
+                let value = self.decode(&data[offset..offset + width]);
+                if filter.keeps(self.field_type, *previous, value) {
+                    kept.push((*address, value));
+                }
+            }
+        }
+        self.candidates = kept;
+    }
+
+    /// Size and alignment of the value, Rekordbox keeps 32 bit values aligned
+    fn layout(&self) -> (usize, usize) {
+        match self.field_type {
+            FieldType::U8 => (1, 1),
+            _ => (4, 4),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> f64 {
+        match self.field_type {
+            FieldType::F32 => f32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            FieldType::I32 => i32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            FieldType::U8 => bytes[0] as f64,
+            FieldType::String => f64::NAN,
+        }
+    }
+}
+
+/// The regions split into pieces of at most `CHUNK_SIZE`, in address order
+fn chunks(regions: &[(usize, usize)]) -> Vec<Range<usize>> {
+    let mut regions = regions.to_vec();
+    regions.sort();
+    regions
+        .into_iter()
+        .flat_map(|(start, size)| {
+            (start..start + size)
+                .step_by(CHUNK_SIZE)
+                .map(move |x| x..(x + CHUNK_SIZE).min(start + size))
+        })
+        .collect()
+}
+
+/// Limits for the pointer scan, the README suggests 5 or 6 levels
+pub struct PointerScan {
+    pub max_depth: usize,
+    pub max_offset: usize,
+    /// Stop after finding this many paths for a target
+    pub max_results: usize,
+}
+
+impl Default for PointerScan {
+    fn default() -> Self {
+        Self {
+            max_depth: 5,
+            max_offset: 0x1000,
+            max_results: 100,
+        }
+    }
+}
+
+impl PointerScan {
+    /// Pointer paths from `module` to any of the targets, shortest first
+    pub fn run(
+        &self,
+        source: &dyn MemorySource,
+        module: &str,
+        targets: &[usize],
+    ) -> Result<Vec<Pointer>, String> {
+        let base = source
+            .module_base(module)
+            .ok_or_else(|| format!("Could not find the {module} module"))?;
+        let size = PeImage::from_memory(source, base)
+            .and_then(|x| x.size_of_image())
+            .ok_or_else(|| format!("Could not read the size of {module}"))?;
+        let map = pointer_map(source);
+
+        let mut found = vec![];
+        for target in targets {
+            let mut walk = Walk {
+                scan: self,
+                map: &map,
+                module: base..base + size,
+                visited: HashMap::new(),
+                found: vec![],
+            };
+            walk.walk(*target, 0, &mut vec![]);
+            found.extend(walk.found);
+        }
+        found.sort_by_key(|x: &Pointer| x.offsets.len());
+        Ok(found)
+    }
+}
+
+/// Every 8 byte aligned value in writable memory that points into writable memory, as
+/// (value, address), ordered by value
+fn pointer_map(source: &dyn MemorySource) -> Vec<(usize, usize)> {
+    let mut regions = source.writable_regions();
+    regions.sort();
+    let points_into_memory = |value: usize| {
+        let index = regions.partition_point(|(start, _)| *start <= value);
+        index > 0 && value < regions[index - 1].0 + regions[index - 1].1
+    };
+
+    let mut map = vec![];
+    for chunk in chunks(&regions) {
+        let Some(data) = source.read_bytes(chunk.start, chunk.len()) else {
+            continue;
+        };
+        for (i, bytes) in data.chunks_exact(8).enumerate() {
+            let value = u64::from_le_bytes(bytes.try_into().unwrap()) as usize;
+            if points_into_memory(value) {
+                map.push((value, chunk.start + i * 8));
+            }
+        }
+    }
+    map.sort_unstable();
+    map
+}
+
+/// A depth first search from one target back to the module
+struct Walk<'a> {
+    scan: &'a PointerScan,
+    map: &'a [(usize, usize)],
+    module: Range<usize>,
+    /// Lowest depth each address was walked from, walking it again deeper can not find more
+    visited: HashMap<usize, usize>,
+    found: Vec<Pointer>,
+}
+
+impl Walk<'_> {
+    /// `offsets` are collected from the target backwards
+    fn walk(&mut self, address: usize, depth: usize, offsets: &mut Vec<usize>) {
+        if self.found.len() >= self.scan.max_results {
+            return;
+        }
+        if self.module.contains(&address) {
+            let rva = address - self.module.start;
+            let numbers: Vec<usize> = [rva]
+                .into_iter()
+                .chain(offsets.iter().rev().copied())
+                .collect();
+            let (final_offset, offsets) = numbers.split_last().unwrap();
+            self.found
+                .push(Pointer::new(offsets.to_vec(), *final_offset));
+            return;
+        }
+        if depth == self.scan.max_depth || self.visited.get(&address).is_some_and(|x| *x <= depth) {
+            return;
+        }
+        self.visited.insert(address, depth);
+
+        // Pointers to the start of the object first, small offsets are the likely ones
+        let first = self
+            .map
+            .partition_point(|(value, _)| *value < address.saturating_sub(self.scan.max_offset));
+        let last = self.map.partition_point(|(value, _)| *value <= address);
+        for i in (first..last).rev() {
+            let (value, pointer_address) = self.map[i];
+            offsets.push(address - value);
+            self.walk(pointer_address, depth + 1, offsets);
+            offsets.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::FakeMemory, pe::tests::image};
+
+    const BASE: usize = 0x140000000;
+    const HEAP: usize = 0x20000;
+    const DECK: usize = 0x80000;
+
+    #[test]
+    fn parses_filters() {
+        assert!(matches!(Filter::parse("128.5"), Some(Filter::Exact(x)) if x == 128.5));
+        let range = Filter::parse("61000..62000");
+        assert!(matches!(range, Some(Filter::Range(a, b)) if a == 61000. && b == 62000.));
+        assert!(matches!(Filter::parse("+"), Some(Filter::Increased)));
+        assert!(matches!(Filter::parse("-"), Some(Filter::Decreased)));
+        assert!(matches!(Filter::parse("="), Some(Filter::Unchanged)));
+        assert!(matches!(Filter::parse("!"), Some(Filter::Changed)));
+        assert!(Filter::parse("fast").is_none());
+        assert!(Filter::parse("1..").is_none());
+    }
+
+    #[test]
+    fn narrows_down_values() {
+        let mut memory = FakeMemory::new();
+        memory.add_region(HEAP, 0x100);
+        memory.write(HEAP, 128f32);
+        memory.write(HEAP + 0x10, 128.004f32);
+        memory.write(HEAP + 0x20, 128.1f32);
+        memory.write(HEAP + 0x30, 128f32);
+
+        let mut scan = ValueScan::new(FieldType::F32).unwrap();
+        assert!(scan.step(&memory, &Filter::Increased).is_err());
+        scan.step(&memory, &Filter::Exact(128.)).unwrap();
+        let addresses = |scan: &ValueScan| scan.candidates().iter().map(|x| x.0).collect::<Vec<_>>();
+        // Within the two decimals Rekordbox shows
+        assert_eq!(addresses(&scan), vec![HEAP, HEAP + 0x10, HEAP + 0x30]);
+
+        memory.write(HEAP, 130f32);
+        memory.write(HEAP + 0x10, 127f32);
+        scan.step(&memory, &Filter::Increased).unwrap();
+        assert_eq!(scan.candidates(), &[(HEAP, 130.)]);
+        scan.step(&memory, &Filter::Unchanged).unwrap();
+        assert_eq!(addresses(&scan), vec![HEAP]);
+        scan.step(&memory, &Filter::Changed).unwrap();
+        assert!(scan.candidates().is_empty());
+    }
+
+    #[test]
+    fn scans_integers_by_range() {
+        let mut memory = FakeMemory::new();
+        memory.add_region(HEAP, 0x100);
+        memory.write(HEAP + 0x8, 61_500i32);
+        memory.write(HEAP + 0x41, 3u8);
+
+        let mut scan = ValueScan::new(FieldType::I32).unwrap();
+        scan.step(&memory, &Filter::Range(61_000., 62_000.)).unwrap();
+        assert_eq!(scan.candidates(), &[(HEAP + 0x8, 61_500.)]);
+
+        // Bytes are not aligned
+        let mut scan = ValueScan::new(FieldType::U8).unwrap();
+        scan.step(&memory, &Filter::Exact(3.)).unwrap();
+        assert_eq!(scan.candidates(), &[(HEAP + 0x41, 3.)]);
+
+        assert!(ValueScan::new(FieldType::String).is_err());
+        let mut scan = ValueScan::new(FieldType::I32).unwrap();
+        assert!(scan.step(&FakeMemory::new(), &Filter::Exact(1.)).is_err());
+    }
+
+    #[test]
+    fn finds_pointer_paths() {
+        // rekordbox.exe+3500 -> HEAP, HEAP+28 -> DECK, the value at DECK+140
+        let image = image(&[0xC3], None);
+        let mut memory = FakeMemory::new();
+        memory.add_module("rekordbox.exe", BASE, image.len());
+        memory.write_bytes(BASE, &image);
+        memory.add_region(HEAP, 0x100);
+        memory.add_region(DECK, 0x200);
+        memory.write(BASE + 0x3500, HEAP as u64);
+        memory.write(HEAP + 0x28, DECK as u64);
+        memory.write(DECK + 0x140, 7i32);
+
+        let found = PointerScan::default().run(&memory, "rekordbox.exe", &[DECK + 0x140]).unwrap();
+        let found: Vec<String> = found.iter().map(|x| x.to_string()).collect();
+        assert_eq!(found, vec!["3500 28 140"]);
+
+        let shallow = PointerScan {
+            max_depth: 1,
+            ..Default::default()
+        };
+        assert!(shallow.run(&memory, "rekordbox.exe", &[DECK + 0x140]).unwrap().is_empty());
+        let near = PointerScan {
+            max_offset: 0x100,
+            ..Default::default()
+        };
+        assert!(near.run(&memory, "rekordbox.exe", &[DECK + 0x140]).unwrap().is_empty());
+        assert!(PointerScan::default().run(&memory, "other.dll", &[DECK]).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+
+use crate::{memory::MemorySource, offsets::RekordboxOffsets, pe::PeImage};
+
+// Signatures find the static addresses in rekordbox.exe by the code that uses them, so they
+// survive updates that move the address itself. Written as
+//
+//   [48 8B 05 ?? ?? ?? ?? 48 85 C0]+3
+//
+// a byte pattern with ?? for bytes that change, and where in the pattern the instruction's
+// 32 bit displacement starts. x64 code addresses statics relative to the end of the
+// instruction, taken to be right after the displacement. Instructions with an immediate after
+// the displacement end later, which is given after a /, eg. for `cmp byte [rip+x], 0`
+//
+//   [80 3D ?? ?? ?? ?? 00]+2/7
+
+/// A byte pattern in the module's code that points at a static address
+#[derive(Clone, PartialEq)]
+pub struct Signature {
+    pattern: Vec<Option<u8>>,
+    displacement: usize,
+    /// Where the instruction ends, counted from the start of the pattern
+    end: usize,
+}
+
+impl Signature {
+    /// Parse `[pattern]+displacement` or `[pattern]+displacement/end`, with the bytes and
+    /// offsets in hex
+    pub fn parse(input: &str) -> Result<Signature, String> {
+        let (pattern, displacement) = input
+            .strip_prefix('[')
+            .and_then(|x| x.split_once("]+"))
+            .ok_or_else(|| format!("Expected '[pattern]+displacement', got '{input}'"))?;
+        let (displacement, end) = match displacement.split_once('/') {
+            Some((displacement, end)) => (displacement, Some(end)),
+            None => (displacement, None),
+        };
+
+        let pattern = pattern
+            .split_whitespace()
+            .map(|byte| match byte {
+                "?" | "??" => Ok(None),
+                _ => u8::from_str_radix(byte, 16)
+                    .map(Some)
+                    .map_err(|_| format!("'{byte}' is not a hex byte or ??")),
+            })
+            .collect::<Result<Vec<Option<u8>>, String>>()?;
+        let hex = |x: &str| {
+            usize::from_str_radix(x, 16).map_err(|_| format!("'{x}' is not a hex number"))
+        };
+        let displacement = hex(displacement)?;
+        let end = end.map(hex).transpose()?.unwrap_or(displacement + 4);
+
+        if pattern.iter().all(|x| x.is_none()) {
+            return Err("A signature needs at least one byte that is not ??".to_string());
+        }
+        if displacement + 4 > pattern.len() {
+            return Err(format!(
+                "The displacement at +{displacement:X} does not fit in a {} byte pattern",
+                pattern.len()
+            ));
+        }
+        if end < displacement + 4 {
+            return Err(format!(
+                "The instruction can not end at +{end:X}, before its displacement at +{displacement:X}"
+            ));
+        }
+        Ok(Signature {
+            pattern,
+            displacement,
+            end,
+        })
+    }
+
+    /// Offsets in `data` where the pattern matches, at most `limit` of them
+    fn find(&self, data: &[u8], limit: usize) -> Vec<usize> {
+        data.windows(self.pattern.len())
+            .enumerate()
+            .filter(|(_, window)| {
+                window
+                    .iter()
+                    .zip(&self.pattern)
+                    .all(|(byte, pattern)| pattern.is_none_or(|x| x == *byte))
+            })
+            .map(|(i, _)| i)
+            .take(limit)
+            .collect()
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pattern: Vec<String> = self
+            .pattern
+            .iter()
+            .map(|x| x.map_or("??".to_string(), |x| format!("{x:02X}")))
+            .collect();
+        write!(f, "[{}]+{:X}", pattern.join(" "), self.displacement)?;
+        if self.end != self.displacement + 4 {
+            write!(f, "/{:X}", self.end)?;
+        }
+        Ok(())
+    }
+}
+
+/// Search the executable sections of a module for each signature. Gives the RVA of the static
+/// address each one points to, or why it could not be found. A signature has to match exactly
+/// once, anything else means it no longer fits this build.
+pub fn scan(source: &dyn MemorySource, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>> {
+    let sections = source
+        .module_base(module)
+        .and_then(|base| Some((base, PeImage::from_memory(source, base)?.executable_sections()?)));
+    let Some((base, sections)) = sections else {
+        return signatures
+            .iter()
+            .map(|_| Err(format!("Could not read the sections of {module}")))
+            .collect();
+    };
+
+    // Sections can be tens of MB, read each once for all signatures
+    let mut found: Vec<Vec<usize>> = vec![vec![]; signatures.len()];
+    for (rva, size) in sections {
+        let Some(data) = source.read_bytes(base + rva, size) else {
+            continue;
+        };
+        for (signature, found) in signatures.iter().zip(&mut found) {
+            for offset in signature.find(&data, 2) {
+                let at = offset + signature.displacement;
+                let displacement = i32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+                let end = rva + offset + signature.end;
+                found.push(end.wrapping_add_signed(displacement as isize));
+            }
+        }
+    }
+
+    found
+        .into_iter()
+        .map(|found| match found.as_slice() {
+            [rva] => Ok(*rva),
+            [] => Err("not found".to_string()),
+            _ => Err("found more than once".to_string()),
+        })
+        .collect()
+}
+
+/// Replace the signatures of the offsets by the static addresses they point to. Gives each
+/// pointer that had a signature by name, with why it could not be found if so.
+pub fn resolve_signatures(
+    offsets: &mut RekordboxOffsets,
+    source: &dyn MemorySource,
+) -> Vec<(String, Result<(), String>)> {
+    let mut pointers = offsets.pointers_mut();
+    pointers.retain(|(_, pointer)| pointer.signature.is_some());
+    if pointers.is_empty() {
+        return vec![];
+    }
+
+    // Pointers into the same object share a signature, only scan for it once
+    let mut signatures: Vec<Signature> = vec![];
+    let indices: Vec<usize> = pointers
+        .iter()
+        .filter_map(|(_, x)| x.signature.as_ref())
+        .map(|signature| match signatures.iter().position(|x| x == signature) {
+            Some(index) => index,
+            None => {
+                signatures.push(signature.clone());
+                signatures.len() - 1
+            }
+        })
+        .collect();
+    let found = source.find_signatures("rekordbox.exe", &signatures);
+
+    pointers
+        .into_iter()
+        .zip(indices)
+        .map(|((name, pointer), index)| {
+            let result = found[index].clone().map(|rva| pointer.rebase(rva));
+            (name, result)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::FakeMemory,
+        offsets::{DeckOffsets, Pointer},
+        pe::tests::{image, CODE_RVA},
+    };
+
+    const BASE: usize = 0x140000000;
+
+    fn module(code: &[u8]) -> FakeMemory {
+        let image = image(code, None);
+        let mut memory = FakeMemory::new();
+        memory.add_module("rekordbox.exe", BASE, image.len());
+        memory.write_bytes(BASE, &image);
+        memory
+    }
+
+    #[test]
+    fn parses() {
+        let signature = Signature::parse("[48 8b 05 ?? ? ?? ?? 48 85 C0]+3").unwrap();
+        assert_eq!(signature.pattern[1], Some(0x8B));
+        assert_eq!(signature.pattern[4], None);
+        assert_eq!((signature.displacement, signature.end), (3, 7));
+        assert_eq!(signature.to_string(), "[48 8B 05 ?? ?? ?? ?? 48 85 C0]+3");
+
+        let signature = Signature::parse("[80 3D ?? ?? ?? ?? 00]+2/7").unwrap();
+        assert_eq!((signature.displacement, signature.end), (2, 7));
+        assert_eq!(signature.to_string(), "[80 3D ?? ?? ?? ?? 00]+2/7");
+        // The default end is left out
+        let signature = Signature::parse("[8B 0D ?? ?? ?? ??]+2/6").unwrap();
+        assert_eq!(signature.to_string(), "[8B 0D ?? ?? ?? ??]+2");
+    }
+
+    #[test]
+    fn parse_errors() {
+        for input in [
+            "48 8B 05 ?? ?? ?? ??+3",
+            "[48 8B 05 ?? ?? ?? ??]",
+            "[48 8B 05 ?? ?? ?? ??]+G",
+            "[48 8B 05 ?? ?? ?? ??]+3/",
+            "[48 XX 05 ?? ?? ?? ??]+3",
+            "[?? ?? ?? ??]+0",
+            "[48 8B 05 ?? ?? ??]+3",
+            "[80 3D ?? ?? ?? ?? 00]+2/5",
+        ] {
+            assert!(Signature::parse(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn scans_code() {
+        // mov rax, [rip+0x2000] at +0x10 and cmp byte [rip+0x100], 0 at +0x20
+        let mut code = vec![0xCC; 0x40];
+        code[0x10..0x17].copy_from_slice(&[0x48, 0x8B, 0x05, 0x00, 0x20, 0x00, 0x00]);
+        code[0x20..0x27].copy_from_slice(&[0x80, 0x3D, 0x00, 0x01, 0x00, 0x00, 0x00]);
+        let memory = module(&code);
+
+        let signatures = [
+            "[48 8B 05 ?? ?? ?? ??]+3",
+            "[80 3D ?? ?? ?? ?? 00]+2/7",
+            "[48 8B 0D ?? ?? ?? ??]+3",
+            "[CC CC ?? ?? ?? ??]+2",
+        ]
+        .map(|x| Signature::parse(x).unwrap());
+        let found = memory.find_signatures("rekordbox.exe", &signatures);
+        assert_eq!(found[0], Ok(CODE_RVA + 0x17 + 0x2000));
+        assert_eq!(found[1], Ok(CODE_RVA + 0x27 + 0x100));
+        assert_eq!(found[2], Err("not found".to_string()));
+        assert_eq!(found[3], Err("found more than once".to_string()));
+    }
+
+    #[test]
+    fn resolves_offsets() {
+        let mut code = vec![0xCC; 0x20];
+        code[0x10..0x17].copy_from_slice(&[0x48, 0x8B, 0x05, 0x00, 0x20, 0x00, 0x00]);
+        let memory = module(&code);
+        let pointer = |x: &str| Pointer::from_string(x).unwrap();
+        let mut offsets = RekordboxOffsets {
+            rbversion: "7.0.0".to_string(),
+            decks: vec![DeckOffsets {
+                bar: pointer("[48 8B 05 ?? ?? ?? ??]+3 0 48 2468"),
+                beat: pointer("[48 8B 05 ?? ?? ?? ??]+3 0 48 246C"),
+                track_id: Some(pointer("[48 8B 0D ?? ?? ?? ??]+3 1FC")),
+                time: None,
+            }],
+            master_bpm: pointer("0544A460 40 170 28 BA0"),
+            masterdeck_index: pointer("052413A8 20 278 124"),
+            api_bearer: None,
+        };
+
+        let resolved = resolve_signatures(&mut offsets, &memory);
+        assert_eq!(
+            resolved,
+            vec![
+                ("deck1.bar".to_string(), Ok(())),
+                ("deck1.beat".to_string(), Ok(())),
+                ("deck1.track_id".to_string(), Err("not found".to_string())),
+            ]
+        );
+        let rva = CODE_RVA + 0x17 + 0x2000;
+        assert_eq!(offsets.decks[0].beat.to_string(), format!("{rva:X} 48 246C"));
+        assert!(offsets.decks[0].track_id.as_ref().unwrap().signature.is_some());
+        assert_eq!(offsets.master_bpm.to_string(), "544A460 40 170 28 BA0");
+    }
+
+    #[test]
+    fn needs_a_module() {
+        let memory = module(&[0xC3]);
+        let signatures = [Signature::parse("[48 8B 05 ?? ?? ?? ??]+3").unwrap()];
+        assert_eq!(
+            memory.find_signatures("other.dll", &signatures),
+            vec![Err("Could not read the sections of other.dll".to_string())]
+        );
+    }
+}
@@ -1,48 +1,248 @@
 use std::{
-    io::Write,
-    thread::sleep,
-    time,
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    thread::{sleep, spawn},
+    time::{self, Duration, Instant},
 };
 
-use std::net::TcpStream;
 use mdns_sd::{ServiceDaemon, ServiceEvent};
 
+use crate::error::{Error, Result};
+use crate::os2l::{windows_path, Os2lMessage, Os2lState};
+
+const DECKS: [i32; 4] = [1, 2, 3, 4];
+
+/// Try to reconnect this often after losing SoundSwitch
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to look for SoundSwitch on the network per reconnect attempt
+const REDISCOVER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Give up on an address that does not answer after this long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
 pub struct SoundSwitchConnectionAddr {
     pub soundswitch_ip: String,
     pub soundswitch_port: u16,
 }
 
-pub struct SoundSwitchConnector;
+impl SoundSwitchConnectionAddr {
+    /// Parse a fixed address like "127.0.0.1:4444"
+    pub fn parse(input: &str) -> Option<Self> {
+        let addr: SocketAddr = input.to_socket_addrs().ok()?.next()?;
+        Some(Self {
+            soundswitch_ip: addr.ip().to_string(),
+            soundswitch_port: addr.port(),
+        })
+    }
+}
+
+/// What the reader thread picked up from SoundSwitch
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    Message(Os2lMessage),
+    /// A line that is not a message we know
+    Unknown(String),
+    /// SoundSwitch closed the connection
+    Closed,
+}
+
+/// A connection made by the reconnect thread, with the address it was made to
+type Reconnected = Result<(TcpStream, SoundSwitchConnectionAddr)>;
+
+/// The mDNS daemon runs a thread of its own, which has to be told to stop
+struct Daemon(ServiceDaemon);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown();
+    }
+}
+
+/// OS2L connection to SoundSwitch. Keeps track of what SoundSwitch has been told, so a
+/// restarted SoundSwitch can be brought back in sync after reconnecting.
+pub struct SoundSwitchConnector {
+    /// Always connect here instead of discovering SoundSwitch with mDNS
+    fixed_addr: Option<SoundSwitchConnectionAddr>,
+    last_addr: Option<SoundSwitchConnectionAddr>,
+    os2l_stream: Option<TcpStream>,
+    last_attempt: Option<Instant>,
+    last_error: String,
+    /// Outcome of the reconnect running on its own thread, so beats go on meanwhile
+    reconnecting: Option<Receiver<Reconnected>>,
+    /// Messages from the reader thread of the current connection
+    incoming: Option<Receiver<Incoming>>,
+
+    /// Latest state, to answer queries and to handshake with after reconnecting
+    state: Os2lState,
+    /// Triggers SoundSwitch subscribed to on this connection
+    subscriptions: HashSet<String>,
+    /// Last value sent for each trigger, so subscriptions are only updated on change
+    sent: HashMap<String, serde_json::Value>,
+}
 
 impl SoundSwitchConnector {
+    pub fn new(fixed_addr: Option<SoundSwitchConnectionAddr>) -> Self {
+        Self {
+            fixed_addr,
+            last_addr: None,
+            os2l_stream: None,
+            last_attempt: None,
+            last_error: "".to_string(),
+            reconnecting: None,
+            incoming: None,
+            state: Os2lState::default(),
+            subscriptions: HashSet::new(),
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Connect for the first time, waiting as long as it takes to discover SoundSwitch.
+    pub fn connect(&mut self) -> Result<()> {
+        let connection = match &self.fixed_addr {
+            Some(addr) => addr.clone(),
+            None => SoundSwitchConnector::discover_soundswitch(None)?,
+        };
+        let os2l_stream = SoundSwitchConnector::initial_connect(connection.clone(), &self.state)?;
+        self.set_stream(os2l_stream);
+        self.last_addr = Some(connection);
+        Ok(())
+    }
+
+    /// Start using a new connection, with a thread reading what SoundSwitch sends on it
+    fn set_stream(&mut self, os2l_stream: TcpStream) {
+        self.incoming = match os2l_stream.try_clone() {
+            Ok(reader) => {
+                let (tx, rx) = channel();
+                spawn(move || read_incoming(reader, tx));
+                Some(rx)
+            }
+            Err(e) => {
+                println!("\nCan not read from SoundSwitch, queries will not be answered: {e}");
+                None
+            }
+        };
+        // A new connection is a new SoundSwitch session, it subscribes again
+        self.subscriptions.clear();
+        self.sent.clear();
+        self.os2l_stream = Some(os2l_stream);
+    }
+
+    fn disconnect(&mut self) {
+        // The reader thread has a clone of the socket, it only stops reading once this is shut
+        if let Some(os2l_stream) = self.os2l_stream.take() {
+            let _ = os2l_stream.shutdown(Shutdown::Both);
+        }
+        self.incoming = None;
+        self.last_attempt = None;
+    }
+
+    /// Answer what SoundSwitch asked for since the last call, and send subscribed values that
+    /// changed in `state`.
+    pub fn update(&mut self, state: &Os2lState) -> Result<()> {
+        self.state = state.clone();
 
-    pub fn discover_soundswitch() -> SoundSwitchConnectionAddr {
+        let incoming: Vec<Incoming> = match &self.incoming {
+            Some(rx) => rx.try_iter().collect(),
+            None => vec![],
+        };
+        for incoming in incoming {
+            match incoming {
+                Incoming::Message(message) => self.handle_message(message)?,
+                Incoming::Unknown(line) => println!("\nUnknown message from SoundSwitch: {line}"),
+                Incoming::Closed => {
+                    println!("\nSoundSwitch closed the connection");
+                    self.disconnect();
+                    return Ok(());
+                }
+            }
+        }
+
+        // Picks up a finished reconnect, or reports the same error as the sends while there is none
+        self.stream()?;
+        let mut changed = vec![];
+        for trigger in &self.subscriptions {
+            if let Some(value) = self.state.value(trigger).filter(|x| !x.is_null()) {
+                if self.sent.get(trigger) != Some(&value) {
+                    changed.push(Os2lMessage::subscribed(trigger.clone(), value));
+                }
+            }
+        }
+        for message in changed {
+            self.send(&message)?;
+        }
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message: Os2lMessage) -> Result<()> {
+        match message {
+            Os2lMessage::Subscribe { trigger } => match self.state.value(&trigger) {
+                // Goes out with the next update once it is known
+                Some(serde_json::Value::Null) => {
+                    self.subscriptions.insert(trigger);
+                }
+                Some(value) => {
+                    self.subscriptions.insert(trigger.clone());
+                    self.send(&Os2lMessage::subscribed(trigger, value))?;
+                }
+                None => println!("\nSoundSwitch asked for unknown trigger: {trigger}"),
+            },
+            Os2lMessage::Unsubscribe { trigger } => {
+                self.subscriptions.remove(&trigger);
+            }
+            // Button lights, we have no buttons to light up
+            Os2lMessage::Feedback { .. } => (),
+            other => println!("\nUnexpected message from SoundSwitch: {other:?}"),
+        }
+        Ok(())
+    }
+
+    pub fn discover_soundswitch(timeout: Option<Duration>) -> Result<SoundSwitchConnectionAddr> {
         // Create a daemon
-        let mdns = ServiceDaemon::new().expect("Failed to create daemon");
+        let mdns = Daemon(ServiceDaemon::new().map_err(|e| Error::Discovery(e.to_string()))?);
         
         // Browse for a service type.
         let service_type = "_os2l._tcp.local.";
-        let receiver = mdns.browse(service_type).expect("Failed to browse");
+        let receiver = mdns.0.browse(service_type).map_err(|e| Error::Discovery(e.to_string()))?;
         
         // Receive the browse events in sync or async. Here is
         // an example of using a thread. Users can call `receiver.recv_async().await`
         // if running in async environment.
 
-        println!("Looking for SoundSwitch application...");
+        // Stay quiet while reconnecting, this runs every second
+        let verbose = timeout.is_none();
+        if verbose {
+            println!("Looking for SoundSwitch application...");
+        }
         let connection: SoundSwitchConnectionAddr;
-        while let Ok(event) = receiver.recv() {
+        let deadline = timeout.map(|x| Instant::now() + x);
+        loop {
+            let event = match deadline {
+                Some(deadline) => receiver.recv_deadline(deadline).ok(),
+                None => receiver.recv().ok(),
+            };
+            let Some(event) = event else { break };
             match event {
                 ServiceEvent::ServiceResolved(info) => {
                     let service_name = info.get_fullname();
-                    println!("Resolved a new service: {}", service_name);
+                    if verbose {
+                        println!("Resolved a new service: {}", service_name);
+                    }
                     if service_name.starts_with("SoundSwitch") {
                         println!("Service is SoundSwitch");
+                        let Some(ip) = info.get_addresses_v4().iter().next().map(|x| x.to_string()) else {
+                            println!("SoundSwitch has no IPv4 address, waiting for another announcement");
+                            continue;
+                        };
                         connection = SoundSwitchConnectionAddr {
-                            soundswitch_ip: info.get_addresses_v4().iter().next().unwrap().to_string(),
+                            soundswitch_ip: ip,
                             soundswitch_port: info.get_port()
                         };
                         println!("SoundSwitch at {}:{}", connection.soundswitch_ip, connection.soundswitch_port);
-                        return connection;
+                        return Ok(connection);
                     }
                 }
                 _other_event => {
@@ -51,73 +251,434 @@ impl SoundSwitchConnector {
                 }
             }
         };        
-        std::process::exit(1);
+        Err(Error::Discovery("SoundSwitch was not found".to_string()))
     }
 
-    pub fn initial_connect(connection: SoundSwitchConnectionAddr) -> TcpStream {
-        let mut os2l_stream = TcpStream::connect(format!("{}:{}",connection.soundswitch_ip, connection.soundswitch_port)).unwrap();
-        // These values are a guess based on what VirtualDJ sends to SoundSwitch
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_text '%SOUNDSWITCH_ID'\",\"value\":\"\"}\n").unwrap();
+    pub fn initial_connect(connection: SoundSwitchConnectionAddr, state: &Os2lState) -> Result<TcpStream> {
+        let addr = (connection.soundswitch_ip.as_str(), connection.soundswitch_port)
+            .to_socket_addrs()
+            .map_err(Error::Os2l)?
+            .next()
+            .ok_or_else(|| Error::Os2l(io::Error::from(io::ErrorKind::AddrNotAvailable)))?;
+        let mut os2l_stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).map_err(Error::Os2l)?;
+        // Announce the same triggers as VirtualDJ does when SoundSwitch connects to it
+        let announce = |os2l_stream: &mut TcpStream, trigger: String| match state.value(&trigger) {
+            Some(serde_json::Value::Null) | None => Ok(()),
+            Some(value) => send(os2l_stream, &Os2lMessage::subscribed(trigger, value)),
+        };
+        let soundswitch_id = |deck: i32| format!("deck {deck} get_text '%SOUNDSWITCH_ID'");
+        // VirtualDJ starts out with empty ids and fills them in once it has looked at the tracks
+        send(&mut os2l_stream, &Os2lMessage::subscribed(soundswitch_id(1), ""))?;
         sleep(time::Duration::from_millis(20));
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 2 get_text '%SOUNDSWITCH_ID'\",\"value\":\"\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 get_text '%SOUNDSWITCH_ID'\",\"value\":\"\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 get_text '%SOUNDSWITCH_ID'\",\"value\":\"\"}\n").unwrap();
+        for deck in 2..=4 {
+            send(&mut os2l_stream, &Os2lMessage::subscribed(soundswitch_id(deck), ""))?;
+        }
         sleep(time::Duration::from_millis(30));
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 level\",\"value\":1}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 2 level\",\"value\":1}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 level\",\"value\":1}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 level\",\"value\":1}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"crossfader\",\"value\":0.5}\n").unwrap();
+        for deck in DECKS {
+            announce(&mut os2l_stream, format!("deck {deck} level"))?;
+        }
+        announce(&mut os2l_stream, "crossfader".to_string())?;
         sleep(time::Duration::from_millis(50));
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_bpm\",\"value\":120}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 2 get_bpm\",\"value\":120}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 get_bpm\",\"value\":120}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 get_bpm\",\"value\":120}\n").unwrap();
+        for deck in DECKS {
+            announce(&mut os2l_stream, format!("deck {deck} get_bpm"))?;
+        }
         sleep(time::Duration::from_millis(50));
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 play\",\"value\":\"off\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 2 play\",\"value\":\"off\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 play\",\"value\":\"off\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 play\",\"value\":\"off\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 loop\",\"value\":\"off\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 2 loop\",\"value\":\"off\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 loop\",\"value\":\"off\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 loop\",\"value\":\"off\"}\n").unwrap();
+        for deck in DECKS {
+            announce(&mut os2l_stream, format!("deck {deck} play"))?;
+        }
+        for deck in DECKS {
+            announce(&mut os2l_stream, format!("deck {deck} loop"))?;
+        }
         sleep(time::Duration::from_millis(50));
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_loop\",\"value\":8}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 2 get_loop\",\"value\":16}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 get_loop\",\"value\":8}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 get_loop\",\"value\":8}\n").unwrap();
+        for deck in DECKS {
+            announce(&mut os2l_stream, format!("deck {deck} get_loop"))?;
+        }
         sleep(time::Duration::from_millis(50));
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 loop_roll 0.03125 ? constant 0.03125 : deck 1 loop_roll 0.0625 ? constant 0.0625 : deck 1 loop_roll 0.125 ? constant 0.125 : deck 1 loop_roll 0.25 ? constant 0.25 : deck 1 loop_roll 0.5 ? constant 0.5 : deck 1 loop_roll 0.75 ? constant 0.75 : deck 1 loop_roll 1 ? constant 1 : deck 1 loop_roll 2 ? constant 2 : deck 1 loop_roll 4 ? constant 4 : constant 0\",\"value\":0}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 2 loop_roll 0.03125 ? constant 0.03125 : deck 2 loop_roll 0.0625 ? constant 0.0625 : deck 2 loop_roll 0.125 ? constant 0.125 : deck 2 loop_roll 0.25 ? constant 0.25 : deck 2 loop_roll 0.5 ? constant 0.5 : deck 2 loop_roll 0.75 ? constant 0.75 : deck 2 loop_roll 1 ? constant 1 : deck 2 loop_roll 2 ? constant 2 : deck 2 loop_roll 4 ? constant 4 : constant 0\",\"value\":0}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 loop_roll 0.03125 ? constant 0.03125 : deck 3 loop_roll 0.0625 ? constant 0.0625 : deck 3 loop_roll 0.125 ? constant 0.125 : deck 3 loop_roll 0.25 ? constant 0.25 : deck 3 loop_roll 0.5 ? constant 0.5 : deck 3 loop_roll 0.75 ? constant 0.75 : deck 3 loop_roll 1 ? constant 1 : deck 3 loop_roll 2 ? constant 2 : deck 3 loop_roll 4 ? constant 4 : constant 0\",\"value\":0}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 loop_roll 0.03125 ? constant 0.03125 : deck 4 loop_roll 0.0625 ? constant 0.0625 : deck 4 loop_roll 0.125 ? constant 0.125 : deck 4 loop_roll 0.25 ? constant 0.25 : deck 4 loop_roll 0.5 ? constant 0.5 : deck 4 loop_roll 0.75 ? constant 0.75 : deck 4 loop_roll 1 ? constant 1 : deck 4 loop_roll 2 ? constant 2 : deck 4 loop_roll 4 ? constant 4 : constant 0\",\"value\":0}\n").unwrap();
+        for deck in DECKS {
+            // Nested conditional asking which loop roll size is active, if any
+            let loop_roll = ["0.03125", "0.0625", "0.125", "0.25", "0.5", "0.75", "1", "2", "4"]
+                .map(|size| format!("deck {deck} loop_roll {size} ? constant {size}"))
+                .join(" : ");
+            announce(&mut os2l_stream, format!("{loop_roll} : constant 0"))?;
+        }
         sleep(time::Duration::from_millis(50));
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_text '%SOUNDSWITCH_ID'\",\"value\":\"{00000000-0000-0000-0000-000000000000}\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 3 get_text '%SOUNDSWITCH_ID'\",\"value\":\"{00000000-0000-0000-0000-000000000000}\"}\n").unwrap();
-        os2l_stream.write(b"{\"evt\":\"subscribed\",\"trigger\":\"deck 4 get_text '%SOUNDSWITCH_ID'\",\"value\":\"{00000000-0000-0000-0000-000000000000}\"}\n").unwrap();
+        for deck in [1, 3, 4] {
+            announce(&mut os2l_stream, soundswitch_id(deck))?;
+        }
         sleep(time::Duration::from_millis(50));
 
-        return os2l_stream;
+        Ok(os2l_stream)
     }
 
-    pub fn send_beatpos(os2l_stream: &mut TcpStream, last_beat: i32) {
-        os2l_stream.write(format!("{{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_beatpos\",\"value\":{}}}\n", last_beat).as_str().as_bytes()).unwrap();
+    /// Get the stream, reconnecting and replaying the session if SoundSwitch was lost.
+    fn stream(&mut self) -> Result<&mut TcpStream> {
+        if self.os2l_stream.is_none() {
+            let not_connected = || {
+                Error::Os2l(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Not connected to SoundSwitch, reconnecting",
+                ))
+            };
+            let Some(reconnected) = self.poll_reconnect() else {
+                return Err(not_connected());
+            };
+            if let Err(e) = reconnected.and_then(|(os2l_stream, connection)| {
+                self.last_addr = Some(connection);
+                self.restore(os2l_stream)
+            }) {
+                // Only print why reconnecting failed when the reason changes
+                let e = e.to_string();
+                if e != self.last_error {
+                    println!("\n{e}");
+                    self.last_error = e;
+                }
+                return Err(not_connected());
+            }
+            self.last_error.clear();
+            println!("\nReconnected to SoundSwitch");
+        }
+        self.os2l_stream
+            .as_mut()
+            .ok_or_else(|| Error::Os2l(io::Error::from(io::ErrorKind::NotConnected)))
     }
 
-    pub fn send_beat(os2l_stream: &mut TcpStream, last_beat: i32, last_bpm: f32) {
-        os2l_stream.write(format!("{{\"evt\":\"beat\",\"change\":false,\"pos\":{},\"bpm\":{},\"strength\":0}}", last_beat, last_bpm).as_str().as_bytes()).unwrap();
+    /// Start a reconnect when it is time for another one, and pick up the running one once it
+    /// is done.
+    fn poll_reconnect(&mut self) -> Option<Reconnected> {
+        if let Some(rx) = &self.reconnecting {
+            let reconnected = match rx.try_recv() {
+                Ok(reconnected) => reconnected,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => Err(Error::Os2l(io::Error::other(
+                    "Reconnecting to SoundSwitch stopped",
+                ))),
+            };
+            self.reconnecting = None;
+            return Some(reconnected);
+        }
+        if self.last_attempt.is_some_and(|x| x.elapsed() < RECONNECT_INTERVAL) {
+            return None;
+        }
+        self.last_attempt = Some(Instant::now());
+        let fixed_addr = self.fixed_addr.clone();
+        let last_addr = self.last_addr.clone();
+        let state = self.state.clone();
+        let (tx, rx) = channel();
+        spawn(move || {
+            let _ = tx.send(SoundSwitchConnector::reconnect(fixed_addr, last_addr, &state));
+        });
+        self.reconnecting = Some(rx);
+        None
     }
 
-    pub fn send_track(os2l_stream: &mut TcpStream, last_master_path: &mut String) {
-        let master_path = last_master_path.replace("/", "\\\\"); // Replace slashes with backslashes for serialization
-        os2l_stream.write(format!("{{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_filepath\",\"value\":\"{}\"}}\n", master_path).as_str().as_bytes()).unwrap();
+    /// Runs on its own thread, the handshake alone takes a few hundred milliseconds
+    fn reconnect(
+        fixed_addr: Option<SoundSwitchConnectionAddr>,
+        last_addr: Option<SoundSwitchConnectionAddr>,
+        state: &Os2lState,
+    ) -> Reconnected {
+        // SoundSwitch usually comes back on the same address, so try that before discovering again
+        let connect = |connection: SoundSwitchConnectionAddr| {
+            SoundSwitchConnector::initial_connect(connection.clone(), state).map(|x| (x, connection))
+        };
+        match last_addr.map(connect) {
+            Some(Ok(reconnected)) => Ok(reconnected),
+            Some(Err(e)) if fixed_addr.is_some() => Err(e),
+            _ => connect(SoundSwitchConnector::discover_soundswitch(Some(REDISCOVER_TIMEOUT))?),
+        }
     }
 
-    pub fn send_time(os2l_stream: &mut TcpStream, last_time: i32) {
-        os2l_stream.write(format!("{{\"evt\":\"subscribed\",\"trigger\":\"deck 1 get_time elapsed absolute\",\"value\":{}}}\n", last_time).as_str().as_bytes()).unwrap();
+    /// Bring SoundSwitch back to where it was on a new connection
+    fn restore(&mut self, mut os2l_stream: TcpStream) -> Result<()> {
+        for (deck, deck_state) in (1..).zip(&self.state.decks) {
+            if !deck_state.filepath.is_empty() {
+                send(&mut os2l_stream, &track_message(deck, &deck_state.filepath))?;
+            }
+        }
+        let master_deck = self.state.master_deck;
+        if let Some(master) = self.state.decks.get(master_deck.wrapping_sub(1)) {
+            send(&mut os2l_stream, &Os2lMessage::beat(master.beatpos, master.bpm.unwrap_or_default()))?;
+            send(&mut os2l_stream, &beatpos_message(master_deck, master.beatpos))?;
+            send(&mut os2l_stream, &time_message(master_deck, master.time))?;
+        }
+
+        self.set_stream(os2l_stream);
+        Ok(())
     }
-    
+
+    /// Write a message, dropping the stream if SoundSwitch has gone away.
+    fn send(&mut self, message: &Os2lMessage) -> Result<()> {
+        let os2l_stream = self.stream()?;
+        if let Err(e) = send(os2l_stream, message) {
+            self.disconnect();
+            return Err(e);
+        }
+        if let Os2lMessage::Subscribed { trigger, value } = message {
+            self.sent.insert(trigger.clone(), value.clone());
+        }
+        Ok(())
+    }
+
+    pub fn send_beatpos(&mut self, deck: usize, last_beat: i32) -> Result<()> {
+        self.send(&beatpos_message(deck, last_beat))
+    }
+
+    pub fn send_beat(&mut self, last_beat: i32, last_bpm: f32) -> Result<()> {
+        self.send(&Os2lMessage::beat(last_beat, last_bpm))
+    }
+
+    pub fn send_track(&mut self, deck: usize, path: &str) -> Result<()> {
+        self.send(&track_message(deck, path))
+    }
+
+    pub fn send_time(&mut self, deck: usize, time: i32) -> Result<()> {
+        self.send(&time_message(deck, time))
+    }
+}
+
+fn beatpos_message(deck: usize, last_beat: i32) -> Os2lMessage {
+    Os2lMessage::subscribed(format!("deck {deck} get_beatpos"), last_beat)
+}
+
+fn track_message(deck: usize, path: &str) -> Os2lMessage {
+    Os2lMessage::subscribed(format!("deck {deck} get_filepath"), windows_path(path))
+}
+
+fn time_message(deck: usize, last_time: i32) -> Os2lMessage {
+    Os2lMessage::subscribed(format!("deck {deck} get_time elapsed absolute"), last_time)
 }
 
+/// Runs on its own thread for each connection, until SoundSwitch or the connector goes away
+fn read_incoming(os2l_stream: impl Read, tx: Sender<Incoming>) {
+    let mut reader = BufReader::new(os2l_stream);
+    let mut buffer = vec![];
+    loop {
+        buffer.clear();
+        match reader.read_until(b'\n', &mut buffer) {
+            Ok(0) | Err(_) => {
+                let _ = tx.send(Incoming::Closed);
+                return;
+            }
+            Ok(_) => (),
+        }
+        let line = String::from_utf8_lossy(&buffer);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let incoming = match Os2lMessage::from_line(&line) {
+            Ok(message) => Incoming::Message(message),
+            Err(_) => Incoming::Unknown(line.trim().to_string()),
+        };
+        if tx.send(incoming).is_err() {
+            return;
+        }
+    }
+}
+
+fn send(os2l_stream: &mut TcpStream, message: &Os2lMessage) -> Result<()> {
+    os2l_stream.write_all(message.to_line().as_bytes()).map_err(Error::Os2l)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
 
+    /// SoundSwitch's end of a connection
+    struct Peer {
+        reader: BufReader<TcpStream>,
+    }
+
+    impl Peer {
+        fn accept(listener: &TcpListener) -> Peer {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            Peer {
+                reader: BufReader::new(stream),
+            }
+        }
+
+        /// Read messages until one with `trigger`, giving its value
+        fn value_of(&mut self, trigger: &str) -> serde_json::Value {
+            loop {
+                let mut line = String::new();
+                assert!(self.reader.read_line(&mut line).unwrap() > 0, "closed before {trigger}");
+                if let Ok(Os2lMessage::Subscribed { trigger: x, value }) = Os2lMessage::from_line(&line) {
+                    if x == trigger {
+                        return value;
+                    }
+                }
+            }
+        }
+
+        fn send(&mut self, data: impl AsRef<[u8]>) {
+            self.reader.get_mut().write_all(data.as_ref()).unwrap();
+        }
+    }
+
+    fn listener() -> (TcpListener, SoundSwitchConnectionAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = SoundSwitchConnectionAddr::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        (listener, addr)
+    }
+
+    /// Update until `done`, the reconnect runs in the background
+    fn update_until(
+        connector: &mut SoundSwitchConnector,
+        state: &Os2lState,
+        done: impl Fn(&SoundSwitchConnector) -> bool,
+    ) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let _ = connector.update(state);
+            if done(connector) {
+                return;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn disconnecting_ends_the_reader() {
+        let (listener, addr) = listener();
+        let mut connector = SoundSwitchConnector::new(Some(addr));
+        connector.connect().unwrap();
+        let mut peer = Peer::accept(&listener);
+        peer.value_of("deck 4 get_text '%SOUNDSWITCH_ID'");
+
+        connector.disconnect();
+        // The reader's clone of the socket is closed as well, so SoundSwitch sees the end
+        // instead of the read timing out
+        let mut rest = vec![];
+        peer.reader.read_to_end(&mut rest).unwrap();
+    }
+
+    #[test]
+    fn reconnects_and_restores() {
+        let (listener, addr) = listener();
+        let mut state = Os2lState::default();
+        state.decks[0].filepath = "/Music/a.mp3".to_string();
+        state.decks[0].bpm = Some(128.);
+        state.decks[0].beatpos = 33;
+        state.decks[0].time = 12_500;
+
+        let mut connector = SoundSwitchConnector::new(Some(addr));
+        connector.connect().unwrap();
+        let mut peer = Peer::accept(&listener);
+        peer.send("{\"evt\":\"subscribe\",\"trigger\":\"deck 1 get_bpm\"}\n");
+        update_until(&mut connector, &state, |x| !x.subscriptions.is_empty());
+        assert_eq!(peer.value_of("deck 1 get_bpm"), 128.);
+
+        // SoundSwitch restarts
+        drop(peer);
+        update_until(&mut connector, &state, |x| x.os2l_stream.is_none());
+        update_until(&mut connector, &state, |x| x.os2l_stream.is_some());
+        let mut peer = Peer::accept(&listener);
+
+        // The handshake, then where the mixer is
+        assert_eq!(peer.value_of("deck 1 get_bpm"), 128.);
+        assert_eq!(peer.value_of("deck 1 get_filepath"), "\\Music\\a.mp3");
+        assert_eq!(peer.value_of("deck 1 get_beatpos"), 33);
+        assert_eq!(peer.value_of("deck 1 get_time elapsed absolute"), 12_500);
+
+        // It subscribes again on the new connection, and gets changes from then on
+        assert!(connector.subscriptions.is_empty());
+        peer.send("{\"evt\":\"subscribe\",\"trigger\":\"deck 1 get_bpm\"}\n");
+        update_until(&mut connector, &state, |x| !x.subscriptions.is_empty());
+        assert_eq!(peer.value_of("deck 1 get_bpm"), 128.);
+        state.decks[0].bpm = Some(130.);
+        connector.update(&state).unwrap();
+        assert_eq!(peer.value_of("deck 1 get_bpm"), 130.);
+    }
+
+    /// Gives its chunks one per read, like a socket that got them in separate packets
+    struct Chunks(Vec<&'static str>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0).as_bytes();
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn reads_lines() {
+        let chunks = Chunks(vec![
+            "{\"evt\":\"subscribe\",\"trig",
+            "ger\":\"deck 1 play\"}\n\n{\"evt\": broken\n",
+            "{\"evt\":\"unsubscribe\",\"trigger\":\"deck 1 play\"}\r\n",
+        ]);
+        let (tx, rx) = channel();
+        read_incoming(chunks, tx);
+        let incoming: Vec<Incoming> = rx.try_iter().collect();
+        assert_eq!(
+            incoming,
+            [
+                Incoming::Message(Os2lMessage::Subscribe {
+                    trigger: "deck 1 play".to_string()
+                }),
+                Incoming::Unknown("{\"evt\": broken".to_string()),
+                Incoming::Message(Os2lMessage::Unsubscribe {
+                    trigger: "deck 1 play".to_string()
+                }),
+                Incoming::Closed,
+            ]
+        );
+    }
+
+    /// A connector talking to a peer, without the handshake
+    fn connected() -> (SoundSwitchConnector, Peer) {
+        let (listener, addr) = listener();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut connector = SoundSwitchConnector::new(Some(addr));
+        connector.set_stream(stream);
+        (connector, Peer::accept(&listener))
+    }
+
+    #[test]
+    fn answers_subscriptions() {
+        let (mut connector, mut peer) = connected();
+        let mut state = Os2lState::default();
+        state.decks[1].playing = true;
+        connector.update(&state).unwrap();
+
+        peer.send("{\"evt\":\"subscribe\",\"trigger\":\"deck 2 play\"}\n");
+        peer.send("{\"evt\":\"subscribe\",\"trigger\":\"deck 2 get_key\"}\n");
+        // Not known yet, answered once it is
+        peer.send("{\"evt\":\"subscribe\",\"trigger\":\"deck 2 get_bpm\"}\n");
+        peer.send("{\"evt\":\"feedback\",\"name\":\"play\",\"page\":\"\",\"state\":\"on\"}\n");
+        update_until(&mut connector, &state, |x| x.subscriptions.len() == 2);
+        assert_eq!(peer.value_of("deck 2 play"), "on");
+        assert!(!connector.subscriptions.contains("deck 2 get_key"));
+        assert!(!connector.sent.contains_key("deck 2 get_bpm"));
+
+        state.decks[1].bpm = Some(128.);
+        connector.update(&state).unwrap();
+        assert_eq!(peer.value_of("deck 2 get_bpm"), 128.);
+
+        // Changes only go out while subscribed
+        peer.send("{\"evt\":\"unsubscribe\",\"trigger\":\"deck 2 play\"}\n");
+        update_until(&mut connector, &state, |x| x.subscriptions.len() == 1);
+        state.decks[1].playing = false;
+        state.decks[1].bpm = Some(130.);
+        connector.update(&state).unwrap();
+        assert_eq!(peer.value_of("deck 2 get_bpm"), 130.);
+        assert_eq!(connector.sent["deck 2 play"], "on");
+    }
+
+    #[test]
+    fn survives_bad_lines() {
+        let (mut connector, mut peer) = connected();
+        let state = Os2lState::default();
+        peer.send(b"hello\n{\"evt\":\"subscribe\"}\n\xff\n{\"evt\":\"subscribe\",\"trigger\":\"crossfader\"}\n");
+        update_until(&mut connector, &state, |x| x.subscriptions.contains("crossfader"));
+        assert_eq!(peer.value_of("crossfader"), 0.5);
+        assert!(connector.os2l_stream.is_some());
+    }
+}
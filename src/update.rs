@@ -0,0 +1,184 @@
+use std::{fs, path::Path};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{Error, Result},
+    offsets::RekordboxOffsets,
+};
+
+// An update fetches two files from the mirror:
+//
+//   offsets          The offsets file
+//   offsets.sha256   Its SHA-256, as written by `sha256sum offsets > offsets.sha256`
+//
+// The new file is downloaded next to the old one, and only replaces it once the checksum
+// matches and it parses. The old one is kept as offsets.bak.
+//
+// Both come from the same mirror, so the checksum only catches a broken or cut off download. It
+// is not a signature, whoever controls the mirror controls the offsets.
+
+/// Where `-u` fetches from unless given a mirror with `-m`
+pub const DEFAULT_MIRROR: &str = "https://raw.githubusercontent.com/fjel/rkbx_os2l/master/";
+
+/// Fetch the offsets file from `mirror` to `path`, giving the versions it has. The checksum
+/// checks the download, not the mirror.
+pub fn update_offsets(mirror: &str, path: &str) -> Result<Vec<String>> {
+    let client = reqwest::blocking::Client::new();
+    let url = |name: &str| format!("{}/{name}", mirror.trim_end_matches('/'));
+
+    let offsets = fetch(&client, &url("offsets"))?;
+    let checksum = fetch(&client, &url("offsets.sha256"))?;
+    let expected = String::from_utf8_lossy(&checksum)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let actual = sha256(&offsets);
+    if expected != actual {
+        return Err(Error::Update(format!(
+            "The checksum does not match, expected {expected} but got {actual}"
+        )));
+    }
+
+    let download = format!("{path}.download");
+    let backup = format!("{path}.bak");
+    fs::write(&download, &offsets).map_err(|e| Error::Update(format!("{download}: {e}")))?;
+    let versions = match RekordboxOffsets::from_file(&download) {
+        Ok(file) => file.versions(),
+        Err(e) => {
+            let _ = fs::remove_file(&download);
+            return Err(Error::Update(format!("The new file is broken, keeping the old one. {e}")));
+        }
+    };
+
+    if Path::new(path).exists() {
+        fs::rename(path, &backup).map_err(|e| Error::Update(format!("{backup}: {e}")))?;
+    }
+    fs::rename(&download, path).map_err(|e| Error::Update(format!("{path}: {e}")))?;
+    Ok(versions)
+}
+
+/// Lowercase hex, like `sha256sum` prints it
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|x| format!("{x:02x}")).collect()
+}
+
+fn fetch(client: &reqwest::blocking::Client, url: &str) -> Result<Vec<u8>> {
+    let response = client
+        .get(url)
+        .send()
+        .and_then(|x| x.error_for_status())
+        .map_err(|e| Error::Update(e.to_string()))?;
+    let bytes = response.bytes().map_err(|e| Error::Update(format!("{url}: {e}")))?;
+    Ok(bytes.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread::spawn,
+    };
+
+    use super::*;
+
+    #[test]
+    fn shipped_checksum() {
+        let checksum = include_str!("../offsets.sha256");
+        assert_eq!(
+            checksum.split_whitespace().next(),
+            Some(sha256(include_bytes!("../offsets")).as_str()),
+            "offsets.sha256 is out of date, run sha256sum offsets > offsets.sha256"
+        );
+    }
+
+    const OLD: &str = "6.8.5\nmaster_bpm = 1\nmasterdeck_index = 2\ndeck1.bar = 3\ndeck1.beat = 4\n";
+    const NEW: &str = "\
+7.0.0\nmaster_bpm = 5\nmasterdeck_index = 2\ndeck1.bar = 3\ndeck1.beat = 4\n
+6.8.5 inherits 7.0.0\n";
+
+    /// A mirror serving `files` over HTTP, anything else is not found
+    fn mirror(files: HashMap<&'static str, String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request).unwrap();
+                // Skip the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request.split(' ').nth(1).unwrap_or_default().trim_start_matches('/');
+                let response = match files.get(path) {
+                    Some(body) => {
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}", body.len())
+                    }
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    /// Update an offsets file holding `OLD` from a mirror with `offsets` and `checksum`, giving
+    /// the result and what is left in the offsets file and its backup
+    fn update(
+        name: &str,
+        offsets: &str,
+        checksum: Option<String>,
+    ) -> (Result<Vec<String>>, String, Option<String>) {
+        let mut files = HashMap::from([("offsets", offsets.to_string())]);
+        if let Some(checksum) = checksum {
+            files.insert("offsets.sha256", checksum);
+        }
+        let mirror = mirror(files);
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_{name}_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, OLD).unwrap();
+
+        let result = update_offsets(&mirror, path);
+        let contents = fs::read_to_string(path).unwrap();
+        let backup = fs::read_to_string(format!("{path}.bak")).ok();
+        assert!(!Path::new(&format!("{path}.download")).exists());
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{path}.bak"));
+        (result, contents, backup)
+    }
+
+    #[test]
+    fn replaces_the_old_file() {
+        let checksum = format!("{}  offsets\n", sha256(NEW.as_bytes()).to_uppercase());
+        let (result, contents, backup) = update("update_new", NEW, Some(checksum));
+        assert_eq!(result.unwrap(), ["7.0.0", "6.8.5"]);
+        assert_eq!(contents, NEW);
+        assert_eq!(backup.as_deref(), Some(OLD));
+    }
+
+    #[test]
+    fn keeps_the_old_file() {
+        // A download that does not match its checksum
+        let (result, contents, backup) = update("update_mismatch", NEW, Some(sha256(OLD.as_bytes())));
+        assert!(matches!(result, Err(Error::Update(e)) if e.starts_with("The checksum does not match")));
+        assert_eq!((contents.as_str(), backup), (OLD, None));
+
+        // One that matches but does not parse
+        let broken = "7.0.0\n1\n";
+        let checksum = sha256(broken.as_bytes());
+        let (result, contents, backup) = update("update_broken", broken, Some(checksum));
+        assert!(matches!(result, Err(Error::Update(e)) if e.starts_with("The new file is broken")));
+        assert_eq!((contents.as_str(), backup), (OLD, None));
+
+        // A mirror without a checksum
+        let (result, contents, backup) = update("update_no_checksum", NEW, None);
+        assert!(matches!(result, Err(Error::Update(e)) if e.contains("404")));
+        assert_eq!((contents.as_str(), backup), (OLD, None));
+    }
+}
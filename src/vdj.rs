@@ -0,0 +1,351 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use quick_xml::{
+    events::{BytesDecl, BytesEnd, BytesStart, Event},
+    Reader, Writer,
+};
+
+use crate::{
+    anlz::Analysis,
+    api::DjmdContent,
+    error::{Error, Result},
+};
+
+// SoundSwitch autoscripts tracks from VirtualDJ's database.xml, which normally needs VirtualDJ
+// to analyse the library first. The same can be written from Rekordbox's analysis:
+//
+//   <VirtualDJ_Database Version="8.5">
+//    <Song FilePath="C:\Music\track.mp3">
+//     <Tags Author="..." Title="..." Genre="..." Album="..." Bpm="0.468750" Key="8A" />
+//     <Infos SongLength="240.000000" />
+//     <Scan Version="801" Bpm="0.468750" Key="8A" />
+//     <Poi Pos="0.100000" Type="beatgrid" />
+//     <Poi Pos="12.345000" Num="1" />
+//    </Song>
+//   </VirtualDJ_Database>
+//
+// VirtualDJ keeps the tempo as seconds per beat and positions in seconds. The beatgrid is
+// anchored on the first downbeat. Songs with a path that is exported are replaced, everything
+// else in the file is kept as it is.
+
+const DATABASE_VERSION: &str = "8.5";
+
+/// What VirtualDJ writes for its own analysis, older ones are analysed again
+const SCAN_VERSION: &str = "801";
+
+/// One track as VirtualDJ would have it
+pub struct VdjSong {
+    /// The path as SoundSwitch gets it, with \
+    pub path: String,
+    pub content: DjmdContent,
+    pub analysis: Option<Analysis>,
+}
+
+impl VdjSong {
+    /// Tempo of the beatgrid, or the one in the library without an analysis
+    fn bpm(&self) -> Option<f32> {
+        let grid = self.analysis.as_ref().and_then(|x| x.beats.first()).map(|x| x.bpm);
+        grid.or(self.content.bpm()).filter(|x| *x > 0.)
+    }
+
+    fn write(&self, writer: &mut Writer<Vec<u8>>) -> quick_xml::Result<()> {
+        let content = &self.content;
+        let seconds_per_beat = self.bpm().map(|x| format!("{:.6}", 60. / x));
+        let mut song = BytesStart::new("Song");
+        song.push_attribute(("FilePath", self.path.as_str()));
+        writer.write_event(Event::Start(song))?;
+
+        let mut tags = BytesStart::new("Tags");
+        let mut scan = BytesStart::new("Scan");
+        scan.push_attribute(("Version", SCAN_VERSION));
+        for (name, value) in [
+            ("Author", &content.artist_name),
+            ("Title", &content.title),
+            ("Genre", &content.genre_name),
+            ("Album", &content.album_name),
+            ("Bpm", &seconds_per_beat),
+            ("Key", &content.key_name),
+        ] {
+            if let Some(value) = value.as_deref().filter(|x| !x.is_empty()) {
+                tags.push_attribute((name, value));
+                if matches!(name, "Bpm" | "Key") {
+                    scan.push_attribute((name, value));
+                }
+            }
+        }
+        writer.write_event(Event::Empty(tags))?;
+        if let Some(length) = content.duration() {
+            let mut infos = BytesStart::new("Infos");
+            infos.push_attribute(("SongLength", format!("{:.6}", length.as_secs_f64()).as_str()));
+            writer.write_event(Event::Empty(infos))?;
+        }
+        writer.write_event(Event::Empty(scan))?;
+
+        let Some(analysis) = &self.analysis else {
+            return writer.write_event(Event::End(BytesEnd::new("Song")));
+        };
+        let anchor = analysis
+            .beats
+            .iter()
+            .find(|x| x.number == 1)
+            .or(analysis.beats.first());
+        if let Some(anchor) = anchor {
+            let mut poi = BytesStart::new("Poi");
+            poi.push_attribute(("Pos", seconds(anchor.time_ms).as_str()));
+            poi.push_attribute(("Type", "beatgrid"));
+            writer.write_event(Event::Empty(poi))?;
+        }
+        // Hot cues keep their letter as a number, memory cues have none
+        for cue in &analysis.cues {
+            let mut poi = BytesStart::new("Poi");
+            poi.push_attribute(("Pos", seconds(cue.time_ms).as_str()));
+            if cue.hot_cue > 0 {
+                poi.push_attribute(("Num", cue.hot_cue.to_string().as_str()));
+            }
+            writer.write_event(Event::Empty(poi))?;
+        }
+        writer.write_event(Event::End(BytesEnd::new("Song")))
+    }
+}
+
+fn seconds(ms: u32) -> String {
+    format!("{:.6}", ms as f64 / 1000.)
+}
+
+/// Write the songs into the database at `path`, replacing songs with the same path and keeping
+/// the rest. The old file is kept as .bak. Gives how many songs were replaced.
+pub fn merge_database(path: &Path, songs: &[VdjSong]) -> Result<usize> {
+    let error = |e: String| Error::Export(format!("{}: {e}", path.display()));
+    let existing = match fs::read_to_string(path) {
+        Ok(text) => Some(text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(error(e.to_string())),
+    };
+
+    // Windows paths, so compare them like Windows does
+    let exported: HashSet<String> = songs.iter().map(|x| x.path.to_lowercase()).collect();
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 1);
+    let replaced = match &existing {
+        Some(text) => copy_except(text, &exported, &mut writer, songs).map_err(error)?,
+        None => {
+            let write = |writer: &mut Writer<Vec<u8>>| -> quick_xml::Result<()> {
+                writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+                let mut database = BytesStart::new("VirtualDJ_Database");
+                database.push_attribute(("Version", DATABASE_VERSION));
+                writer.write_event(Event::Start(database))?;
+                for song in songs {
+                    song.write(writer)?;
+                }
+                writer.write_event(Event::End(BytesEnd::new("VirtualDJ_Database")))
+            };
+            write(&mut writer).map_err(|e| error(e.to_string()))?;
+            0
+        }
+    };
+
+    if existing.is_some() {
+        let backup = path.with_extension("xml.bak");
+        fs::copy(path, &backup).map_err(|e| error(format!("{}: {e}", backup.display())))?;
+    }
+    let mut data = writer.into_inner();
+    data.push(b'\n');
+    fs::write(path, data).map_err(|e| error(e.to_string()))?;
+    Ok(replaced)
+}
+
+/// Copy an existing database, leaving out the songs that are exported and adding them at the end
+fn copy_except(
+    text: &str,
+    exported: &HashSet<String>,
+    writer: &mut Writer<Vec<u8>>,
+    songs: &[VdjSong],
+) -> std::result::Result<usize, String> {
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+    let mut replaced = 0;
+    let mut found_database = false;
+    loop {
+        let event = reader.read_event().map_err(|e| e.to_string())?;
+        match &event {
+            Event::Start(e) if e.name().as_ref() == b"Song" && is_exported(e, exported)? => {
+                replaced += 1;
+                reader.read_to_end(e.name()).map_err(|e| e.to_string())?;
+                continue;
+            }
+            Event::Empty(e) if e.name().as_ref() == b"Song" && is_exported(e, exported)? => {
+                replaced += 1;
+                continue;
+            }
+            Event::End(e) if e.name().as_ref() == b"VirtualDJ_Database" => {
+                found_database = true;
+                for song in songs {
+                    song.write(writer).map_err(|e| e.to_string())?;
+                }
+            }
+            // A database without songs yet
+            Event::Empty(e) if e.name().as_ref() == b"VirtualDJ_Database" => {
+                found_database = true;
+                let end = BytesEnd::new("VirtualDJ_Database");
+                let write = |writer: &mut Writer<Vec<u8>>| -> quick_xml::Result<()> {
+                    writer.write_event(Event::Start(e.borrow()))?;
+                    for song in songs {
+                        song.write(writer)?;
+                    }
+                    writer.write_event(Event::End(end))
+                };
+                write(writer).map_err(|e| e.to_string())?;
+                continue;
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        writer.write_event(event).map_err(|e| e.to_string())?;
+    }
+    if !found_database {
+        return Err("Not a VirtualDJ database".to_string());
+    }
+    Ok(replaced)
+}
+
+fn is_exported(song: &BytesStart, exported: &HashSet<String>) -> std::result::Result<bool, String> {
+    let path = song.try_get_attribute("FilePath").map_err(|e| e.to_string())?;
+    match path {
+        Some(path) => {
+            let path = path.unescape_value().map_err(|e| e.to_string())?;
+            Ok(exported.contains(&path.to_lowercase()))
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anlz::{Beat, Cue};
+
+    fn song(path: &str, analysis: bool) -> VdjSong {
+        let beat = |number, time_ms| Beat {
+            number,
+            bpm: 128.,
+            time_ms,
+            bar: 0,
+        };
+        VdjSong {
+            path: path.to_string(),
+            content: DjmdContent {
+                title: Some("Track & Co".to_string()),
+                artist_name: Some("Artist".to_string()),
+                album_name: Some(String::new()),
+                key_name: Some("8A".to_string()),
+                bpm_centi: Some(12000),
+                length: Some(240),
+                ..Default::default()
+            },
+            analysis: analysis.then(|| Analysis {
+                beats: vec![beat(4, 100), beat(1, 569), beat(2, 1038)],
+                cues: vec![
+                    Cue {
+                        hot_cue: 0,
+                        time_ms: 569,
+                        loop_end_ms: None,
+                    },
+                    Cue {
+                        hot_cue: 2,
+                        time_ms: 12345,
+                        loop_end_ms: None,
+                    },
+                ],
+                structure: None,
+            }),
+        }
+    }
+
+    /// Merge into a database with `existing` in it, or none, and give what comes out
+    fn merge(name: &str, existing: Option<&str>, songs: &[VdjSong]) -> (Result<usize>, String, Option<String>) {
+        let path = std::env::temp_dir().join(format!("rkbx_os2l_{name}_{}.xml", std::process::id()));
+        let backup = path.with_extension("xml.bak");
+        if let Some(existing) = existing {
+            fs::write(&path, existing).unwrap();
+        }
+        let replaced = merge_database(&path, songs);
+        let text = fs::read_to_string(&path).unwrap_or_default();
+        let backup_text = fs::read_to_string(&backup).ok();
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&backup);
+        (replaced, text, backup_text)
+    }
+
+    #[test]
+    fn new_database() {
+        let (replaced, text, backup) = merge("vdj_new", None, &[song("C:\\Music\\a.mp3", true)]);
+        assert_eq!(replaced.unwrap(), 0);
+        assert!(backup.is_none());
+        assert_eq!(
+            text,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<VirtualDJ_Database Version="8.5">
+ <Song FilePath="C:\Music\a.mp3">
+  <Tags Author="Artist" Title="Track &amp; Co" Bpm="0.468750" Key="8A"/>
+  <Infos SongLength="240.000000"/>
+  <Scan Version="801" Bpm="0.468750" Key="8A"/>
+  <Poi Pos="0.569000" Type="beatgrid"/>
+  <Poi Pos="0.569000"/>
+  <Poi Pos="12.345000" Num="2"/>
+ </Song>
+</VirtualDJ_Database>
+"#
+        );
+    }
+
+    #[test]
+    fn without_analysis() {
+        // The tempo comes from the library
+        let (_, text, _) = merge("vdj_library", None, &[song("C:\\Music\\a.mp3", false)]);
+        assert!(text.contains(r#"Bpm="0.500000""#), "{text}");
+        assert!(!text.contains("<Poi"), "{text}");
+    }
+
+    #[test]
+    fn replaces_exported_songs() {
+        let existing = r#"<?xml version="1.0" encoding="UTF-8"?>
+<VirtualDJ_Database Version="8.5">
+ <Song FilePath="c:\music\A.mp3">
+  <Tags Title="Old"/>
+ </Song>
+ <Song FilePath="C:\Music\b.mp3" FileSize="1">
+  <Tags Title="Kept"/>
+ </Song>
+ <Song FilePath="C:\Music\c.mp3"/>
+</VirtualDJ_Database>
+"#;
+        let songs = [song("C:\\Music\\a.mp3", true), song("C:\\Music\\c.mp3", false)];
+        let (replaced, text, backup) = merge("vdj_merge", Some(existing), &songs);
+        assert_eq!(replaced.unwrap(), 2);
+        assert_eq!(backup.as_deref(), Some(existing));
+
+        assert!(!text.contains("Old"), "{text}");
+        assert!(text.contains(r#"<Song FilePath="C:\Music\b.mp3" FileSize="1">"#), "{text}");
+        assert!(text.contains(r#"<Tags Title="Kept"/>"#), "{text}");
+        // The exported ones go at the end
+        let a = text.find(r#"FilePath="C:\Music\a.mp3""#).unwrap();
+        let c = text.find(r#"FilePath="C:\Music\c.mp3""#).unwrap();
+        assert!(text.find("Kept").unwrap() < a && a < c, "{text}");
+        assert!(text.trim_end().ends_with("</VirtualDJ_Database>"), "{text}");
+    }
+
+    #[test]
+    fn empty_database() {
+        let existing = r#"<VirtualDJ_Database Version="2024"/>"#;
+        let (replaced, text, _) = merge("vdj_empty", Some(existing), &[song("C:\\Music\\a.mp3", false)]);
+        assert_eq!(replaced.unwrap(), 0);
+        assert!(text.starts_with(r#"<VirtualDJ_Database Version="2024">"#), "{text}");
+        assert!(text.contains(r#"<Song FilePath="C:\Music\a.mp3">"#), "{text}");
+    }
+
+    #[test]
+    fn not_a_database() {
+        let (replaced, _, backup) = merge("vdj_other", Some("<Playlist/>"), &[song("C:\\Music\\a.mp3", false)]);
+        assert!(matches!(replaced, Err(Error::Export(e)) if e.ends_with("Not a VirtualDJ database")));
+        assert!(backup.is_none());
+    }
+}
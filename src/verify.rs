@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    memory::{read, MemorySource},
+    offsets::{Pointer, RekordboxOffsets},
+    sane_bpm,
+    signature::resolve_signatures,
+};
+
+// Checks every pointer of an offsets entry against what Rekordbox should hold, so a broken
+// chain can be told apart from the working ones:
+//
+//   master_bpm         20 to 999
+//   masterdeck_index   one of the decks in the offsets
+//   deckN.bar          -1 to 9999, bar -1 is the count in before the first beat
+//   deckN.beat         1 to 4
+//   deckN.track_id     above 0, so a track has to be loaded on every deck
+//   deckN.time         0 or more, and never going backwards while it moves
+//   api_bearer         32 printable characters
+//
+// Values are read again for a while, a chain that only holds up for a moment is not right.
+
+/// Time between two reads of every value
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Outcome for one field, with the value read or what is wrong with it
+pub struct FieldReport {
+    pub name: String,
+    pub result: Result<String, String>,
+}
+
+/// What a field should hold
+enum Rule {
+    Bpm,
+    MasterdeckIndex { decks: usize },
+    Bar,
+    Beat,
+    TrackId,
+    Time,
+    Bearer,
+}
+
+impl Rule {
+    fn for_field(name: &str, decks: usize) -> Rule {
+        match name.rsplit('.').next().unwrap_or(name) {
+            "master_bpm" => Rule::Bpm,
+            "masterdeck_index" => Rule::MasterdeckIndex { decks },
+            "bar" => Rule::Bar,
+            "beat" => Rule::Beat,
+            "track_id" => Rule::TrackId,
+            "time" => Rule::Time,
+            "api_bearer" => Rule::Bearer,
+            // Every field of the offsets needs a check, a new one has to get it here
+            _ => unreachable!("No check for the field {name}"),
+        }
+    }
+
+    /// Read the value at `address` and check it, giving it as text either way
+    fn check(&self, source: &dyn MemorySource, address: usize) -> Result<Sample, String> {
+        let unreadable = || format!("could not read {address:X}");
+        match self {
+            Rule::Bpm => {
+                let bpm = read::<f32>(source, address).ok_or_else(unreadable)?;
+                plausible(sane_bpm(&bpm), format!("{bpm:.2}"), "expected 20 to 999")
+            }
+            Rule::MasterdeckIndex { decks } => {
+                let index = read::<u8>(source, address).ok_or_else(unreadable)?;
+                plausible(
+                    (index as usize) < *decks,
+                    index.to_string(),
+                    &format!("expected 0 to {}", decks - 1),
+                )
+            }
+            Rule::Bar => {
+                let bar = read::<i32>(source, address).ok_or_else(unreadable)?;
+                plausible(
+                    (-1..=9999).contains(&bar),
+                    bar.to_string(),
+                    "expected -1 to 9999",
+                )
+            }
+            Rule::Beat => {
+                let beat = read::<i32>(source, address).ok_or_else(unreadable)?;
+                plausible((1..=4).contains(&beat), beat.to_string(), "expected 1 to 4")
+            }
+            Rule::TrackId => {
+                let track_id = read::<i32>(source, address).ok_or_else(unreadable)?;
+                plausible(
+                    track_id > 0,
+                    track_id.to_string(),
+                    "expected above 0, is a track loaded?",
+                )
+            }
+            Rule::Time => {
+                let time = read::<i32>(source, address).ok_or_else(unreadable)?;
+                let sample = plausible(time >= 0, time.to_string(), "expected 0 or more")?;
+                Ok(Sample {
+                    time: Some(time),
+                    ..sample
+                })
+            }
+            Rule::Bearer => {
+                let bearer = source.read_bytes(address, 32).ok_or_else(unreadable)?;
+                let text = String::from_utf8_lossy(&bearer).to_string();
+                plausible(
+                    bearer.iter().all(|x| x.is_ascii_graphic()),
+                    text,
+                    "expected 32 printable characters",
+                )
+            }
+        }
+    }
+}
+
+struct Sample {
+    text: String,
+    /// Kept to check that the time does not go backwards
+    time: Option<i32>,
+}
+
+fn plausible(ok: bool, text: String, expected: &str) -> Result<Sample, String> {
+    match ok {
+        true => Ok(Sample { text, time: None }),
+        false => Err(format!("{text}, {expected}")),
+    }
+}
+
+/// Walk a pointer chain like the reader does, but say where it broke
+fn walk(source: &dyn MemorySource, base: usize, pointer: &Pointer) -> Result<usize, String> {
+    let mut address = base;
+    for (level, offset) in pointer.offsets.iter().enumerate() {
+        address = read::<usize>(source, address + offset).ok_or_else(|| {
+            format!(
+                "could not read {:X} at level {} ({offset:X})",
+                address + offset,
+                level + 1
+            )
+        })?;
+    }
+    Ok(address + pointer.final_offset)
+}
+
+/// A field while it is being checked
+struct Field {
+    name: String,
+    rule: Rule,
+    /// With its signature resolved, or why that failed
+    pointer: Result<Pointer, String>,
+    /// The last value read, or the first thing that was wrong, which stops further reads
+    result: Option<Result<String, String>>,
+    last_time: Option<i32>,
+    moved: bool,
+}
+
+impl Field {
+    fn sample(&mut self, source: &dyn MemorySource, base: usize) {
+        let sample = self.pointer.clone().and_then(|pointer| {
+            // The chain is walked again every time, Rekordbox moves things around
+            let address = walk(source, base, &pointer)?;
+            self.rule.check(source, address)
+        });
+        self.result = Some(
+            sample.and_then(|sample| match (self.last_time, sample.time) {
+                (Some(last), Some(time)) if time < last => {
+                    Err(format!("went back from {last} to {time}"))
+                }
+                (last, time) => {
+                    self.moved |= last.is_some() && last != time;
+                    self.last_time = time;
+                    Ok(sample.text)
+                }
+            }),
+        );
+    }
+
+    fn report(self) -> FieldReport {
+        let result = match (self.rule, self.result) {
+            (_, None) => Err("not read".to_string()),
+            (Rule::Time, Some(Ok(text))) if !self.moved => {
+                Ok(format!("{text}, not playing so not checked"))
+            }
+            (_, Some(result)) => result,
+        };
+        FieldReport {
+            name: self.name,
+            result,
+        }
+    }
+}
+
+/// Follow every pointer of the offsets for `duration` and report on each
+pub fn verify(
+    source: &dyn MemorySource,
+    offsets: &RekordboxOffsets,
+    duration: Duration,
+) -> Vec<FieldReport> {
+    let mut offsets = offsets.clone();
+    let decks = offsets.decks.len();
+    let base = source.module_base("rekordbox.exe");
+
+    // Signatures are found once, like when attaching
+    let mut not_found: HashMap<String, String> = HashMap::new();
+    if base.is_some() {
+        for (name, result) in resolve_signatures(&mut offsets, source) {
+            if let Err(e) = result {
+                not_found.insert(name, format!("signature {e}"));
+            }
+        }
+    }
+
+    let mut fields: Vec<Field> = offsets
+        .pointers_mut()
+        .into_iter()
+        .map(|(name, pointer)| Field {
+            rule: Rule::for_field(&name, decks),
+            pointer: not_found.remove(&name).map_or_else(|| Ok(pointer.clone()), Err),
+            name,
+            result: None,
+            last_time: None,
+            moved: false,
+        })
+        .collect();
+
+    let Some(base) = base else {
+        for field in &mut fields {
+            field.result = Some(Err("could not find the rekordbox.exe module".to_string()));
+        }
+        return fields.into_iter().map(Field::report).collect();
+    };
+
+    let start = Instant::now();
+    loop {
+        for field in fields
+            .iter_mut()
+            .filter(|x| !matches!(x.result, Some(Err(_))))
+        {
+            field.sample(source, base);
+        }
+        if start.elapsed() >= duration {
+            break;
+        }
+        sleep(SAMPLE_INTERVAL);
+    }
+
+    fields.into_iter().map(Field::report).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::FakeMemory,
+        offsets::{DeckOffsets, MAX_DECKS},
+    };
+
+    const BASE: usize = 0x140000000;
+
+    fn offsets(decks: usize) -> RekordboxOffsets {
+        let deck = |n: usize| DeckOffsets {
+            bar: Pointer::new(vec![], 0x100 + n * 0x10),
+            beat: Pointer::new(vec![], 0x104 + n * 0x10),
+            track_id: Some(Pointer::new(vec![], 0x108 + n * 0x10)),
+            time: Some(Pointer::new(vec![], 0x10C + n * 0x10)),
+        };
+        RekordboxOffsets {
+            rbversion: "6.8.5".to_string(),
+            decks: (0..decks).map(deck).collect(),
+            master_bpm: Pointer::new(vec![0x10], 0x8),
+            masterdeck_index: Pointer::new(vec![], 0x20),
+            api_bearer: Some(Pointer::new(vec![], 0x40)),
+        }
+    }
+
+    #[test]
+    fn every_field_has_a_rule() {
+        for (name, _) in offsets(MAX_DECKS).pointers_mut() {
+            Rule::for_field(&name, MAX_DECKS);
+        }
+    }
+
+    #[test]
+    fn reports_each_field() {
+        let mut memory = FakeMemory::new();
+        memory.add_module("rekordbox.exe", BASE, 0x200);
+        memory.add_region(0x20000, 0x100);
+        memory.write(BASE + 0x10, 0x20000u64);
+        memory.write(0x20008, 128f32);
+        memory.write(BASE + 0x20, 1u8);
+        memory.write_bytes(BASE + 0x40, &[b'a'; 32]);
+        // Deck 1 is fine, deck 2 is not
+        memory.write(BASE + 0x100, 12i32);
+        memory.write(BASE + 0x104, 3i32);
+        memory.write(BASE + 0x108, 42i32);
+        memory.write(BASE + 0x10C, 1500i32);
+        memory.write(BASE + 0x110, 12i32);
+        memory.write(BASE + 0x114, 5i32);
+
+        let mut offsets = offsets(2);
+        offsets.decks[1].time = Some(Pointer::new(vec![0x300], 0));
+        let reports = verify(&memory, &offsets, Duration::ZERO);
+        let results: Vec<(&str, Result<&str, &str>)> = reports
+            .iter()
+            .map(|x| (x.name.as_str(), x.result.as_deref().map_err(|x| x.as_str())))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("master_bpm", Ok("128.00")),
+                ("masterdeck_index", Ok("1")),
+                ("api_bearer", Ok("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")),
+                ("deck1.bar", Ok("12")),
+                ("deck1.beat", Ok("3")),
+                ("deck1.track_id", Ok("42")),
+                ("deck1.time", Ok("1500, not playing so not checked")),
+                ("deck2.bar", Ok("12")),
+                ("deck2.beat", Err("5, expected 1 to 4")),
+                ("deck2.track_id", Err("0, expected above 0, is a track loaded?")),
+                ("deck2.time", Err("could not read 140000300 at level 1 (300)")),
+            ]
+        );
+    }
+}
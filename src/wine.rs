@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    memory::MemorySource,
+    signature::{self, Signature},
+};
+
+/// A Windows process running under Wine/Proton, read through `/proc/<pid>/mem`.
+///
+/// Reading another process' memory needs ptrace permission, so either run as the same user
+/// with `kernel.yama.ptrace_scope = 0`, or give the binary `CAP_SYS_PTRACE`.
+pub struct ProcessMemory {
+    pid: u32,
+    mem: File,
+    modules: HashMap<String, (usize, PathBuf)>,
+}
+
+impl ProcessMemory {
+    pub fn attach(process_name: &str) -> Option<Self> {
+        Self::open(find_pid(process_name)?, process_name)
+    }
+
+    /// The process `pid`, with `process_name` as its module
+    fn open(pid: u32, process_name: &str) -> Option<Self> {
+        let maps = fs::read_to_string(format!("/proc/{pid}/maps")).ok()?;
+        let module = module_from_maps(&maps, process_name)?;
+
+        let mut modules = HashMap::new();
+        modules.insert(process_name.to_string(), module);
+
+        Some(Self {
+            pid,
+            mem: File::open(format!("/proc/{pid}/mem")).ok()?,
+            modules,
+        })
+    }
+}
+
+impl MemorySource for ProcessMemory {
+    fn read_bytes(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; len];
+        self.mem.read_exact_at(&mut buffer, address as u64).ok()?;
+        Some(buffer)
+    }
+
+    fn module_base(&self, name: &str) -> Option<usize> {
+        self.modules.get(name).map(|(base, _)| *base)
+    }
+
+    fn module_path(&self, name: &str) -> Option<PathBuf> {
+        self.modules.get(name).map(|(_, path)| path.clone())
+    }
+
+    fn find_signatures(&self, module: &str, signatures: &[Signature]) -> Vec<Result<usize, String>> {
+        signature::scan(self, module, signatures)
+    }
+
+    fn writable_regions(&self) -> Vec<(usize, usize)> {
+        let maps = fs::read_to_string(format!("/proc/{}/maps", self.pid)).unwrap_or_default();
+        maps.lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let (start, end) = fields.next()?.split_once('-')?;
+                let perms = fields.next()?;
+                if !perms.starts_with("rw") {
+                    return None;
+                }
+                let start = usize::from_str_radix(start, 16).ok()?;
+                let end = usize::from_str_radix(end, 16).ok()?;
+                Some((start, end - start))
+            })
+            .collect()
+    }
+}
+
+/// Wine names the process after the executable, but the full Windows path can also show up
+/// as the first argument, eg. `C:\Program Files\Pioneer\rekordbox 6.8.5\rekordbox.exe`.
+fn find_pid(process_name: &str) -> Option<u32> {
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|x| x.parse::<u32>().ok()) else {
+            continue;
+        };
+
+        let comm = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        if comm.trim_end().eq_ignore_ascii_case(process_name) {
+            return Some(pid);
+        }
+
+        let cmdline = fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        if let Some(first_arg) = cmdline.split(|x| *x == 0).next() {
+            let first_arg = String::from_utf8_lossy(first_arg);
+            if file_name(&first_arg).eq_ignore_ascii_case(process_name) {
+                return Some(pid);
+            }
+        }
+    }
+    None
+}
+
+/// Wine maps PE images straight from the .exe file, so the module base is the start of the
+/// first mapping of that file. Returns the base and the path of the file.
+fn module_from_maps(maps: &str, module_name: &str) -> Option<(usize, PathBuf)> {
+    maps.lines().find_map(|line| parse_maps_line(line, module_name))
+}
+
+/// `<start>-<end> <perms> <offset> <dev> <inode> <path>`, the path padded to a column and kept
+/// as it is after that, spaces and all
+fn parse_maps_line(line: &str, module_name: &str) -> Option<(usize, PathBuf)> {
+    let mut rest = line;
+    let mut fields = [""; 5];
+    for field in &mut fields {
+        let (next, after) = rest.trim_start().split_once(' ').unwrap_or((rest.trim_start(), ""));
+        *field = next;
+        rest = after;
+    }
+    let [range, _perms, offset, _dev, _inode] = fields;
+    let path = rest.trim_start();
+
+    // Anonymous mappings have no path, and ones like [heap] are not files
+    if path.is_empty() || path.starts_with('[') || !file_name(path).eq_ignore_ascii_case(module_name) {
+        return None;
+    }
+    if usize::from_str_radix(offset, 16).ok()? != 0 {
+        return None;
+    }
+
+    let start = range.split('-').next()?;
+    Some((usize::from_str_radix(start, 16).ok()?, PathBuf::from(path)))
+}
+
+fn file_name(path: &str) -> &str {
+    let path = path.rsplit('\\').next().unwrap_or(path);
+    Path::new(path)
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: &str = "\
+00010000-00011000 r--p 00000000 00:00 0 
+140000000-140001000 r--p 00000000 fd:01 1835   /home/dj/.wine/drive_c/Program Files/Pioneer/rekordbox  6.8.5/rekordbox.exe
+140001000-145000000 r-xp 00001000 fd:01 1835   /home/dj/.wine/drive_c/Program Files/Pioneer/rekordbox  6.8.5/rekordbox.exe
+7f0000000000-7f0000010000 rw-p 00000000 00:00 0                          [heap]
+7f1000000000-7f1000001000 r--p 00000000 fd:01 2002   Z:\\opt\\Pioneer\\Rekordbox.EXE
+";
+
+    #[test]
+    fn maps_lines() {
+        let (base, path) = parse_maps_line(MAPS.lines().nth(1).unwrap(), "rekordbox.exe").unwrap();
+        assert_eq!(base, 0x140000000);
+        assert_eq!(
+            path,
+            PathBuf::from("/home/dj/.wine/drive_c/Program Files/Pioneer/rekordbox  6.8.5/rekordbox.exe")
+        );
+        // Later mappings of the file are not its start
+        assert!(parse_maps_line(MAPS.lines().nth(2).unwrap(), "rekordbox.exe").is_none());
+        // Anonymous mappings have no path
+        assert!(parse_maps_line(MAPS.lines().next().unwrap(), "rekordbox.exe").is_none());
+        assert!(parse_maps_line(MAPS.lines().nth(3).unwrap(), "[heap]").is_none());
+        assert!(parse_maps_line("", "rekordbox.exe").is_none());
+    }
+
+    #[test]
+    fn modules() {
+        assert_eq!(module_from_maps(MAPS, "REKORDBOX.EXE").unwrap().0, 0x140000000);
+        // A Windows path, named with \ and any case
+        let (base, path) = module_from_maps(MAPS, "rekordbox.exe").unwrap();
+        assert_eq!(base, 0x140000000);
+        assert!(path.starts_with("/home"));
+        let maps = MAPS.lines().skip(2).collect::<Vec<_>>().join("\n");
+        let (base, path) = module_from_maps(&maps, "rekordbox.exe").unwrap();
+        assert_eq!(base, 0x7f1000000000);
+        assert_eq!(path, PathBuf::from("Z:\\opt\\Pioneer\\Rekordbox.EXE"));
+        assert!(module_from_maps(MAPS, "rekordbox").is_none());
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("C:\\Program Files\\Pioneer\\rekordbox.exe"), "rekordbox.exe");
+        assert_eq!(file_name("/opt/rekordbox.exe"), "rekordbox.exe");
+        assert_eq!(file_name("rekordbox.exe"), "rekordbox.exe");
+    }
+
+    #[test]
+    fn reads_own_memory() {
+        let exe = std::env::current_exe().unwrap();
+        let name = exe.file_name().unwrap().to_str().unwrap();
+        let memory = ProcessMemory::open(std::process::id(), name).unwrap();
+
+        // The test binary is mapped from its start, an ELF header
+        let base = memory.module_base(name).unwrap();
+        assert_eq!(memory.read_bytes(base, 4).unwrap(), b"\x7fELF");
+        assert_eq!(memory.module_path(name).unwrap(), exe);
+
+        let value = Box::new(0x1234_5678_9abc_def0u64);
+        let address = &*value as *const u64 as usize;
+        assert_eq!(memory.read_bytes(address, 8).unwrap(), value.to_le_bytes());
+        let regions = memory.writable_regions();
+        assert!(regions.iter().any(|(start, len)| (*start..start + len).contains(&address)));
+        assert!(memory.read_bytes(0, 8).is_none());
+    }
+}